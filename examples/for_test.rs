@@ -12,7 +12,7 @@ fn main() {
     list.push(Command::Stats);
     //list.push(Command::Next);
     list.push(Command::Count {
-        tag: (TagType::Genre, "Rock".into()),
+        query: vec![(TagType::Genre.into(), "Rock".into())].into(),
         group: None
     });
     println!("{:?}", c.run_commands(list));
@@ -1,30 +1,776 @@
+//! An asynchronous client, running on a tokio event loop.
+//!
+//! Requests are pipelined: each request is written to the server as soon as it is made, without
+//! waiting for the responses of earlier requests. Responses are matched to requests in the order
+//! they were sent.
 use std::io;
+use std::mem;
 use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::collections::VecDeque;
+
+use bytes::BytesMut;
+use futures::{Future, Poll, Async, AsyncSink, Stream, Sink};
+use futures::sync::{mpsc, oneshot};
+use nom::IResult;
+use semver::Version;
 use tokio_core::reactor::Handle;
-use tokio_core::net::{TcpStream, TcpStreamNew};
-use futures::{Future, Poll};
+use tokio_core::net::TcpStream;
+use tokio_codec::{Decoder, Encoder, Framed};
+
+use client::Error;
+use protocol::command::{Command, CommandList, CommandListResponse, CommandResponse};
+use protocol::command::parse_next_song;
+use protocol::{Dispatch, ParseResponse, parse_handshake, parse_ok, parse_result};
+use types::{CmdError, Song, SubSystem};
+use util::forget_input;
+
+/// How many songs a `SongStream` reads ahead of whoever is polling it
+const SONG_BUFFER: usize = 16;
 
 pub fn default_address() -> SocketAddr {
     SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), 6600))
 }
 
+/// Something that can be sent to the server
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Command(Command),
+    CommandList(CommandList),
+    /// A command whose response is a list of songs, decoded a song at a time
+    Songs(Command),
+}
+
+/// A message from the server
+#[derive(Clone, Debug, PartialEq)]
+enum Frame {
+    /// The greeting sent when the connection is opened
+    Handshake(Version),
+    /// The response to a `Request::Command`
+    Command(Result<CommandResponse, CmdError>),
+    /// The response to a `Request::CommandList`
+    CommandList(CommandListResponse),
+    /// The next song in the response to a `Request::Songs`
+    Song(Song),
+    /// The end of the response to a `Request::Songs`
+    SongsEnd(Result<(), CmdError>),
+}
+
+/// Encodes requests, and decodes responses using the requests they are in response to.
+struct MpdCodec {
+    handshake_done: bool,
+    /// Requests that have been sent, but whose responses haven't been decoded yet
+    pending: VecDeque<Request>,
+}
+
+impl MpdCodec {
+    fn new() -> MpdCodec {
+        MpdCodec {
+            handshake_done: false,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl Encoder for MpdCodec {
+    type Item = Request;
+    type Error = Error;
+
+    fn encode(&mut self, req: Request, dst: &mut BytesMut) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        match req {
+            Request::Command(ref cmd) | Request::Songs(ref cmd) => cmd.dispatch(&mut buf)?,
+            Request::CommandList(ref list) => list.dispatch(&mut buf)?,
+        }
+        dst.extend_from_slice(&buf);
+        // noidle never gets a response of its own
+        if req != Request::Command(Command::NoIdle) {
+            self.pending.push_back(req);
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for MpdCodec {
+    type Item = Frame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        let (consumed, frame) = {
+            let res = if !self.handshake_done {
+                parse_handshake(&src[..]).map(Frame::Handshake)
+            } else {
+                match self.pending.front() {
+                    Some(&Request::Command(ref cmd)) => parse_result(&src[..], |i| {
+                        let (i, response) = try_parse!(i, |i| cmd.parse_response(i));
+                        let (i, _) = try_parse!(i, parse_ok);
+                        IResult::Done(i, response)
+                    }).map(Frame::Command),
+                    Some(&Request::CommandList(ref list)) => {
+                        list.parse_response(&src[..]).map(Frame::CommandList)
+                    },
+                    Some(&Request::Songs(_)) => parse_result(&src[..], |i| {
+                        match try_parse!(i, parse_next_song) {
+                            (i, Some(song)) => IResult::Done(i, Some(song)),
+                            (i, None) => parse_ok(i).map(|_| None),
+                        }
+                    }).map(|res| match res {
+                        Ok(Some(song)) => Frame::Song(song),
+                        Ok(None) => Frame::SongsEnd(Ok(())),
+                        Err(e) => Frame::SongsEnd(Err(e)),
+                    }),
+                    None if src.is_empty() => { return Ok(None); },
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "unexpected data from server"
+                        ).into());
+                    }
+                }
+            };
+            match res {
+                IResult::Done(rest, frame) => (src.len() - rest.len(), frame),
+                IResult::Incomplete(_) => { return Ok(None); },
+                // the error can't borrow `src`, which is about to be split
+                IResult::Error(e) => { return Err(Error::from(forget_input(e))); },
+            }
+        };
+        src.split_to(consumed);
+        match frame {
+            Frame::Handshake(_) => { self.handshake_done = true; },
+            // the rest of the songs are still to come
+            Frame::Song(_) => (),
+            _ => { self.pending.pop_front(); },
+        }
+        Ok(Some(frame))
+    }
+}
+
+type Responder = oneshot::Sender<Result<Frame, Error>>;
+
+/// Where the songs for a `SongStream` are sent. `None` marks the end of the list.
+type SongSender = mpsc::Sender<Result<Option<Song>, Error>>;
+
+/// Messages from `TokioMpc` handles to the driver
+enum Message {
+    /// Send a request and return its response
+    Request(Request, Responder),
+    /// Start idling on the given subsystems whenever the connection isn't otherwise in use,
+    /// sending the changes to the given channel
+    Idle(Vec<SubSystem>, mpsc::UnboundedSender<Result<Vec<SubSystem>, Error>>),
+    /// Send a command whose response is a list of songs, sending the songs to the given channel
+    Songs(Command, SongSender),
+}
+
+/// Something waiting for a response from the server
+enum Waiter {
+    Caller(Responder),
+    /// The response to an idle sent on behalf of an `IdleStream`
+    Idle,
+    /// A `SongStream`, which stays at the front of the queue until its last song has been read
+    Songs(SongSender),
+}
+
+/// The subscription of an `IdleStream`
+struct IdleSubscription {
+    subsystems: Vec<SubSystem>,
+    events: mpsc::UnboundedSender<Result<Vec<SubSystem>, Error>>,
+}
+
+/// Drives the connection, sending requests and routing responses back to their callers.
+///
+/// If there is an idle subscription, the driver idles whenever there are no requests
+/// outstanding, and cancels the idle with `noidle` as soon as a new request comes in.
+struct Driver {
+    transport: Framed<TcpStream, MpdCodec>,
+    messages: mpsc::UnboundedReceiver<Message>,
+    messages_done: bool,
+    /// Requests waiting to be written to the transport, along with who is waiting for the response
+    outgoing: VecDeque<(Request, Option<Waiter>)>,
+    /// Waiters for requests that have been written, in the order the requests were sent
+    in_flight: VecDeque<Waiter>,
+    idle: Option<IdleSubscription>,
+    /// Whether an idle for the subscription has been sent and not cancelled
+    idling: bool,
+}
+
+impl Driver {
+    fn new(transport: Framed<TcpStream, MpdCodec>, messages: mpsc::UnboundedReceiver<Message>)
+        -> Driver
+    {
+        Driver {
+            transport: transport,
+            messages: messages,
+            messages_done: false,
+            outgoing: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            idle: None,
+            idling: false,
+        }
+    }
+
+    /// Queue a `noidle` if we are idling
+    fn cancel_idle(&mut self) {
+        if self.idling {
+            self.outgoing.push_back((Request::Command(Command::NoIdle), None));
+            self.idling = false;
+        }
+    }
+
+    fn receive_messages(&mut self) {
+        while !self.messages_done {
+            match self.messages.poll() {
+                Ok(Async::Ready(Some(Message::Request(req, tx)))) => {
+                    self.cancel_idle();
+                    if req == Request::Command(Command::NoIdle) {
+                        // noidle has no response of its own
+                        self.outgoing.push_back((req, None));
+                        let _ = tx.send(Ok(Frame::Command(Ok(CommandResponse::Blank))));
+                    } else {
+                        self.outgoing.push_back((req, Some(Waiter::Caller(tx))));
+                    }
+                },
+                Ok(Async::Ready(Some(Message::Songs(command, songs)))) => {
+                    self.cancel_idle();
+                    self.outgoing.push_back((Request::Songs(command), Some(Waiter::Songs(songs))));
+                },
+                Ok(Async::Ready(Some(Message::Idle(subsystems, events)))) => {
+                    self.cancel_idle();
+                    self.idle = Some(IdleSubscription {
+                        subsystems: subsystems,
+                        events: events,
+                    });
+                },
+                Ok(Async::Ready(None)) | Err(()) => { self.messages_done = true; },
+                Ok(Async::NotReady) => { break; },
+            }
+        }
+    }
+
+    /// Start idling if there is a subscription and nothing else to do
+    fn queue_idle(&mut self) {
+        if self.idle.as_ref().map(|idle| idle.events.is_closed()).unwrap_or(false) {
+            self.idle = None;
+        }
+        if self.idle.is_none() || self.messages_done {
+            self.cancel_idle();
+            return;
+        }
+        if !self.idling && self.outgoing.is_empty() && self.in_flight.is_empty() {
+            let subsystems = self.idle.as_ref().unwrap().subsystems.clone();
+            let req = Request::Command(Command::Idle(subsystems));
+            self.outgoing.push_back((req, Some(Waiter::Idle)));
+            self.idling = true;
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        while let Some((req, waiter)) = self.outgoing.pop_front() {
+            match self.transport.start_send(req)? {
+                AsyncSink::Ready => {
+                    if let Some(waiter) = waiter {
+                        self.in_flight.push_back(waiter);
+                    }
+                },
+                AsyncSink::NotReady(req) => {
+                    self.outgoing.push_front((req, waiter));
+                    break;
+                }
+            }
+        }
+        self.transport.poll_complete()?;
+        Ok(())
+    }
+
+    fn read_frames(&mut self) -> Result<(), Error> {
+        loop {
+            // leave songs on the socket until a slow `SongStream` has caught up, rather than
+            // buffering them. If the stream has been dropped, the songs are read and thrown away.
+            if let Some(&mut Waiter::Songs(ref mut songs)) = self.in_flight.front_mut() {
+                if let Ok(Async::NotReady) = songs.poll_ready() {
+                    return Ok(());
+                }
+            }
+            let frame = match self.transport.poll()? {
+                Async::Ready(Some(frame)) => frame,
+                Async::Ready(None) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed by server"
+                    ).into());
+                },
+                Async::NotReady => { return Ok(()); },
+            };
+            if let Frame::Song(song) = frame {
+                match self.in_flight.front_mut() {
+                    Some(&mut Waiter::Songs(ref mut songs)) => {
+                        let _ = songs.start_send(Ok(Some(song)));
+                    },
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "unexpected song from server"
+                        ).into());
+                    }
+                }
+                continue;
+            }
+            match self.in_flight.pop_front() {
+                // the caller may have lost interest, that's fine
+                Some(Waiter::Caller(tx)) => { let _ = tx.send(Ok(frame)); },
+                Some(Waiter::Idle) => {
+                    self.idling = false;
+                    let event = match frame {
+                        Frame::Command(Ok(CommandResponse::Changed(ref changed)))
+                            if changed.is_empty() => None,
+                        Frame::Command(Ok(CommandResponse::Changed(changed))) => Some(Ok(changed)),
+                        Frame::Command(Err(e)) => Some(Err(Error::from(e))),
+                        _ => unreachable!(),
+                    };
+                    if let Some(event) = event {
+                        if let Some(ref idle) = self.idle {
+                            let _ = idle.events.unbounded_send(event);
+                        }
+                    }
+                },
+                Some(Waiter::Songs(mut songs)) => {
+                    let end = match frame {
+                        Frame::SongsEnd(Ok(())) => Ok(None),
+                        Frame::SongsEnd(Err(e)) => Err(Error::from(e)),
+                        _ => unreachable!(),
+                    };
+                    let _ = songs.start_send(end);
+                },
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected response from server"
+                    ).into());
+                }
+            }
+        }
+    }
+
+    fn poll_inner(&mut self) -> Poll<(), Error> {
+        self.receive_messages();
+        self.queue_idle();
+        self.flush()?;
+        self.read_frames()?;
+        self.queue_idle();
+        self.flush()?;
+
+        if self.messages_done && self.outgoing.is_empty() && self.in_flight.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl Future for Driver {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        match self.poll_inner() {
+            Ok(a) => Ok(a),
+            Err(e) => {
+                // the first caller gets the real error, the rest are told the connection is gone
+                let mut e = Some(e);
+                let waiting = mem::replace(&mut self.in_flight, VecDeque::new()).into_iter()
+                    .chain(mem::replace(&mut self.outgoing, VecDeque::new()).into_iter()
+                        .filter_map(|(_, waiter)| waiter));
+                for waiter in waiting {
+                    let err = e.take().unwrap_or_else(|| io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "connection failed"
+                    ).into());
+                    match waiter {
+                        Waiter::Caller(tx) => { let _ = tx.send(Err(err)); },
+                        Waiter::Idle => if let Some(ref idle) = self.idle {
+                            let _ = idle.events.unbounded_send(Err(err));
+                        },
+                        // a new sender always has room for one message, even if the stream has
+                        // fallen behind
+                        Waiter::Songs(songs) => { let _ = songs.clone().start_send(Err(err)); },
+                    }
+                }
+                Err(())
+            }
+        }
+    }
+}
+
+/// An asynchronous connection to an mpd server
+///
+/// The connection is driven by a task on the event loop it was created on, and is closed once
+/// the `TokioMpc` is dropped and all outstanding requests have completed.
+#[derive(Clone)]
 pub struct TokioMpc {
-    stream: TcpStream
+    messages: mpsc::UnboundedSender<Message>,
+    version: Version,
 }
 
-pub struct TokioMpcNew(TcpStreamNew);
+/// A future resolving to a connected `TokioMpc`
+pub struct TokioMpcNew(Box<Future<Item = TokioMpc, Error = Error>>);
 
 impl Future for TokioMpcNew {
     type Item = TokioMpc;
-    type Error = io::Error;
+    type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.0.poll().map(|ok| ok.map(|stream| TokioMpc { stream: stream }))
+        self.0.poll()
+    }
+}
+
+/// A future resolving to the response of a `Request`
+struct ResponseFuture(oneshot::Receiver<Result<Frame, Error>>);
+
+impl Future for ResponseFuture {
+    type Item = Frame;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Frame, Error> {
+        match self.0.poll() {
+            Ok(Async::Ready(Ok(frame))) => Ok(Async::Ready(frame)),
+            Ok(Async::Ready(Err(e))) => Err(e),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "connection closed"
+            ).into()),
+        }
     }
 }
 
 impl TokioMpc {
+    /// Connect to the server at the given address, running the connection on `handle`'s event
+    /// loop
     pub fn new(addr: &SocketAddr, handle: &Handle) -> TokioMpcNew {
-        TokioMpcNew(TcpStream::connect(addr, handle))
+        let handle = handle.clone();
+        let connect = TcpStream::connect(addr, &handle)
+            .map_err(Error::from)
+            .and_then(|stream| MpdCodec::new().framed(stream).into_future().map_err(|(e, _)| e))
+            .and_then(move |(frame, transport)| {
+                let version = match frame {
+                    Some(Frame::Handshake(v)) => v,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "connection closed before handshake"
+                        ).into());
+                    }
+                };
+                let (tx, rx) = mpsc::unbounded();
+                handle.spawn(Driver::new(transport, rx));
+                Ok(TokioMpc {
+                    messages: tx,
+                    version: version,
+                })
+            });
+        TokioMpcNew(Box::new(connect))
+    }
+
+    /// The version of the protocol the server speaks
+    pub fn version(&self) -> Version {
+        self.version.clone()
+    }
+
+    /// Send a request, returning a future for its response
+    fn request(&self, req: Request) -> ResponseFuture {
+        let (tx, rx) = oneshot::channel();
+        // if the driver has gone, `tx` is dropped and the future resolves to an error
+        let _ = self.messages.unbounded_send(Message::Request(req, tx));
+        ResponseFuture(rx)
+    }
+
+    /// Run a single command, resolving to its response
+    pub fn run_command(&self, command: Command)
+        -> Box<Future<Item = CommandResponse, Error = Error>>
+    {
+        Box::new(self.request(Request::Command(command)).and_then(|frame| match frame {
+            Frame::Command(res) => res.map_err(Error::from),
+            _ => unreachable!(),
+        }))
+    }
+
+    /// Run a list of commands, resolving to their responses in the order the commands were given.
+    ///
+    /// If the server rejects one of the commands, the future fails with `Error::Command`.
+    pub fn run_commands(&self, commands: CommandList)
+        -> Box<Future<Item = Vec<CommandResponse>, Error = Error>>
+    {
+        Box::new(self.run_commands_partial(commands)
+            .and_then(|res| res.into_result().map_err(Error::from)))
+    }
+
+    /// Run a list of commands, resolving to the responses of all commands that succeeded.
+    pub fn run_commands_partial(&self, commands: CommandList)
+        -> Box<Future<Item = CommandListResponse, Error = Error>>
+    {
+        Box::new(self.request(Request::CommandList(commands)).map(|frame| match frame {
+            Frame::CommandList(res) => res,
+            _ => unreachable!(),
+        }))
+    }
+
+    /// Wait until one of the given subsystems changes, resolving to the subsystems that changed.
+    ///
+    /// No other commands may be sent until the future resolves; use `noidle` to make it resolve
+    /// early.
+    pub fn idle(&self, subsystems: &[SubSystem])
+        -> Box<Future<Item = Vec<SubSystem>, Error = Error>>
+    {
+        Box::new(self.run_command(Command::Idle(subsystems.to_vec())).map(|res| match res {
+            CommandResponse::Changed(changed) => changed,
+            _ => unreachable!(),
+        }))
+    }
+
+    /// Cancel a pending `idle`, making it resolve immediately
+    pub fn noidle(&self) -> Box<Future<Item = (), Error = Error>> {
+        Box::new(self.request(Request::Command(Command::NoIdle)).map(|_| ()))
+    }
+
+    /// Run a command whose response is a list of songs, streaming the songs as they are read.
+    ///
+    /// Songs are only read from the connection as fast as the stream is polled, so a very large
+    /// response (e.g. to `listallinfo` on a big database) never has to be held in memory.
+    /// Requests made after this one are answered once all the songs have been read. If the
+    /// stream is dropped early, the rest of the songs are read and thrown away.
+    pub fn song_stream(&self, command: Command) -> SongStream {
+        let (tx, rx) = mpsc::channel(SONG_BUFFER);
+        let _ = self.messages.unbounded_send(Message::Songs(command, tx));
+        SongStream {
+            songs: rx,
+            done: false,
+            _mpc: self.clone(),
+        }
+    }
+
+    /// Get a stream of changes to the given subsystems (or all subsystems if empty).
+    ///
+    /// The connection idles whenever no other requests are outstanding. Requests made while
+    /// idling cancel the idle with `noidle`, and idling resumes once they have completed, so
+    /// commands can be run as normal while the stream is alive. Don't use `idle` or `noidle`
+    /// directly while there is an `IdleStream`.
+    ///
+    /// There can only be one `IdleStream` per connection: creating a new one ends the old one.
+    pub fn idle_stream(&self, subsystems: &[SubSystem]) -> IdleStream {
+        let (tx, rx) = mpsc::unbounded();
+        let _ = self.messages.unbounded_send(Message::Idle(subsystems.to_vec(), tx));
+        IdleStream {
+            events: rx,
+            _mpc: self.clone(),
+        }
+    }
+}
+
+/// A stream of the subsystems that changed, created by `TokioMpc::idle_stream`
+///
+/// The stream keeps the connection open while it is alive.
+pub struct IdleStream {
+    events: mpsc::UnboundedReceiver<Result<Vec<SubSystem>, Error>>,
+    _mpc: TokioMpc,
+}
+
+impl Stream for IdleStream {
+    type Item = Vec<SubSystem>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<SubSystem>>, Error> {
+        match self.events.poll() {
+            Ok(Async::Ready(Some(Ok(changed)))) => Ok(Async::Ready(Some(changed))),
+            Ok(Async::Ready(Some(Err(e)))) => Err(e),
+            Ok(Async::Ready(None)) | Err(()) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// A stream of the songs in a response, created by `TokioMpc::song_stream`
+///
+/// The stream keeps the connection open while it is alive.
+pub struct SongStream {
+    songs: mpsc::Receiver<Result<Option<Song>, Error>>,
+    done: bool,
+    _mpc: TokioMpc,
+}
+
+impl Stream for SongStream {
+    type Item = Song;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Song>, Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        let result = match self.songs.poll() {
+            Ok(Async::Ready(Some(Ok(Some(song))))) => { return Ok(Async::Ready(Some(song))); },
+            Ok(Async::NotReady) => { return Ok(Async::NotReady); },
+            Ok(Async::Ready(Some(Ok(None)))) => Ok(Async::Ready(None)),
+            Ok(Async::Ready(Some(Err(e)))) => Err(e),
+            // the driver went away without saying the list was finished
+            Ok(Async::Ready(None)) | Err(()) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "connection closed"
+            ).into()),
+        };
+        self.done = true;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+    use test_server::{FakeServer, Script};
+    use types::{CmdErrorType, TagType};
+
+    #[test]
+    fn pipelined() {
+        let server = FakeServer::spawn(Script::new().respond(
+            "stop\ncommand_list_ok_begin\nnext\nplay 10240\ncommand_list_end\nidle player\n",
+            "OK\nlist_OK\nACK [50@1] {play} song doesn't exist: \"10240\"\nchanged: player\nOK\n"
+        )).unwrap();
+        let mut core = Core::new().unwrap();
+        let mpc = core.run(TokioMpc::new(&server.addr(), &core.handle())).unwrap();
+        assert_eq!(mpc.version(), Version::parse("0.19.0").unwrap());
+
+        let mut list = CommandList::new();
+        list.push(Command::Next);
+        list.push(Command::Play(10240));
+        let stop = mpc.run_command(Command::Stop);
+        let list = mpc.run_commands_partial(list);
+        let idle = mpc.idle(&[SubSystem::Player]);
+        let (stop, list, idle) = core.run(stop.join3(list, idle)).unwrap();
+        assert_eq!(stop, CommandResponse::Blank);
+        assert_eq!(list.responses, vec![CommandResponse::Blank]);
+        assert_eq!(list.error.map(|e| e.error_type), Some(CmdErrorType::NoExist));
+        assert_eq!(idle, vec![SubSystem::Player]);
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn command_error() {
+        let server = FakeServer::spawn(Script::new().respond(
+            "play 10240\n",
+            "ACK [50@0] {play} song doesn't exist: \"10240\"\n"
+        )).unwrap();
+        let mut core = Core::new().unwrap();
+        let mpc = core.run(TokioMpc::new(&server.addr(), &core.handle())).unwrap();
+        match core.run(mpc.run_command(Command::Play(10240))) {
+            Err(Error::Command(e)) => assert_eq!(e.command_no, 0),
+            other => panic!("expected command error, got {:?}", other),
+        }
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn idle_stream() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .respond("idle player mixer\n", "changed: mixer\nOK\n")
+                // a command interrupts the idle, which has nothing to report
+                .expect("idle player mixer\n")
+                .respond("noidle\nstop\n", "OK\nOK\n")
+                .respond("idle player mixer\n", "changed: player\nOK\n")
+                .expect("idle player mixer\n")
+        ).unwrap();
+        let mut core = Core::new().unwrap();
+        let mpc = core.run(TokioMpc::new(&server.addr(), &core.handle())).unwrap();
+        let events = mpc.idle_stream(&[SubSystem::Player, SubSystem::Mixer]);
+        let (event, events) = core.run(events.into_future()).map_err(|(e, _)| e).unwrap();
+        assert_eq!(event, Some(vec![SubSystem::Mixer]));
+        assert_eq!(core.run(mpc.run_command(Command::Stop)).unwrap(), CommandResponse::Blank);
+        let (event, _) = core.run(events.into_future()).map_err(|(e, _)| e).unwrap();
+        assert_eq!(event, Some(vec![SubSystem::Player]));
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn split_response() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .greeting("0.20.0")
+                .expect("currentsong\nstop\n")
+                .trickle("file: a.mp3\nTitle: Split\nOK\nO", 4, Duration::from_millis(5))
+                .delay(Duration::from_millis(20))
+                .send("K\n")
+        ).unwrap();
+        let mut core = Core::new().unwrap();
+        let mpc = core.run(TokioMpc::new(&server.addr(), &core.handle())).unwrap();
+        assert_eq!(mpc.version(), Version::parse("0.20.0").unwrap());
+        let song = mpc.run_command(Command::CurrentSong);
+        let stop = mpc.run_command(Command::Stop);
+        let (song, stop) = core.run(song.join(stop)).unwrap();
+        match song {
+            CommandResponse::Song(Some(song)) => {
+                assert_eq!(song.file, "a.mp3");
+                assert_eq!(song.tag(TagType::Title), Some("Split"));
+            },
+            other => panic!("expected a song, got {:?}", other),
+        }
+        assert_eq!(stop, CommandResponse::Blank);
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn song_stream() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .expect("listallinfo\nstop\n")
+                .trickle(
+                    "directory: a\nfile: a/1.mp3\nTitle: One\nfile: a/2.mp3\nOK\nOK\n",
+                    6,
+                    Duration::from_millis(1)
+                )
+        ).unwrap();
+        let mut core = Core::new().unwrap();
+        let mpc = core.run(TokioMpc::new(&server.addr(), &core.handle())).unwrap();
+        let songs = mpc.song_stream(Command::ListAllInfo(None)).collect();
+        let stop = mpc.run_command(Command::Stop);
+        let (songs, stop) = core.run(songs.join(stop)).unwrap();
+        let files: Vec<_> = songs.iter().map(|song| &song.file[..]).collect();
+        assert_eq!(files, ["a/1.mp3", "a/2.mp3"]);
+        assert_eq!(songs[0].tag(TagType::Title), Some("One"));
+        assert_eq!(stop, CommandResponse::Blank);
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn song_stream_error() {
+        let server = FakeServer::spawn(Script::new().respond(
+            "playlistinfo\n",
+            "file: a.mp3\nACK [5@0] {playlistinfo} oops\n"
+        )).unwrap();
+        let mut core = Core::new().unwrap();
+        let mpc = core.run(TokioMpc::new(&server.addr(), &core.handle())).unwrap();
+        let songs = mpc.song_stream(Command::PlaylistInfo(None));
+        let (song, songs) = core.run(songs.into_future()).map_err(|(e, _)| e).unwrap();
+        assert_eq!(song.map(|song| song.file), Some("a.mp3".to_owned()));
+        match core.run(songs.into_future()) {
+            Err((Error::Command(e), _)) => assert_eq!(e.message_text, "oops"),
+            Err((e, _)) => panic!("expected a command error, got {:?}", e),
+            Ok((song, _)) => panic!("expected a command error, got {:?}", song),
+        }
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn song_stream_dropped() {
+        let songs: String = (0..100).map(|n| format!("file: {}.mp3\n", n)).collect();
+        let server = FakeServer::spawn(
+            Script::new()
+                .respond("playlistinfo\n", &format!("{}OK\n", songs))
+                .respond("stop\n", "OK\n")
+        ).unwrap();
+        let mut core = Core::new().unwrap();
+        let mpc = core.run(TokioMpc::new(&server.addr(), &core.handle())).unwrap();
+        let first = mpc.song_stream(Command::PlaylistInfo(None)).take(1).collect();
+        assert_eq!(core.run(first).unwrap()[0].file, "0.mp3");
+        assert_eq!(core.run(mpc.run_command(Command::Stop)).unwrap(), CommandResponse::Blank);
+        server.finish().unwrap();
     }
 }
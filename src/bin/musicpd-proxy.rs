@@ -0,0 +1,514 @@
+//! A caching proxy that lets many clients share one connection to mpd.
+//!
+//! Usage: `musicpd-proxy [LISTEN_ADDR]`, listening on `127.0.0.1:6601` by default. The server is
+//! found using `MPD_HOST` and `MPD_PORT`, as for other mpd clients.
+//!
+//! Commands from every client are forwarded over a single upstream connection, which sits in
+//! `idle` whenever no command is waiting and is woken with `noidle` when one arrives. The
+//! responses to `status`, `currentsong` and `playlistinfo` are cached, and the changes reported
+//! by `idle` are used to find out when the cache is stale. They are also passed on to any client
+//! that is idling, so clients never idle on the server themselves. If the connection is lost, the
+//! proxy reconnects and tells idling clients that everything may have changed.
+
+extern crate musicpd;
+extern crate nom;
+extern crate semver;
+
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process;
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nom::IResult;
+use semver::Version;
+
+use musicpd::client::{Error, HostSpec, IdleHandle};
+use musicpd::protocol::command::{Command, CommandList, CommandListResponse, CommandResponse};
+use musicpd::protocol::server::{self, ClientRequest};
+use musicpd::reconnect::ReconnectingClient;
+use musicpd::types::{CmdError, CmdErrorType, Song, State, Status, SubSystem};
+use musicpd::util::Buffer;
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:6601";
+/// How long writing to a client can take before it is disconnected
+const WRITE_TIMEOUT_SECS: u64 = 30;
+/// Every subsystem, for when the proxy can't know what changed
+const ALL_SUBSYSTEMS: &[SubSystem] = &[
+    SubSystem::Database,
+    SubSystem::Update,
+    SubSystem::StoredPlaylist,
+    SubSystem::Playlist,
+    SubSystem::Player,
+    SubSystem::Mixer,
+    SubSystem::Output,
+    SubSystem::Options,
+    SubSystem::Sticker,
+    SubSystem::Subscription,
+    SubSystem::Message,
+];
+
+/// Responses that are answered without asking the server
+#[derive(Debug, Default)]
+struct Cache {
+    /// Bumped whenever anything is invalidated, so that a response fetched before a change isn't
+    /// stored after it
+    generation: u64,
+    /// The status, and when it was fetched
+    status: Option<(Status, Instant)>,
+    current_song: Option<Option<Song>>,
+    playlist: Option<Vec<Song>>,
+}
+
+impl Cache {
+    fn get(&self, command: &Command) -> Option<CommandResponse> {
+        match *command {
+            Command::Status => self.status.as_ref().map(|&(ref status, fetched)| {
+                let mut status = status.clone();
+                // the elapsed time moves on without the server telling us
+                if status.state == State::Play {
+                    status.elapsed = status.elapsed.map(|elapsed| {
+                        let elapsed = elapsed + fetched.elapsed();
+                        match status.duration {
+                            Some(duration) if duration < elapsed => duration,
+                            _ => elapsed,
+                        }
+                    });
+                }
+                CommandResponse::Status(status)
+            }),
+            Command::CurrentSong => self.current_song.clone().map(CommandResponse::Song),
+            Command::PlaylistInfo(None) => self.playlist.clone().map(CommandResponse::Songs),
+            _ => None,
+        }
+    }
+
+    fn store(&mut self, command: &Command, response: &CommandResponse) {
+        match (command, response) {
+            (&Command::Status, &CommandResponse::Status(ref status)) => {
+                self.status = Some((status.clone(), Instant::now()));
+            },
+            (&Command::CurrentSong, &CommandResponse::Song(ref song)) => {
+                self.current_song = Some(song.clone());
+            },
+            (&Command::PlaylistInfo(None), &CommandResponse::Songs(ref songs)) => {
+                self.playlist = Some(songs.clone());
+            },
+            _ => (),
+        }
+    }
+
+    /// Forget the responses affected by changes to the given subsystems
+    fn invalidate(&mut self, changed: &[SubSystem]) {
+        self.generation += 1;
+        // the status covers nearly every subsystem, from the volume to database updates
+        self.status = None;
+        if changed.contains(&SubSystem::Player) || changed.contains(&SubSystem::Playlist) {
+            self.current_song = None;
+        }
+        if changed.contains(&SubSystem::Playlist) {
+            self.playlist = None;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.generation += 1;
+        self.status = None;
+        self.current_song = None;
+        self.playlist = None;
+    }
+}
+
+/// A connected client, shared with the thread that passes on idle events
+#[derive(Debug)]
+struct Downstream {
+    /// Responses waiting to be written by the client's writer thread, so that a client that
+    /// stops reading only holds up itself
+    outgoing: mpsc::Sender<Vec<u8>>,
+    /// The subsystems the client is idling on, if it is idling. Empty means all of them.
+    idle: Option<Vec<SubSystem>>,
+    /// Changes the client hasn't been told about yet
+    pending: Vec<SubSystem>,
+}
+
+impl Downstream {
+    /// Write a response in one go, so it isn't interleaved with idle notifications
+    fn send<F>(&mut self, write: F) -> io::Result<()>
+        where F: FnOnce(&mut Vec<u8>) -> io::Result<()>
+    {
+        let mut buf = Vec::new();
+        write(&mut buf)?;
+        self.outgoing.send(buf).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "the client's writer thread has stopped")
+        })
+    }
+
+    fn changed(&mut self, changed: &[SubSystem]) -> io::Result<()> {
+        for sub in changed {
+            if !self.pending.contains(sub) {
+                self.pending.push(*sub);
+            }
+        }
+        self.notify()
+    }
+
+    /// Answer the client's `idle` if any of the subsystems it is waiting on have changed
+    fn notify(&mut self) -> io::Result<()> {
+        let changed: Vec<SubSystem> = match self.idle {
+            Some(ref subs) => self.pending.iter()
+                .filter(|sub| subs.is_empty() || subs.contains(sub))
+                .cloned()
+                .collect(),
+            None => return Ok(()),
+        };
+        if changed.is_empty() {
+            return Ok(());
+        }
+        self.pending.retain(|sub| !changed.contains(sub));
+        self.idle = None;
+        self.send(|w| server::write_result(w, &Ok(CommandResponse::Changed(changed))))
+    }
+}
+
+/// Write a client's responses until it disconnects, or stops reading them
+fn write_responses(mut stream: TcpStream, responses: mpsc::Receiver<Vec<u8>>) {
+    for response in responses {
+        if stream.write_all(&response).is_err() {
+            // wake the client's own thread, which is blocked reading
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+    }
+}
+
+/// A request waiting for the upstream connection, and where to send its response
+enum Job {
+    Command(Command, mpsc::Sender<Result<CommandResponse, CmdError>>),
+    CommandList(CommandList, mpsc::Sender<CommandListResponse>),
+}
+
+/// The requests waiting for the upstream connection
+#[derive(Default)]
+struct Upstream {
+    jobs: VecDeque<Job>,
+    /// Cancels the connection's `idle`, while it is idling
+    idle: Option<IdleHandle>,
+}
+
+struct Proxy {
+    version: Version,
+    upstream: Mutex<Upstream>,
+    cache: Mutex<Cache>,
+    clients: Mutex<Vec<Weak<Mutex<Downstream>>>>,
+}
+
+impl Proxy {
+    /// Talk to a client until it disconnects
+    fn serve(&self, mut stream: TcpStream) -> io::Result<()> {
+        let writer = stream.try_clone()?;
+        writer.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)))?;
+        let (outgoing, responses) = mpsc::channel();
+        thread::spawn(move || write_responses(writer, responses));
+        let client = Arc::new(Mutex::new(Downstream {
+            outgoing: outgoing,
+            idle: None,
+            pending: Vec::new(),
+        }));
+        self.clients.lock().unwrap().push(Arc::downgrade(&client));
+        client.lock().unwrap().send(|w| server::write_greeting(w, &self.version))?;
+
+        // a client that never finishes a request is dropped rather than buffered forever
+        let mut buffer = Buffer::new().max_size(Some(server::MAX_REQUEST_SIZE));
+        loop {
+            let request = match buffer.parse(server::parse_request, &mut stream) {
+                Ok(IResult::Done((), request)) => request,
+                Ok(_) => unreachable!(),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            if !self.handle(&client, request)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Respond to a request, returning false if the connection should be closed
+    fn handle(&self, client: &Mutex<Downstream>, request: Result<ClientRequest, CmdError>)
+        -> io::Result<bool>
+    {
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                client.lock().unwrap().send(|w| server::write_error(w, &e))?;
+                return Ok(true);
+            },
+        };
+        match request {
+            ClientRequest::Command(Command::NoIdle) => {
+                let mut client = client.lock().unwrap();
+                if client.idle.take().is_some() {
+                    client.send(|w| server::write_result(w, &Ok(CommandResponse::Blank)))?;
+                }
+            },
+            // like mpd, drop clients that send anything but `noidle` while idling
+            _ if client.lock().unwrap().idle.is_some() => return Ok(false),
+            ClientRequest::Command(Command::Idle(subs)) => {
+                let mut client = client.lock().unwrap();
+                client.idle = Some(subs);
+                client.notify()?;
+            },
+            ClientRequest::Command(command) => {
+                let result = self.run_command(command);
+                client.lock().unwrap().send(|w| server::write_result(w, &result))?;
+            },
+            ClientRequest::CommandList { commands, list_ok } => {
+                let response = self.run_commands(commands);
+                client.lock().unwrap()
+                    .send(|w| server::write_list_response(w, &response, list_ok))?;
+            },
+        }
+        Ok(true)
+    }
+
+    fn run_command(&self, command: Command) -> Result<CommandResponse, CmdError> {
+        check_supported(&command, 0)?;
+        let generation = {
+            let cache = self.cache.lock().unwrap();
+            if let Some(response) = cache.get(&command) {
+                return Ok(response);
+            }
+            cache.generation
+        };
+        let (reply, result) = mpsc::channel();
+        self.submit(Job::Command(command.clone(), reply));
+        let result = result.recv().unwrap_or_else(|e| fatal("the upstream thread stopped", e));
+        let mut cache = self.cache.lock().unwrap();
+        if !is_read_only(&command) {
+            cache.clear();
+        } else if let Ok(ref response) = result {
+            if cache.generation == generation {
+                cache.store(&command, response);
+            }
+        }
+        result
+    }
+
+    fn run_commands(&self, commands: CommandList) -> CommandListResponse {
+        for (idx, command) in commands.iter().enumerate() {
+            if let Err(e) = check_supported(command, idx) {
+                return CommandListResponse {
+                    responses: Vec::new(),
+                    error: Some(e),
+                };
+            }
+        }
+        let read_only = commands.iter().all(is_read_only);
+        let (reply, response) = mpsc::channel();
+        self.submit(Job::CommandList(commands, reply));
+        let response = response.recv().unwrap_or_else(|e| fatal("the upstream thread stopped", e));
+        if !read_only {
+            self.cache.lock().unwrap().clear();
+        }
+        response
+    }
+
+    /// Queue a request for the upstream connection, waking it if it is idling
+    fn submit(&self, job: Job) {
+        let mut upstream = self.upstream.lock().unwrap();
+        upstream.jobs.push_back(job);
+        if let Some(handle) = upstream.idle.take() {
+            // if this fails the connection is lost, which the upstream thread finds out itself
+            let _ = handle.noidle();
+        }
+    }
+
+    /// Run the queued requests on the upstream connection, and idle on it while there are none
+    fn drive(&self, mut upstream: ReconnectingClient) -> ! {
+        loop {
+            let job = self.upstream.lock().unwrap().jobs.pop_front();
+            match job {
+                Some(Job::Command(command, reply)) => {
+                    let result = upstream.run_command(command.clone())
+                        .map_err(|e| upstream_error(e, command.name()));
+                    // the client may have disconnected while waiting
+                    let _ = reply.send(result);
+                },
+                Some(Job::CommandList(commands, reply)) => {
+                    let first = commands.iter().next().map_or("", |command| command.name());
+                    let response = upstream.run_commands_partial(commands)
+                        .unwrap_or_else(|e| CommandListResponse {
+                            responses: Vec::new(),
+                            error: Some(upstream_error(e, first)),
+                        });
+                    let _ = reply.send(response);
+                },
+                None => {
+                    let changed = upstream.idle_with_handle(&[], |handle| {
+                        // a `noidle` sent before the `idle` would be ignored, so a job queued
+                        // since the queue was checked has to be noticed here
+                        let mut queue = self.upstream.lock().unwrap();
+                        if queue.jobs.is_empty() {
+                            queue.idle = Some(handle);
+                        } else {
+                            let _ = handle.noidle();
+                        }
+                    });
+                    self.upstream.lock().unwrap().idle = None;
+                    match changed {
+                        Ok(ref changed) if changed.is_empty() => (),
+                        Ok(changed) => self.changed(&changed),
+                        Err(e) => {
+                            eprintln!("musicpd-proxy: upstream error: {:?}", e);
+                            // don't spin if the server keeps refusing us
+                            thread::sleep(Duration::from_secs(1));
+                        },
+                    }
+                },
+            }
+        }
+    }
+
+    /// Pass on the changes reported by the server to the cache and all clients
+    fn changed(&self, changed: &[SubSystem]) {
+        self.cache.lock().unwrap().invalidate(changed);
+        self.clients.lock().unwrap().retain(|client| match client.upgrade() {
+            Some(client) => {
+                // a client that can't be written to will be dropped by its own thread
+                let _ = client.lock().unwrap().changed(changed);
+                true
+            },
+            None => false,
+        });
+    }
+}
+
+/// Whether a command can be answered without changing anything on the server
+fn is_read_only(command: &Command) -> bool {
+    match *command {
+        Command::CurrentSong
+            | Command::Status
+            | Command::Stats
+            | Command::ReplayGainStatus
+            | Command::PlaylistFind(_)
+            | Command::PlaylistId(_)
+            | Command::PlaylistInfo(_)
+            | Command::PlaylistSearch(_)
+            | Command::PlaylistChanges { .. }
+            | Command::PlaylistChangesPositionId { .. }
+            | Command::ListPlaylist(_)
+            | Command::ListPlaylistInfo(_)
+            | Command::ListPlaylists
+            | Command::Count { .. }
+            | Command::GroupCount(_)
+            | Command::ListAllInfo(_)
+            | Command::Find { .. }
+            | Command::List { .. }
+            | Command::Search { .. }
+            | Command::Commands
+            | Command::NotCommands
+            | Command::TagTypes
+            | Command::UrlHandlers
+            | Command::Decoders
+            | Command::Ping
+            | Command::StickerGet { .. }
+            | Command::StickerList(_)
+            | Command::StickerFind { .. }
+            | Command::Outputs
+            | Command::Channels
+            | Command::ListMounts
+            | Command::ListNeighbors => true,
+        _ => false,
+    }
+}
+
+/// Reject commands the proxy can't forward: idling is handled by the proxy itself, and some
+/// commands would affect every client sharing the upstream connection.
+fn check_supported(command: &Command, command_no: usize) -> Result<(), CmdError> {
+    let message = match *command {
+        Command::Idle(_) | Command::NoIdle => "idle is not allowed in command lists",
+        // the upstream connection is shared, so one client can't change its permissions
+        Command::Password(_) => "set the password for the proxy with MPD_HOST",
+        // the server would answer as if the proxy's client were local
+        Command::Config => "not supported by the proxy",
+        Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::ReadMessages => "not supported by the proxy",
+        _ => return Ok(()),
+    };
+    Err(CmdError {
+        error_type: CmdErrorType::Unknown,
+        command_no: command_no,
+        command_name: command.name().to_owned(),
+        message_text: message.to_owned(),
+    })
+}
+
+/// Report an error from the upstream connection to the client that sent the command
+fn upstream_error(e: Error, command_name: &str) -> CmdError {
+    match e {
+        Error::Command(e) => e,
+        e => CmdError {
+            error_type: CmdErrorType::Unknown,
+            command_no: 0,
+            command_name: command_name.to_owned(),
+            message_text: format!("lost connection to the server: {}", e),
+        },
+    }
+}
+
+fn fatal<E: fmt::Debug>(context: &str, e: E) -> ! {
+    eprintln!("musicpd-proxy: {}: {:?}", context, e);
+    process::exit(1)
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let listen = args.next().unwrap_or_else(|| DEFAULT_LISTEN.to_owned());
+    let upstream = HostSpec::from_env()
+        .unwrap_or_else(|e| fatal("invalid MPD_HOST or MPD_PORT", e));
+
+    let address = format!("{:?}", upstream.address);
+    let client = ReconnectingClient::connect(upstream)
+        .unwrap_or_else(|e| fatal(&format!("could not connect to {}", address), e));
+    let listener = TcpListener::bind(&listen[..])
+        .unwrap_or_else(|e| fatal(&format!("could not listen on {}", listen), e));
+
+    let proxy = Arc::new(Proxy {
+        version: client.version(),
+        upstream: Mutex::new(Upstream::default()),
+        cache: Mutex::new(Cache::default()),
+        clients: Mutex::new(Vec::new()),
+    });
+
+    // changes made while the connection was lost weren't reported
+    let watcher = proxy.clone();
+    let client = client.on_reconnect(move |event| {
+        eprintln!("musicpd-proxy: reconnected to the server: {}", event.reason);
+        watcher.changed(ALL_SUBSYSTEMS);
+    });
+    let driver = proxy.clone();
+    thread::spawn(move || driver.drive(client));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("musicpd-proxy: could not accept connection: {:?}", e);
+                // don't spin if we've run out of file descriptors
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            },
+        };
+        let proxy = proxy.clone();
+        thread::spawn(move || {
+            if let Err(e) = proxy.serve(stream) {
+                eprintln!("musicpd-proxy: client error: {:?}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,784 @@
+//! A command-line client for mpd, in the style of `mpc`.
+//!
+//! Run `musicpd --help` for the list of commands.
+
+extern crate musicpd;
+
+use std::env;
+use std::fmt;
+use std::process;
+use std::time::Duration;
+
+use musicpd::client::{Client, Error, HostSpec};
+use musicpd::protocol::command::{Command, CommandList, CommandResponse};
+use musicpd::types::{Query, Range, Selector, SingleOrRange, Song, State, Stats, Status, SubSystem,
+    TagType};
+
+const DEFAULT_FORMAT: &str = "[[%artist% - ]%title%]|%file%";
+
+const USAGE: &str = "\
+usage: musicpd [OPTIONS] [COMMAND [ARGS...]]
+
+Options:
+  --host HOST             the server to connect to (default: $MPD_HOST, or localhost).
+                          This may be a host name, the path of a unix socket, or @ and
+                          the name of an abstract socket, optionally after PASSWORD@
+  --port PORT             the port to connect to (default: $MPD_PORT, or 6600)
+  -f, --format FORMAT     how to print songs (default: \"[[%artist% - ]%title%]|%file%\").
+                          %tag% is replaced by a tag, or by file, time, position or id;
+                          [...] is left out if a tag inside it is missing; and | separates
+                          alternatives, the first that can be filled in being used
+  --help                  print this message
+
+Playback:
+  status                  show what is playing (the default)
+  current                 show the current song
+  play [POSITION]         start playing, from the song at POSITION if given
+  pause                   pause playback
+  toggle                  toggle between playing and paused
+  stop                    stop playback
+  next                    play the next song in the queue
+  prev                    play the previous song in the queue
+  seek [+-]TIME           seek within the current song, where TIME is [[HH:]MM:]SS or N%
+  volume [[+-]N]          show or change the volume
+  repeat [on|off]         toggle or set repeat mode
+  random [on|off]         toggle or set random mode
+  single [on|off]         toggle or set single mode
+  consume [on|off]        toggle or set consume mode
+
+Queue:
+  playlist                list the songs in the queue
+  add URI...              add songs to the queue
+  del POSITION...         remove songs from the queue, where POSITION may be a range N-M
+  move FROM TO            move a song within the queue
+  clear                   remove all songs from the queue
+  shuffle                 shuffle the queue
+
+Stored playlists:
+  lsplaylists             list the stored playlists
+  lsplaylist NAME         list the songs in a stored playlist
+  load NAME               add a stored playlist to the queue
+  save NAME               save the queue as a stored playlist
+  rm NAME                 delete a stored playlist
+
+Database:
+  find TAG VALUE...       list songs exactly matching all the given tags
+  findadd TAG VALUE...    add songs exactly matching all the given tags to the queue
+  search TAG VALUE...     list songs with tags containing all the given values
+  list TAG [TAG VALUE...] list the values of a tag, optionally only for matching songs
+  stats                   show database statistics
+
+Outputs:
+  outputs                 list the audio outputs, and whether they are enabled
+  enable N...             enable audio outputs, by the numbers shown by outputs
+  disable N...            disable audio outputs
+  toggleoutput N...       turn audio outputs on if they are off, and off if they are on
+
+Waiting for changes:
+  idle [SUBSYSTEM...]     wait for a change and print the subsystems that changed
+  idleloop [SUBSYSTEM...] keep printing the subsystems that change
+
+Positions in the queue and output numbers start at 1.
+";
+
+#[derive(Debug)]
+enum CliError {
+    /// The command line couldn't be understood
+    Usage(String),
+    /// Talking to the server failed
+    Client(Error),
+}
+
+impl From<Error> for CliError {
+    fn from(e: Error) -> Self {
+        CliError::Client(e)
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CliError::Usage(ref message) => f.write_str(message),
+            CliError::Client(Error::Command(ref e)) => write!(f, "mpd error: {}", e.message_text),
+            CliError::Client(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+fn usage<T, S: Into<String>>(message: S) -> Result<T, CliError> {
+    Err(CliError::Usage(message.into()))
+}
+
+/// The options given before the command
+#[derive(Debug)]
+struct Options {
+    host: String,
+    port: Option<String>,
+    format: String,
+    command: Vec<String>,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, CliError> {
+        let mut options = Options {
+            host: env::var("MPD_HOST").unwrap_or_else(|_| "localhost".to_owned()),
+            port: env::var("MPD_PORT").ok(),
+            format: DEFAULT_FORMAT.to_owned(),
+            command: Vec::new(),
+        };
+        while let Some(arg) = args.next() {
+            match &arg[..] {
+                "--help" => options.command = vec!["help".to_owned()],
+                "--host" => options.host = option_value(&arg, args.next())?,
+                "--port" => options.port = Some(option_value(&arg, args.next())?),
+                "-f" | "--format" => options.format = option_value(&arg, args.next())?,
+                option if option.starts_with('-') => {
+                    return usage(format!("unknown option {}", option));
+                },
+                _ => {
+                    options.command.push(arg);
+                    options.command.extend(args);
+                    break;
+                },
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn option_value(option: &str, value: Option<String>) -> Result<String, CliError> {
+    match value {
+        Some(value) => Ok(value),
+        None => usage(format!("{} needs a value", option)),
+    }
+}
+
+fn connect(host: &str, port: Option<&str>) -> Result<Client, CliError> {
+    let spec = match HostSpec::parse(host, port) {
+        Ok(spec) => spec,
+        Err(e) => return usage(e.to_string()),
+    };
+    Ok(Client::connect_host(&spec)?)
+}
+
+/// Check the number of arguments to a command
+fn check_args(command: &str, args: &[String], min: usize, max: usize) -> Result<(), CliError> {
+    if args.len() < min || args.len() > max {
+        return usage(format!("wrong number of arguments for {}", command));
+    }
+    Ok(())
+}
+
+/// Parse a 1-based queue position into the 0-based position the server uses
+fn parse_position(arg: &str) -> Result<u32, CliError> {
+    match arg.parse::<u32>() {
+        Ok(pos) if pos > 0 => Ok(pos - 1),
+        _ => usage(format!("invalid position {:?}", arg)),
+    }
+}
+
+/// Parse a 1-based output number, as printed by `outputs`, into the output's id
+fn parse_output(arg: &str) -> Result<u32, CliError> {
+    match arg.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(n - 1),
+        _ => usage(format!("invalid output {:?}", arg)),
+    }
+}
+
+/// Parse a 1-based position, or an inclusive range of positions `N-M`
+fn parse_positions(arg: &str) -> Result<SingleOrRange, CliError> {
+    let mut parts = arg.splitn(2, '-');
+    let start = parse_position(parts.next().unwrap())?;
+    match parts.next() {
+        None => Ok(SingleOrRange::Single(start)),
+        Some(end) => {
+            let end = parse_position(end)?;
+            if end < start {
+                return usage(format!("invalid range {:?}", arg));
+            }
+            Ok(SingleOrRange::Range(Range { start: start, end: Some(end + 1) }))
+        },
+    }
+}
+
+/// Parse a time in `[[HH:]MM:]SS` form
+fn parse_time(arg: &str) -> Option<Duration> {
+    let mut secs = 0;
+    for (idx, part) in arg.split(':').enumerate() {
+        if idx > 2 {
+            return None;
+        }
+        secs = secs * 60 + part.parse::<u64>().ok()?;
+    }
+    Some(Duration::from_secs(secs))
+}
+
+/// Work out where to seek to from the argument to `seek`
+fn parse_seek(arg: &str, status: &Status) -> Result<Duration, CliError> {
+    let (sign, time) = match arg.chars().next() {
+        Some(sign) if sign == '+' || sign == '-' => (Some(sign), &arg[1..]),
+        _ => (None, arg),
+    };
+    let offset = if time.ends_with('%') {
+        let percent = match time[..time.len() - 1].parse::<u64>() {
+            Ok(percent) => percent,
+            Err(_) => return usage(format!("invalid time {:?}", arg)),
+        };
+        let duration = match status.duration {
+            Some(duration) => duration,
+            None => return usage("the current song has no known length"),
+        };
+        Duration::from_millis(duration_millis(duration) * percent / 100)
+    } else {
+        match parse_time(time) {
+            Some(time) => time,
+            None => return usage(format!("invalid time {:?}", arg)),
+        }
+    };
+    let elapsed = status.elapsed.unwrap_or(Duration::from_secs(0));
+    Ok(match sign {
+        Some('+') => elapsed + offset,
+        Some(_) => elapsed.checked_sub(offset).unwrap_or(Duration::from_secs(0)),
+        None => offset,
+    })
+}
+
+fn parse_on_off(command: &str, args: &[String]) -> Result<Option<bool>, CliError> {
+    check_args(command, args, 0, 1)?;
+    match args.first().map(|arg| &arg[..]) {
+        None => Ok(None),
+        Some("on") => Ok(Some(true)),
+        Some("off") => Ok(Some(false)),
+        Some(arg) => usage(format!("expected on or off, got {:?}", arg)),
+    }
+}
+
+/// Parse `TAG VALUE` pairs into a query
+fn parse_query(command: &str, args: &[String]) -> Result<Query, CliError> {
+    if args.is_empty() || args.len() % 2 != 0 {
+        return usage(format!("{} needs pairs of TAG VALUE", command));
+    }
+    let mut tags = Vec::new();
+    for pair in args.chunks(2) {
+        match Selector::from_name(pair[0].as_bytes()) {
+            Some(selector) => tags.push((selector, pair[1].clone())),
+            None => return usage(format!("unknown tag {:?}", pair[0])),
+        }
+    }
+    Ok(tags.into())
+}
+
+fn parse_tag(arg: &str) -> Result<TagType, CliError> {
+    match TagType::from_name(arg.as_bytes()) {
+        Some(tag) => Ok(tag),
+        None => usage(format!("unknown tag {:?}", arg)),
+    }
+}
+
+fn parse_subsystems(args: &[String]) -> Result<Vec<SubSystem>, CliError> {
+    args.iter()
+        .map(|arg| match SubSystem::from_name(arg.as_bytes()) {
+            Some(sub) => Ok(sub),
+            None => usage(format!("unknown subsystem {:?}", arg)),
+        })
+        .collect()
+}
+
+fn duration_millis(time: Duration) -> u64 {
+    time.as_secs() * 1000 + u64::from(time.subsec_nanos() / 1_000_000)
+}
+
+/// Format a duration as `M:SS`, or `H:MM:SS` if it is an hour or longer
+fn format_time(time: Duration) -> String {
+    let secs = time.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// Format a long duration as e.g. `2 days, 3:04:05`
+fn format_long_time(time: Duration) -> String {
+    let secs = time.as_secs();
+    format!("{} days, {}:{:02}:{:02}", secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60)
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
+
+/// Get a value for a `%name%` in a song format
+fn song_field(song: &Song, name: &str) -> Option<String> {
+    match name {
+        "file" => Some(song.file.clone()),
+        "time" => song.duration.map(format_time),
+        "position" => song.position.map(|pos| (pos + 1).to_string()),
+        "id" => song.id.map(|id| id.to_string()),
+        name => TagType::from_name(name.as_bytes())
+            .and_then(|tag| song.tag(tag))
+            .map(|value| value.to_owned()),
+    }
+}
+
+/// Render alternatives separated by `|`, up to the end of the format or an unmatched `]`.
+///
+/// Returns the first alternative that could be filled in, and the rest of the format.
+fn render_format<'a>(format: &'a str, song: &Song) -> (Option<String>, &'a str) {
+    let mut chosen = None;
+    let mut current = Some(String::new());
+    let mut rest = format;
+    loop {
+        let mut chars = rest.chars();
+        match chars.next() {
+            None | Some(']') => break,
+            Some('|') => {
+                if chosen.is_none() {
+                    chosen = current;
+                }
+                current = Some(String::new());
+                rest = chars.as_str();
+            },
+            Some('[') => {
+                let (group, after) = render_format(chars.as_str(), song);
+                // skip the closing bracket
+                rest = after.get(1..).unwrap_or("");
+                if let (Some(current), Some(group)) = (current.as_mut(), group) {
+                    current.push_str(&group);
+                }
+            },
+            Some('%') => {
+                let name_and_rest = chars.as_str();
+                match name_and_rest.find('%') {
+                    Some(end) => {
+                        match song_field(song, &name_and_rest[..end]) {
+                            Some(value) => if let Some(current) = current.as_mut() {
+                                current.push_str(&value);
+                            },
+                            None => current = None,
+                        }
+                        rest = &name_and_rest[end + 1..];
+                    },
+                    None => {
+                        if let Some(current) = current.as_mut() {
+                            current.push('%');
+                        }
+                        rest = name_and_rest;
+                    },
+                }
+            },
+            Some(c) => {
+                // a backslash makes the next character literal
+                let c = if c == '\\' { chars.next().unwrap_or('\\') } else { c };
+                if let Some(current) = current.as_mut() {
+                    current.push(c);
+                }
+                rest = chars.as_str();
+            },
+        }
+    }
+    (chosen.or(current), rest)
+}
+
+/// Format a song using an mpc style format string
+fn format_song(format: &str, song: &Song) -> String {
+    let mut result = String::new();
+    let mut rest = format;
+    loop {
+        let (rendered, after) = render_format(rest, song);
+        result.push_str(&rendered.unwrap_or_default());
+        if after.is_empty() {
+            return result;
+        }
+        // an unmatched `]` is printed as it is
+        result.push(']');
+        rest = &after[1..];
+    }
+}
+
+fn get_status(client: &mut Client) -> Result<Status, CliError> {
+    match client.run_command(Command::Status)? {
+        CommandResponse::Status(status) => Ok(status),
+        _ => unreachable!(),
+    }
+}
+
+fn get_current_song(client: &mut Client) -> Result<Option<Song>, CliError> {
+    match client.run_command(Command::CurrentSong)? {
+        CommandResponse::Song(song) => Ok(song),
+        _ => unreachable!(),
+    }
+}
+
+fn print_songs(client: &mut Client, format: &str, command: Command) -> Result<(), CliError> {
+    // printed as they arrive, so that a huge result doesn't have to fit in memory
+    for song in client.iter_songs(command)? {
+        println!("{}", format_song(format, &song?));
+    }
+    Ok(())
+}
+
+fn print_status(client: &mut Client, format: &str) -> Result<(), CliError> {
+    let status = get_status(client)?;
+    if status.state != State::Stop {
+        if let Some(song) = get_current_song(client)? {
+            println!("{}", format_song(format, &song));
+        }
+        let state = if status.state == State::Play { "playing" } else { "paused" };
+        let position = status.song.map(|pos| (pos + 1).to_string()).unwrap_or_default();
+        let elapsed = status.elapsed.unwrap_or(Duration::from_secs(0));
+        let time = match status.duration {
+            Some(duration) if duration.as_secs() > 0 => format!(
+                "{}/{} ({}%)",
+                format_time(elapsed),
+                format_time(duration),
+                duration_millis(elapsed) * 100 / duration_millis(duration)
+            ),
+            _ => format_time(elapsed),
+        };
+        println!("[{}] #{}/{}   {}", state, position, status.playlist_length, time);
+    }
+    if let Some(job) = status.updating_db {
+        println!("Updating DB (#{}) ...", job);
+    }
+    println!("volume: {:>3}%   repeat: {}   random: {}   single: {}   consume: {}",
+             status.volume, on_off(status.repeat), on_off(status.random), on_off(status.single),
+             on_off(status.consume));
+    if let Some(ref error) = status.error {
+        println!("ERROR: {}", error);
+    }
+    Ok(())
+}
+
+fn print_stats(stats: &Stats) {
+    println!("Artists: {:>6}", stats.artists);
+    println!("Albums:  {:>6}", stats.albums);
+    println!("Songs:   {:>6}", stats.songs);
+    println!();
+    println!("Play Time:    {}", format_long_time(stats.playtime));
+    println!("Uptime:       {}", format_long_time(stats.uptime));
+    println!("DB Updated:   {}", stats.db_update);
+    println!("DB Play Time: {}", format_long_time(stats.db_playtime));
+}
+
+fn run_command(client: &mut Client, format: &str, name: &str, args: &[String])
+    -> Result<(), CliError>
+{
+    // commands that change playback print the status afterwards, like mpc
+    let command = match name {
+        "status" => {
+            check_args(name, args, 0, 0)?;
+            return print_status(client, format);
+        },
+        "current" => {
+            check_args(name, args, 0, 0)?;
+            if let Some(song) = get_current_song(client)? {
+                println!("{}", format_song(format, &song));
+            }
+            return Ok(());
+        },
+        "play" => {
+            check_args(name, args, 0, 1)?;
+            match args.first() {
+                Some(pos) => Command::Play(parse_position(pos)?),
+                None => {
+                    let status = get_status(client)?;
+                    match status.state {
+                        State::Pause => Command::Pause(false),
+                        _ => Command::Play(status.song.unwrap_or(0)),
+                    }
+                },
+            }
+        },
+        "pause" => {
+            check_args(name, args, 0, 0)?;
+            Command::Pause(true)
+        },
+        "toggle" => {
+            check_args(name, args, 0, 0)?;
+            let status = get_status(client)?;
+            match status.state {
+                State::Play => Command::Pause(true),
+                State::Pause => Command::Pause(false),
+                State::Stop => Command::Play(status.song.unwrap_or(0)),
+            }
+        },
+        "stop" => {
+            check_args(name, args, 0, 0)?;
+            Command::Stop
+        },
+        "next" => {
+            check_args(name, args, 0, 0)?;
+            Command::Next
+        },
+        "prev" => {
+            check_args(name, args, 0, 0)?;
+            Command::Previous
+        },
+        "seek" => {
+            check_args(name, args, 1, 1)?;
+            let status = get_status(client)?;
+            Command::SeekCurrent(parse_seek(&args[0], &status)?)
+        },
+        "volume" => {
+            check_args(name, args, 0, 1)?;
+            let arg = match args.first() {
+                Some(arg) => arg,
+                None => {
+                    println!("volume: {}%", get_status(client)?.volume);
+                    return Ok(());
+                },
+            };
+            let (sign, number) = match arg.chars().next() {
+                Some(sign) if sign == '+' || sign == '-' => (Some(sign), &arg[1..]),
+                _ => (None, &arg[..]),
+            };
+            let change = match number.parse::<i32>() {
+                Ok(change) => change,
+                Err(_) => return usage(format!("invalid volume {:?}", arg)),
+            };
+            let volume = match sign {
+                Some('+') => i32::from(get_status(client)?.volume) + change,
+                Some(_) => i32::from(get_status(client)?.volume) - change,
+                None => change,
+            };
+            Command::Volume(volume.max(0).min(100) as u8)
+        },
+        "repeat" | "random" | "single" | "consume" => {
+            let on = match parse_on_off(name, args)? {
+                Some(on) => on,
+                None => {
+                    let status = get_status(client)?;
+                    !match name {
+                        "repeat" => status.repeat,
+                        "random" => status.random,
+                        "single" => status.single,
+                        _ => status.consume,
+                    }
+                },
+            };
+            match name {
+                "repeat" => Command::Repeat(on),
+                "random" => Command::Random(on),
+                "single" => Command::Single(on),
+                _ => Command::Consume(on),
+            }
+        },
+        _ => return run_other_command(client, format, name, args),
+    };
+    client.run_command(command)?;
+    print_status(client, format)
+}
+
+/// Run a command that doesn't change playback
+fn run_other_command(client: &mut Client, format: &str, name: &str, args: &[String])
+    -> Result<(), CliError>
+{
+    match name {
+        "playlist" => {
+            check_args(name, args, 0, 0)?;
+            print_songs(client, format, Command::PlaylistInfo(None))?;
+        },
+        "add" => {
+            if args.is_empty() {
+                return usage("add needs at least one uri");
+            }
+            let mut list = CommandList::new();
+            for uri in args {
+                list.push(Command::Add(uri.clone()));
+            }
+            client.run_commands(list)?;
+        },
+        "del" => {
+            if args.is_empty() {
+                return usage("del needs at least one position");
+            }
+            let mut positions = args.iter()
+                .map(|arg| parse_positions(arg))
+                .collect::<Result<Vec<_>, _>>()?;
+            // delete from the end, so earlier deletions don't move later positions
+            positions.sort_by_key(|positions| match *positions {
+                SingleOrRange::Single(pos) => pos,
+                SingleOrRange::Range(range) => range.start,
+            });
+            let mut list = CommandList::new();
+            for positions in positions.into_iter().rev() {
+                list.push(Command::Delete(positions));
+            }
+            client.run_commands(list)?;
+        },
+        "move" => {
+            check_args(name, args, 2, 2)?;
+            client.run_command(Command::Move {
+                from: SingleOrRange::Single(parse_position(&args[0])?),
+                to: parse_position(&args[1])?,
+            })?;
+        },
+        "clear" => {
+            check_args(name, args, 0, 0)?;
+            client.run_command(Command::Clear)?;
+        },
+        "shuffle" => {
+            check_args(name, args, 0, 0)?;
+            let length = get_status(client)?.playlist_length;
+            client.run_command(Command::Shuffle(Range { start: 0, end: Some(length) }))?;
+        },
+        "lsplaylists" => {
+            check_args(name, args, 0, 0)?;
+            match client.run_command(Command::ListPlaylists)? {
+                CommandResponse::Playlists(playlists) => for playlist in playlists {
+                    println!("{}", playlist.name);
+                },
+                _ => unreachable!(),
+            }
+        },
+        "lsplaylist" => {
+            check_args(name, args, 1, 1)?;
+            print_songs(client, format, Command::ListPlaylistInfo(args[0].clone()))?;
+        },
+        "load" => {
+            check_args(name, args, 1, 1)?;
+            client.run_command(Command::Load { name: args[0].clone(), range: None })?;
+        },
+        "save" => {
+            check_args(name, args, 1, 1)?;
+            client.run_command(Command::Save(args[0].clone()))?;
+        },
+        "rm" => {
+            check_args(name, args, 1, 1)?;
+            client.run_command(Command::Remove(args[0].clone()))?;
+        },
+        "find" => {
+            let query = parse_query(name, args)?;
+            print_songs(client, format, Command::Find { query: query, window: None })?;
+        },
+        "findadd" => {
+            client.run_command(Command::FindAdd(parse_query(name, args)?))?;
+        },
+        "search" => {
+            let query = parse_query(name, args)?;
+            print_songs(client, format, Command::Search { query: query, window: None })?;
+        },
+        "list" => {
+            if args.is_empty() {
+                return usage("list needs a tag");
+            }
+            let tag = parse_tag(&args[0])?;
+            let query = if args.len() > 1 { parse_query(name, &args[1..])? } else { Query::all() };
+            let command = Command::List { tag: tag, query: query, group: Vec::new() };
+            match client.run_command(command)? {
+                CommandResponse::TagValues(groups) => for group in groups {
+                    for value in group.values {
+                        println!("{}", value);
+                    }
+                },
+                _ => unreachable!(),
+            }
+        },
+        "stats" => {
+            check_args(name, args, 0, 0)?;
+            match client.run_command(Command::Stats)? {
+                CommandResponse::Stats(stats) => print_stats(&stats),
+                _ => unreachable!(),
+            }
+        },
+        "outputs" => {
+            check_args(name, args, 0, 0)?;
+            for output in client.outputs()? {
+                let state = if output.enabled { "enabled" } else { "disabled" };
+                println!("Output {} ({}) is {}", output.id + 1, output.name, state);
+            }
+        },
+        "enable" | "disable" | "toggleoutput" => {
+            if args.is_empty() {
+                return usage(format!("{} needs at least one output", name));
+            }
+            let mut list = CommandList::new();
+            for arg in args {
+                let id = parse_output(arg)?;
+                list.push(match name {
+                    "enable" => Command::EnableOutput(id),
+                    "disable" => Command::DisableOutput(id),
+                    _ => Command::ToggleOutput(id),
+                });
+            }
+            client.run_commands(list)?;
+        },
+        "idle" | "idleloop" => {
+            let subsystems = parse_subsystems(args)?;
+            loop {
+                for sub in client.idle(&subsystems)? {
+                    println!("{}", sub);
+                }
+                if name == "idle" {
+                    break;
+                }
+            }
+        },
+        _ => return usage(format!("unknown command {:?}", name)),
+    }
+    Ok(())
+}
+
+fn run() -> Result<(), CliError> {
+    let options = Options::parse(env::args().skip(1))?;
+    let (name, args) = match options.command.split_first() {
+        Some((name, args)) => (&name[..], args),
+        None => ("status", &[][..]),
+    };
+    if name == "help" {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    let mut client = connect(&options.host, options.port.as_ref().map(|port| &port[..]))?;
+    run_command(&mut client, &options.format, name, args)
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("musicpd: {}", e);
+        if let CliError::Usage(_) = e {
+            eprintln!("Try 'musicpd --help' for more information.");
+        }
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use musicpd::types::Tag;
+
+    fn song() -> Song {
+        let mut song = Song::new("music/song.flac");
+        song.duration = Some(Duration::from_secs(125));
+        song.add_tag(Tag { tag_type: TagType::Title, value: "Pigs".into() });
+        song
+    }
+
+    #[test]
+    fn formats() {
+        let song = song();
+        assert_eq!(format_song(DEFAULT_FORMAT, &song), "Pigs");
+        assert_eq!(format_song("%title% (%time%)", &song), "Pigs (2:05)");
+        assert_eq!(format_song("[%artist% - ]%title%", &song), "Pigs");
+        assert_eq!(format_song("%artist%|%file%", &song), "music/song.flac");
+        assert_eq!(format_song("%artist%|%album%|[%time%]", &song), "2:05");
+        assert_eq!(format_song("%artist% - %title%", &song), "");
+        assert_eq!(format_song("\\[%title%\\]]", &song), "[Pigs]]");
+    }
+
+    #[test]
+    fn arguments() {
+        assert_eq!(parse_time("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_time("1:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_time("1:2:3:4"), None);
+        assert_eq!(parse_positions("3").unwrap(), SingleOrRange::Single(2));
+        assert_eq!(
+            parse_positions("3-5").unwrap(),
+            SingleOrRange::Range(Range { start: 2, end: Some(5) })
+        );
+        assert!(parse_positions("0").is_err());
+        assert!(parse_positions("5-3").is_err());
+    }
+}
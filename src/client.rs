@@ -1,31 +1,276 @@
+use std::collections::VecDeque;
+use std::env;
 use std::net;
 use std::io;
 use std::io::prelude::*;
 use std::error::Error as StdError;
+use std::fmt;
 use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
 
 use semver::Version;
 use nom::{IResult, ErrorKind};
 #[cfg(feature = "verbose-errors")]
 use nom::Err as NomErr;
 
-use protocol::command::{CommandList, CommandResponse};
-use protocol::{Dispatch, ParseResponse, parse_handshake,};
+use protocol::command::{Command, CommandList, CommandListResponse, CommandResponse};
+use protocol::command::parse_next_song;
+use protocol::{Dispatch, ParseResponse, parse_handshake, parse_ok, parse_result};
+use sticker::Stickers;
+use types::{CmdError, CmdErrorType, Decoder, Message, Output, Song, SubSystem, TagType};
 use util::Buffer;
 
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
+    /// The server closed the connection
+    Closed,
+    /// The server responded with an error (`ACK`)
+    Command(CmdError),
     #[cfg(not(feature = "verbose-errors"))]
     Parse(ErrorKind),
     #[cfg(feature = "verbose-errors")]
     Parse(Box<StdError>),
 }
 
-// use a buffered reader, but get inner for writes
+/// The default port mpd listens on
+pub const DEFAULT_PORT: u16 = 6600;
+
+/// The largest response a client accepts unless told otherwise, in bytes
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Where the server is listening
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    /// A host name or IP address, and a port
+    Tcp(String, u16),
+    /// The path of a unix domain socket
+    Unix(PathBuf),
+    /// A name in the Linux abstract socket namespace (without the leading `@`)
+    Abstract(String),
+}
+
+/// A server address and password, in the form used by the `MPD_HOST` and `MPD_PORT`
+/// environment variables
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostSpec {
+    pub address: Address,
+    pub password: Option<String>,
+}
+
+impl HostSpec {
+    /// Parse a host in the form `[password@]host`, where host is a host name, the path of a unix
+    /// socket (`/run/mpd/socket`), or an abstract socket name (`@mpd`).
+    ///
+    /// The port is only used for TCP connections, and defaults to 6600. A host starting with `/`
+    /// is always a socket path, so an `@` in it isn't taken as the end of a password.
+    pub fn parse(host: &str, port: Option<&str>) -> io::Result<HostSpec> {
+        let (password, host) = match host.find('@') {
+            Some(idx) if idx > 0 && !host.starts_with('/') => {
+                (Some(host[..idx].to_owned()), &host[idx + 1..])
+            },
+            _ => (None, host),
+        };
+        let address = if host.starts_with('/') {
+            Address::Unix(PathBuf::from(host))
+        } else if host.starts_with('@') {
+            Address::Abstract(host[1..].to_owned())
+        } else {
+            let port = match port {
+                Some(port) => port.parse().map_err(|_| io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid port {:?}", port)
+                ))?,
+                None => DEFAULT_PORT,
+            };
+            Address::Tcp(host.to_owned(), port)
+        };
+        Ok(HostSpec {
+            address: address,
+            password: password,
+        })
+    }
+
+    /// Read the host from `MPD_HOST` and `MPD_PORT`, defaulting to port 6600 on localhost
+    pub fn from_env() -> io::Result<HostSpec> {
+        let host = env::var("MPD_HOST").unwrap_or_else(|_| "localhost".to_owned());
+        let port = env::var("MPD_PORT").ok();
+        HostSpec::parse(&host, port.as_ref().map(|port| &port[..]))
+    }
+}
+
+/// How long to wait for the server before giving up. `None` means waiting forever, which is the
+/// default.
+///
+/// If a read or write times out, the connection is left part way through a response and
+/// shouldn't be used again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// How long to wait for each address when connecting over TCP
+    pub connect: Option<Duration>,
+    /// How long to wait for the server to respond
+    pub read: Option<Duration>,
+    /// How long to wait for the server to accept a request
+    pub write: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Use the same timeout for connecting, reading and writing
+    pub fn new(timeout: Duration) -> Timeouts {
+        Timeouts {
+            connect: Some(timeout),
+            read: Some(timeout),
+            write: Some(timeout),
+        }
+    }
+}
+
+/// The connection to the server
+#[derive(Debug)]
+enum Stream {
+    Tcp(net::TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Stream> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(ref stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(ref stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(ref stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+// like the sockets themselves, a shared reference can be read from and written to
+impl<'a> Read for &'a Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match **self {
+            Stream::Tcp(ref stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl<'a> Write for &'a Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match **self {
+            Stream::Tcp(ref stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match **self {
+            Stream::Tcp(ref stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(ref stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+/// Connect over TCP, trying each address the host resolves to in turn
+fn connect_tcp<A: net::ToSocketAddrs>(addr: A, timeout: Option<Duration>) -> io::Result<Stream> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return net::TcpStream::connect(addr).map(Stream::Tcp),
+    };
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match net::TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(Stream::Tcp(stream)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "the host name didn't resolve to any addresses"
+    )))
+}
+
+/// Read a response, reporting the connection being closed as `Error::Closed`
+fn read_response<F, O>(buffer: &mut Buffer, stream: &Stream, parser: F) -> Result<O, Error>
+    where F: FnMut(&[u8]) -> IResult<&[u8], O>
+{
+    match buffer.parse(parser, stream) {
+        Ok(IResult::Done(_, v)) => Ok(v),
+        Ok(IResult::Incomplete(_)) => unreachable!(),
+        Ok(IResult::Error(e)) => Err(Error::from(e)),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(Error::Closed),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &Path) -> io::Result<Stream> {
+    UnixStream::connect(path).map(Stream::Unix)
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_: &Path) -> io::Result<Stream> {
+    Err(io::Error::new(io::ErrorKind::Other, "unix sockets are not supported on this platform"))
+}
+
+#[cfg(target_os = "linux")]
+fn connect_abstract(name: &str) -> io::Result<Stream> {
+    let addr = ::std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+    UnixStream::connect_addr(&addr).map(Stream::Unix)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_abstract(_: &str) -> io::Result<Stream> {
+    Err(io::Error::new(io::ErrorKind::Other, "abstract sockets are only supported on Linux"))
+}
+
+// responses are read through the buffer, which keeps anything read past the end of one
 pub struct Client {
-    stream: io::BufReader<net::TcpStream>,
-    version: Version
+    stream: Stream,
+    buffer: Buffer,
+    version: Version,
+    timeouts: Timeouts,
+    last_used: Instant,
 }
 
 impl From<io::Error> for Error {
@@ -34,6 +279,12 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<CmdError> for Error {
+    fn from(e: CmdError) -> Self {
+        Error::Command(e)
+    }
+}
+
 #[cfg(not(feature = "verbose-errors"))]
 impl From<ErrorKind> for Error {
     fn from(e: ErrorKind) -> Self {
@@ -48,34 +299,934 @@ impl<P: Debug + 'static> From<NomErr<P>> for Error {
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Closed => write!(f, "the server closed the connection"),
+            Error::Command(ref e) => write!(f, "{}", e),
+            Error::Parse(ref e) => write!(f, "could not parse the response: {:?}", e),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl Error {
+    /// Whether the server refused to run a command because the connection doesn't have
+    /// permission, e.g. because the right password hasn't been given
+    pub fn is_permission_denied(&self) -> bool {
+        match *self {
+            Error::Command(ref e) => e.error_type == CmdErrorType::Permission,
+            _ => false,
+        }
+    }
+
+    /// Whether the server rejected a password
+    pub fn is_incorrect_password(&self) -> bool {
+        match *self {
+            Error::Command(ref e) => e.error_type == CmdErrorType::Password,
+            _ => false,
+        }
+    }
+
+    /// Whether the server didn't respond within the read or write timeout
+    pub fn is_timeout(&self) -> bool {
+        match *self {
+            Error::Io(ref e) => match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// The commands a connection may run, from `commands` and `notcommands`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Permissions {
+    /// The commands the connection may run
+    pub allowed: Vec<String>,
+    /// The commands the connection may not run, e.g. until a password is given
+    pub denied: Vec<String>,
+}
+
+impl Permissions {
+    /// Whether the connection may run the command
+    pub fn allows(&self, command: &Command) -> bool {
+        self.allowed.iter().any(|name| name == command.name())
+    }
+}
+
 impl Client {
+    /// Connect to a server over TCP
     pub fn connect<A: net::ToSocketAddrs>(addr: A) -> Result<Client, Error> {
-        let mut stream = io::BufReader::new(net::TcpStream::connect(addr)?);
-        let version = match Buffer::parse(parse_handshake, &mut stream) {
-            IResult::Done(_, v) => v,
-            IResult::Incomplete(_) => unreachable!(),
-            IResult::Error(e) => { return Err(Error::from(e)) }
+        Client::handshake(connect_tcp(addr, None)?, Timeouts::default())
+    }
+
+    /// Connect to a server listening on a unix domain socket, e.g. `/run/mpd/socket`.
+    ///
+    /// Clients connected this way are allowed some local-only commands, such as adding
+    /// arbitrary `file://` uris.
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Client, Error> {
+        Client::handshake(connect_unix(path.as_ref())?, Timeouts::default())
+    }
+
+    /// Connect to a server over TCP, and authenticate with a password
+    pub fn connect_with_password<A: net::ToSocketAddrs>(addr: A, password: &str)
+        -> Result<Client, Error>
+    {
+        let mut client = Client::connect(addr)?;
+        client.password(password)?;
+        Ok(client)
+    }
+
+    /// Connect to a server, authenticating if the host includes a password
+    pub fn connect_host(host: &HostSpec) -> Result<Client, Error> {
+        Client::connect_host_with_timeouts(host, Timeouts::default())
+    }
+
+    /// Connect to a server with timeouts, authenticating if the host includes a password
+    pub fn connect_host_with_timeouts(host: &HostSpec, timeouts: Timeouts)
+        -> Result<Client, Error>
+    {
+        let mut client = Client::connect_with_timeouts(&host.address, timeouts)?;
+        if let Some(ref password) = host.password {
+            client.password(password)?;
+        }
+        Ok(client)
+    }
+
+    /// Connect to a server at the given address
+    pub fn connect_to(address: &Address) -> Result<Client, Error> {
+        Client::connect_with_timeouts(address, Timeouts::default())
+    }
+
+    /// Connect to a server at the given address, giving up if it doesn't respond in time.
+    ///
+    /// The connect timeout only applies to TCP; local sockets connect straight away or not at
+    /// all.
+    pub fn connect_with_timeouts(address: &Address, timeouts: Timeouts) -> Result<Client, Error> {
+        let stream = match *address {
+            Address::Tcp(ref host, port) => connect_tcp((&host[..], port), timeouts.connect)?,
+            Address::Unix(ref path) => connect_unix(path)?,
+            Address::Abstract(ref name) => connect_abstract(name)?,
         };
+        Client::handshake(stream, timeouts)
+    }
+
+    fn handshake(stream: Stream, timeouts: Timeouts) -> Result<Client, Error> {
+        stream.set_read_timeout(timeouts.read)?;
+        stream.set_write_timeout(timeouts.write)?;
+        let mut buffer = Buffer::new().max_size(Some(DEFAULT_MAX_RESPONSE_SIZE));
+        let version = read_response(&mut buffer, &stream, parse_handshake)?;
         Ok(Client {
             stream: stream,
-            version: version
+            buffer: buffer,
+            version: version,
+            timeouts: timeouts,
+            last_used: Instant::now(),
         })
     }
 
+    /// The timeouts the connection is using
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Change the read and write timeouts. The connect timeout has no effect once connected.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) -> Result<(), Error> {
+        self.stream.set_read_timeout(timeouts.read)?;
+        self.stream.set_write_timeout(timeouts.write)?;
+        self.timeouts = timeouts;
+        Ok(())
+    }
+
+    /// Limit the size of a response, so that a huge one (e.g. from `listallinfo` on a big
+    /// database) is an error rather than using up all the memory. `None` removes the limit.
+    ///
+    /// Defaults to `DEFAULT_MAX_RESPONSE_SIZE`. A response that is too big leaves the connection
+    /// part way through it, so it shouldn't be used again.
+    pub fn set_max_response_size(&mut self, max_size: Option<usize>) {
+        self.buffer.set_max_size(max_size);
+    }
+
     pub fn version(&self) -> Version {
         self.version.clone()
     }
 
+    /// Write out a command or command list.
+    ///
+    /// The whole request is serialized before anything is sent, so that an invalid argument
+    /// doesn't leave a half-written command on the connection.
+    fn send<D: Dispatch>(&mut self, request: &D) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        request.dispatch(&mut buf)?;
+        self.last_used = Instant::now();
+        (&self.stream).write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Run a single command, returning its response
+    pub fn run_command(&mut self, command: Command) -> Result<CommandResponse, Error> {
+        self.send(&command)?;
+        self.receive(&command)
+    }
+
+    /// Read the response to a command that has been sent
+    fn receive(&mut self, command: &Command) -> Result<CommandResponse, Error> {
+        read_response(&mut self.buffer, &self.stream, |i| parse_result(i, |i| {
+            let (i, response) = try_parse!(i, |i| command.parse_response(i));
+            let (i, _) = try_parse!(i, parse_ok);
+            IResult::Done(i, response)
+        }))?.map_err(Error::from)
+    }
+
+    /// Block until one of the given subsystems changes, returning the subsystems that changed.
+    ///
+    /// If `subsystems` is empty, changes to any subsystem are waited for. The wait can be
+    /// cancelled from another thread using an `IdleHandle`, in which case the returned list may
+    /// be empty.
+    ///
+    /// The read timeout doesn't apply while waiting, since nothing may change for hours. The
+    /// server doesn't time out idling connections either.
+    pub fn idle(&mut self, subsystems: &[SubSystem]) -> Result<Vec<SubSystem>, Error> {
+        self.stream.set_read_timeout(None)?;
+        let response = self.run_command(Command::Idle(subsystems.to_vec()));
+        self.stream.set_read_timeout(self.timeouts.read)?;
+        match response? {
+            CommandResponse::Changed(changed) => Ok(changed),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Like `idle`, but calls `started` with a handle for cancelling the wait once the server has
+    /// been asked to idle.
+    ///
+    /// The server ignores a `noidle` that reaches it before the `idle`, so a thread that cancels
+    /// waits it didn't start should get its handle from here rather than from `idle_handle`.
+    pub fn idle_with_handle<F>(&mut self, subsystems: &[SubSystem], started: F)
+        -> Result<Vec<SubSystem>, Error>
+        where F: FnOnce(IdleHandle)
+    {
+        // made first, since the connection can't be used for anything else once it is idling
+        let handle = self.idle_handle()?;
+        let command = Command::Idle(subsystems.to_vec());
+        self.stream.set_read_timeout(None)?;
+        let response = self.send(&command).and_then(|()| {
+            started(handle);
+            self.receive(&command)
+        });
+        self.stream.set_read_timeout(self.timeouts.read)?;
+        match response? {
+            CommandResponse::Changed(changed) => Ok(changed),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Check that the server is still there, and stop it from closing the connection for being
+    /// unused
+    pub fn ping(&mut self) -> Result<(), Error> {
+        self.run_command(Command::Ping)?;
+        Ok(())
+    }
+
+    /// Authenticate with a password, giving the connection the permissions that go with it.
+    ///
+    /// An incorrect password is reported as an error for which `is_incorrect_password` is true.
+    pub fn password(&mut self, password: &str) -> Result<(), Error> {
+        self.run_command(Command::Password(password.to_owned()))?;
+        Ok(())
+    }
+
+    /// Find out which commands this connection may run, e.g. to disable actions in a user
+    /// interface rather than have them fail.
+    pub fn permissions(&mut self) -> Result<Permissions, Error> {
+        let mut list = CommandList::new();
+        list.push(Command::Commands);
+        list.push(Command::NotCommands);
+        let mut responses = self.run_commands(list)?.into_iter();
+        match (responses.next(), responses.next()) {
+            (Some(CommandResponse::CommandNames(allowed)),
+             Some(CommandResponse::CommandNames(denied))) => Ok(Permissions {
+                allowed: allowed,
+                denied: denied,
+            }),
+            _ => unreachable!(),
+        }
+    }
+
+    /// List the tag types the server reads from songs, along with the names of any that this
+    /// library doesn't know
+    pub fn tag_types(&mut self) -> Result<(Vec<TagType>, Vec<String>), Error> {
+        match self.run_command(Command::TagTypes)? {
+            CommandResponse::TagTypes(known, unknown) => Ok((known, unknown)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// List the uri schemes the server can play, e.g. `http://`
+    pub fn url_handlers(&mut self) -> Result<Vec<String>, Error> {
+        match self.run_command(Command::UrlHandlers)? {
+            CommandResponse::UrlHandlers(handlers) => Ok(handlers),
+            _ => unreachable!(),
+        }
+    }
+
+    /// List the decoder plugins, and the file types they decode
+    pub fn decoders(&mut self) -> Result<Vec<Decoder>, Error> {
+        match self.run_command(Command::Decoders)? {
+            CommandResponse::Decoders(decoders) => Ok(decoders),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Get the server's music directory, or `None` if it isn't on the local file system.
+    ///
+    /// Only local (unix socket) connections are allowed to ask; others get an error for which
+    /// `is_permission_denied` is true.
+    pub fn music_directory(&mut self) -> Result<Option<String>, Error> {
+        match self.run_command(Command::Config)? {
+            CommandResponse::MusicDirectory(music_directory) => Ok(music_directory),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Read and write stickers, such as ratings and play counts
+    pub fn stickers(&mut self) -> Stickers<'_> {
+        Stickers::new(self)
+    }
+
+    /// List the audio outputs, which can be switched with `EnableOutput`, `DisableOutput` and
+    /// `ToggleOutput`
+    pub fn outputs(&mut self) -> Result<Vec<Output>, Error> {
+        match self.run_command(Command::Outputs)? {
+            CommandResponse::Outputs(outputs) => Ok(outputs),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Subscribe to a channel, to receive the messages other clients send to it
+    pub fn subscribe(&mut self, channel: &str) -> Result<(), Error> {
+        self.run_command(Command::Subscribe(channel.to_owned()))?;
+        Ok(())
+    }
+
+    /// Stop receiving messages sent to a channel
+    pub fn unsubscribe(&mut self, channel: &str) -> Result<(), Error> {
+        self.run_command(Command::Unsubscribe(channel.to_owned()))?;
+        Ok(())
+    }
+
+    /// List the channels that have subscribers
+    pub fn channels(&mut self) -> Result<Vec<String>, Error> {
+        match self.run_command(Command::Channels)? {
+            CommandResponse::Channels(channels) => Ok(channels),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Send a message to the clients subscribed to a channel
+    pub fn send_message(&mut self, channel: &str, text: &str) -> Result<(), Error> {
+        self.run_command(Command::SendMessage {
+            channel: channel.to_owned(),
+            text: text.to_owned(),
+        })?;
+        Ok(())
+    }
+
+    /// Read the messages that have arrived on the subscribed channels, without waiting
+    pub fn read_messages(&mut self) -> Result<Vec<Message>, Error> {
+        match self.run_command(Command::ReadMessages)? {
+            CommandResponse::Messages(messages) => Ok(messages),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Block until there are messages on the subscribed channels, and read them.
+    ///
+    /// Returns an empty list if the wait is cancelled with an `IdleHandle`.
+    pub fn wait_for_messages(&mut self) -> Result<Vec<Message>, Error> {
+        loop {
+            let messages = self.read_messages()?;
+            if !messages.is_empty() {
+                return Ok(messages);
+            }
+            // the server reports a message that arrived since the last idle straight away, so
+            // nothing can be missed between reading and idling
+            if self.idle(&[SubSystem::Message])?.is_empty() {
+                return Ok(messages);
+            }
+        }
+    }
+
+    /// Iterate over the messages on the subscribed channels as they arrive.
+    ///
+    /// The iterator blocks while waiting, and ends when the wait is cancelled with an
+    /// `IdleHandle`.
+    pub fn messages(&mut self) -> Messages<'_> {
+        Messages {
+            client: self,
+            received: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Get a handle that can be used to cancel `idle` from another thread
+    pub fn idle_handle(&self) -> Result<IdleHandle, Error> {
+        Ok(IdleHandle(self.stream.try_clone()?))
+    }
+
+    /// Run a list of commands, returning their responses in the order the commands were given.
+    ///
+    /// If the server rejects one of the commands, `Error::Command` is returned. Use
+    /// `run_commands_partial` to also get the responses of the commands that ran before it.
     pub fn run_commands(&mut self, commands: CommandList)
         -> Result<Vec<CommandResponse>, Error>
     {
-        commands.dispatch(&mut self.stream.get_mut());
-        let response = match Buffer::parse(|i| commands.parse_response(i), &mut self.stream) {
-            IResult::Done(_, v) => v,
-            IResult::Incomplete(_) => unreachable!(),
-            IResult::Error(e) => { return Err(Error::from(e)) }
+        self.run_commands_partial(commands)?.into_result().map_err(Error::from)
+    }
+
+    /// Run a list of commands, returning the responses of all commands that succeeded.
+    ///
+    /// The server stops at the first command that fails, so if `error` is set on the response,
+    /// the commands after `error.command_no` were not run.
+    pub fn run_commands_partial(&mut self, commands: CommandList)
+        -> Result<CommandListResponse, Error>
+    {
+        self.send(&commands)?;
+        read_response(&mut self.buffer, &self.stream, |i| commands.parse_response(i))
+    }
+
+    /// Run a command whose response is a list of songs, reading the songs one at a time.
+    ///
+    /// Only one song is held in memory at a time, so this is the way to read very large responses
+    /// such as `listallinfo` on a big database. The maximum response size applies to each song
+    /// rather than to the whole response. Anything that isn't a song, such as a directory, is
+    /// skipped.
+    ///
+    /// If the iterator is dropped early, the rest of the response is read and thrown away.
+    pub fn iter_songs(&mut self, command: Command) -> Result<Songs<'_>, Error> {
+        self.send(&command)?;
+        Ok(Songs {
+            client: self,
+            done: false,
+        })
+    }
+}
+
+/// An iterator over the songs in a response, created by `Client::iter_songs`
+pub struct Songs<'a> {
+    client: &'a mut Client,
+    done: bool,
+}
+
+impl<'a> Iterator for Songs<'a> {
+    type Item = Result<Song, Error>;
+
+    fn next(&mut self) -> Option<Result<Song, Error>> {
+        if self.done {
+            return None;
+        }
+        let client = &mut *self.client;
+        let next = read_response(&mut client.buffer, &client.stream, |i| parse_result(i, |i| {
+            match try_parse!(i, parse_next_song) {
+                (i, Some(song)) => IResult::Done(i, Some(song)),
+                (i, None) => parse_ok(i).map(|_| None),
+            }
+        }));
+        match next {
+            Ok(Ok(Some(song))) => Some(Ok(song)),
+            Ok(Ok(None)) => {
+                self.done = true;
+                None
+            },
+            Ok(Err(e)) => {
+                self.done = true;
+                Some(Err(Error::Command(e)))
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+impl<'a> Drop for Songs<'a> {
+    fn drop(&mut self) {
+        // the connection can't be used until the whole response has been read
+        for _ in self {}
+    }
+}
+
+/// An iterator over the messages sent to the subscribed channels, created by `Client::messages`
+pub struct Messages<'a> {
+    client: &'a mut Client,
+    /// Messages that have been read but not returned yet
+    received: VecDeque<Message>,
+    done: bool,
+}
+
+impl<'a> Iterator for Messages<'a> {
+    type Item = Result<Message, Error>;
+
+    fn next(&mut self) -> Option<Result<Message, Error>> {
+        if self.received.is_empty() && !self.done {
+            match self.client.wait_for_messages() {
+                Ok(messages) => {
+                    self.done = messages.is_empty();
+                    self.received.extend(messages);
+                },
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+            }
+        }
+        self.received.pop_front().map(Ok)
+    }
+}
+
+/// Pings the server from a background thread whenever a shared client hasn't been used for a
+/// while, so that the server doesn't close the connection.
+///
+/// The server closes connections that haven't sent anything for `connection_timeout` (60
+/// seconds by default), so the interval should be well under that. Pinging stops when this is
+/// dropped, or when a ping fails, in which case the next command reports the error.
+#[derive(Debug)]
+pub struct KeepAlive {
+    // the thread stops when this is dropped
+    _stop: mpsc::Sender<()>,
+}
+
+impl KeepAlive {
+    /// Start pinging the server once the client has been unused for `interval`
+    pub fn start(client: Arc<Mutex<Client>>, interval: Duration) -> KeepAlive {
+        let (stop, stopped) = mpsc::channel();
+        thread::spawn(move || {
+            let mut wait = interval;
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(wait) {
+                let mut client = match client.lock() {
+                    Ok(client) => client,
+                    Err(_) => return,
+                };
+                let unused = client.last_used.elapsed();
+                if unused < interval {
+                    wait = interval - unused;
+                } else if client.ping().is_ok() {
+                    wait = interval;
+                } else {
+                    return;
+                }
+            }
+        });
+        KeepAlive {
+            _stop: stop,
+        }
+    }
+}
+
+/// A handle for cancelling a blocking `Client::idle` from another thread
+#[derive(Debug)]
+pub struct IdleHandle(Stream);
+
+impl IdleHandle {
+    /// Send `noidle` to the server, causing a pending `idle` to return immediately.
+    ///
+    /// If the client isn't idling, this does nothing.
+    pub fn noidle(&self) -> Result<(), Error> {
+        Command::NoIdle.dispatch(&mut &self.0)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use test_server::{FakeServer, Script};
+    use types::{CmdErrorType, TagType};
+
+    #[test]
+    fn run_commands() {
+        let server = FakeServer::spawn(Script::new().respond(
+            "command_list_ok_begin\nclearerror\nstop\ncommand_list_end\n",
+            "list_OK\nlist_OK\nOK\n"
+        )).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        assert_eq!(client.version(), Version::parse("0.19.0").unwrap());
+        let mut list = CommandList::new();
+        list.push(Command::ClearError);
+        list.push(Command::Stop);
+        assert_eq!(
+            client.run_commands(list).unwrap(),
+            vec![CommandResponse::Blank, CommandResponse::Blank]
+        );
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn run_commands_ack() {
+        let server = FakeServer::spawn(Script::new().respond(
+            "command_list_ok_begin\nplay 10240\ncommand_list_end\n",
+            "ACK [50@0] {play} song doesn't exist: \"10240\"\n"
+        )).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        let mut list = CommandList::new();
+        list.push(Command::Play(10240));
+        match client.run_commands(list) {
+            Err(Error::Command(e)) => assert_eq!(e, CmdError {
+                error_type: CmdErrorType::NoExist,
+                command_no: 0,
+                command_name: "play".to_owned(),
+                message_text: "song doesn't exist: \"10240\"".to_owned()
+            }),
+            other => panic!("expected command error, got {:?}", other),
+        }
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn run_commands_partial() {
+        let server = FakeServer::spawn(Script::new().respond(
+            "command_list_ok_begin\nstop\nplay 10240\nnext\ncommand_list_end\n",
+            "list_OK\nACK [50@1] {play} song doesn't exist: \"10240\"\n"
+        )).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        let mut list = CommandList::new();
+        list.push(Command::Stop);
+        list.push(Command::Play(10240));
+        list.push(Command::Next);
+        let response = client.run_commands_partial(list).unwrap();
+        assert_eq!(response.responses, vec![CommandResponse::Blank]);
+        assert_eq!(response.error.map(|e| e.command_no), Some(1));
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn idle() {
+        let server = FakeServer::spawn(Script::new().respond(
+            "idle player mixer\n",
+            "changed: player\nchanged: mixer\nOK\n"
+        )).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        assert_eq!(
+            client.idle(&[SubSystem::Player, SubSystem::Mixer]).unwrap(),
+            vec![SubSystem::Player, SubSystem::Mixer]
+        );
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn noidle() {
+        let server = FakeServer::spawn(Script::new().respond("idle\nnoidle\n", "OK\n")).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        let handle = client.idle_handle().unwrap();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.noidle().unwrap();
+        });
+        assert_eq!(client.idle(&[]).unwrap(), vec![]);
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn idle_with_handle() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .respond("idle player\nnoidle\n", "changed: player\nOK\n")
+                .respond("ping\n", "OK\n")
+        ).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        let changed = client.idle_with_handle(&[SubSystem::Player], |handle| {
+            handle.noidle().unwrap();
+        });
+        assert_eq!(changed.unwrap(), vec![SubSystem::Player]);
+        client.ping().unwrap();
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn password() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .respond("password \"secret\"\n", "OK\n")
+                .respond(
+                    "command_list_ok_begin\ncommands\nnotcommands\ncommand_list_end\n",
+                    "command: password\ncommand: play\nlist_OK\ncommand: add\nlist_OK\nOK\n"
+                )
+                .respond("password \"guess\"\n", "ACK [3@0] {password} incorrect password\n")
+                .respond(
+                    "add \"a.mp3\"\n",
+                    "ACK [4@0] {add} you don't have permission for \"add\"\n"
+                )
+        ).unwrap();
+        let host = HostSpec {
+            address: Address::Tcp("127.0.0.1".into(), server.addr().port()),
+            password: Some("secret".into()),
+        };
+        let mut client = Client::connect_host(&host).unwrap();
+        let permissions = client.permissions().unwrap();
+        assert!(permissions.allows(&Command::Play(0)));
+        assert!(!permissions.allows(&Command::Add("a.mp3".into())));
+        assert_eq!(permissions.denied, vec!["add".to_owned()]);
+        let e = client.password("guess").unwrap_err();
+        assert!(e.is_incorrect_password());
+        assert_eq!(e.to_string(), "password: incorrect password");
+        let e = client.run_command(Command::Add("a.mp3".into())).unwrap_err();
+        assert!(e.is_permission_denied());
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn host_spec() {
+        assert_eq!(HostSpec::parse("localhost", None).unwrap(), HostSpec {
+            address: Address::Tcp("localhost".into(), 6600),
+            password: None,
+        });
+        assert_eq!(HostSpec::parse("secret@music.lan", Some("6601")).unwrap(), HostSpec {
+            address: Address::Tcp("music.lan".into(), 6601),
+            password: Some("secret".into()),
+        });
+        assert_eq!(HostSpec::parse("/run/mpd/socket", Some("6601")).unwrap(), HostSpec {
+            address: Address::Unix("/run/mpd/socket".into()),
+            password: None,
+        });
+        assert_eq!(HostSpec::parse("/run/user@1000/mpd.sock", None).unwrap(), HostSpec {
+            address: Address::Unix("/run/user@1000/mpd.sock".into()),
+            password: None,
+        });
+        assert_eq!(HostSpec::parse("secret@/run/mpd/socket", None).unwrap(), HostSpec {
+            address: Address::Unix("/run/mpd/socket".into()),
+            password: Some("secret".into()),
+        });
+        assert_eq!(HostSpec::parse("@mpd", None).unwrap(), HostSpec {
+            address: Address::Abstract("mpd".into()),
+            password: None,
+        });
+        assert_eq!(HostSpec::parse("secret@@mpd", None).unwrap(), HostSpec {
+            address: Address::Abstract("mpd".into()),
+            password: Some("secret".into()),
+        });
+        assert!(HostSpec::parse("localhost", Some("http")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket() {
+        use std::env;
+        use std::fs;
+        use std::os::unix::net::UnixListener;
+
+        let path = env::temp_dir().join(format!("musicpd-test-{}.socket", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"OK MPD 0.20.0\n").unwrap();
+            let mut request = [0; 5];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request, b"stop\n");
+            stream.write_all(b"OK\n").unwrap();
+        });
+        let mut client = Client::connect_to(&Address::Unix(path.clone())).unwrap();
+        assert_eq!(client.version(), Version::parse("0.20.0").unwrap());
+        assert_eq!(client.run_command(Command::Stop).unwrap(), CommandResponse::Blank);
+        server.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn split_response() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .greeting("0.20.18")
+                .expect("currentsong\n")
+                .trickle(
+                    "file: a.mp3\nTitle: Split\nId: 3\nOK\n",
+                    5,
+                    Duration::from_millis(5)
+                )
+                .expect("stop\n")
+                .send("OK")
+                .delay(Duration::from_millis(20))
+                .send("\n")
+        ).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        assert_eq!(client.version(), Version::parse("0.20.18").unwrap());
+        match client.run_command(Command::CurrentSong).unwrap() {
+            CommandResponse::Song(Some(song)) => {
+                assert_eq!(song.file, "a.mp3");
+                assert_eq!(song.tag(TagType::Title), Some("Split"));
+                assert_eq!(song.id, Some(3));
+            },
+            other => panic!("expected a song, got {:?}", other),
+        }
+        assert_eq!(client.run_command(Command::Stop).unwrap(), CommandResponse::Blank);
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn timeouts() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .expect("idle\n")
+                .delay(Duration::from_millis(60))
+                .send("changed: player\nOK\n")
+                .expect("status\n")
+                .delay(Duration::from_millis(200))
+                .send("OK\n")
+        ).unwrap();
+        let address = Address::Tcp("127.0.0.1".into(), server.addr().port());
+        let timeouts = Timeouts {
+            read: Some(Duration::from_millis(20)),
+            ..Timeouts::default()
         };
-        println!("{:?}", response);
-        Ok(Vec::new())
+        let mut client = Client::connect_with_timeouts(&address, timeouts).unwrap();
+        assert_eq!(client.timeouts(), timeouts);
+        assert_eq!(client.idle(&[]).unwrap(), vec![SubSystem::Player]);
+        let e = client.run_command(Command::Status).unwrap_err();
+        assert!(e.is_timeout(), "expected a timeout, got {:?}", e);
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn closed() {
+        let server = FakeServer::spawn(Script::new().expect("status\n")).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        match client.run_command(Command::Status) {
+            Err(Error::Closed) => (),
+            other => panic!("expected the connection to be closed, got {:?}", other),
+        }
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn keep_alive() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .respond("ping\n", "OK\n")
+                .respond("stop\n", "OK\n")
+        ).unwrap();
+        let client = Arc::new(Mutex::new(Client::connect(server.addr()).unwrap()));
+        let keep_alive = KeepAlive::start(client.clone(), Duration::from_millis(50));
+        thread::sleep(Duration::from_millis(75));
+        drop(keep_alive);
+        client.lock().unwrap().run_command(Command::Stop).unwrap();
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn max_response_size() {
+        let playlist = "file: a.mp3\nPos: 0\nId: 1\n".repeat(10);
+        let server = FakeServer::spawn(
+            Script::new().respond("playlistinfo\n", &format!("{}OK\n", playlist))
+        ).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        client.set_max_response_size(Some(100));
+        match client.run_command(Command::PlaylistInfo(None)) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => (),
+            other => panic!("expected the response to be too large, got {:?}", other),
+        }
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn iter_songs() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .expect("listallinfo\n")
+                .trickle(
+                    "directory: a\nfile: a/1.mp3\nTitle: One\nfile: a/2.mp3\nfile: a/3.mp3\nOK\n",
+                    7,
+                    Duration::from_millis(1)
+                )
+                .respond("playlistinfo\n", "file: b.mp3\nACK [5@0] {playlistinfo} oops\n")
+                .respond("stop\n", "OK\n")
+        ).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        {
+            let mut songs = client.iter_songs(Command::ListAllInfo(None)).unwrap();
+            let first = songs.next().unwrap().unwrap();
+            assert_eq!(first.file, "a/1.mp3");
+            assert_eq!(first.tag(TagType::Title), Some("One"));
+            assert_eq!(songs.next().unwrap().unwrap().file, "a/2.mp3");
+            // dropped before the end
+        }
+        let songs: Vec<_> = client.iter_songs(Command::PlaylistInfo(None)).unwrap().collect();
+        assert_eq!(songs.len(), 2);
+        assert_eq!(songs[0].as_ref().unwrap().file, "b.mp3");
+        match songs[1] {
+            Err(Error::Command(ref e)) => assert_eq!(e.message_text, "oops"),
+            ref other => panic!("expected a command error, got {:?}", other),
+        }
+        assert_eq!(client.run_command(Command::Stop).unwrap(), CommandResponse::Blank);
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn outputs() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .respond(
+                    "outputs\n",
+                    "outputid: 0\noutputname: Kitchen\noutputenabled: 0\n\
+                     outputid: 1\noutputname: Lounge\noutputenabled: 1\nOK\n"
+                )
+                .respond("enableoutput 0\n", "OK\n")
+                .respond("toggleoutput 1\n", "OK\n")
+        ).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        let outputs = client.outputs().unwrap();
+        assert_eq!(outputs.iter().map(|o| &o.name[..]).collect::<Vec<_>>(), ["Kitchen", "Lounge"]);
+        assert!(!outputs[0].enabled && outputs[1].enabled);
+        client.run_command(Command::EnableOutput(0)).unwrap();
+        client.run_command(Command::ToggleOutput(1)).unwrap();
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn messages() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .respond("subscribe \"party\"\n", "OK\n")
+                .respond("sendmessage \"party\" \"hello\"\n", "OK\n")
+                .respond("readmessages\n", "channel: party\nmessage: hello\nOK\n")
+                .respond("readmessages\n", "OK\n")
+                .respond("idle message\n", "changed: message\nOK\n")
+                .respond(
+                    "readmessages\n",
+                    "channel: party\nmessage: a\nchannel: party\nmessage: b\nOK\n"
+                )
+                .respond("readmessages\n", "OK\n")
+                // cancelled with noidle
+                .respond("idle message\n", "OK\n")
+        ).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        client.subscribe("party").unwrap();
+        client.send_message("party", "hello").unwrap();
+        let texts: Vec<_> = client.messages().map(|message| message.unwrap().text).collect();
+        assert_eq!(texts, ["hello", "a", "b"]);
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn reflection() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .respond("tagtypes\n", "tagtype: Artist\ntagtype: Mood\nOK\n")
+                .respond("urlhandlers\n", "handler: http://\nOK\n")
+                .respond(
+                    "config\n",
+                    "ACK [4@0] {config} Command only permitted to local clients\n"
+                )
+        ).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        assert_eq!(client.tag_types().unwrap(), (vec![TagType::Artist], vec!["Mood".to_owned()]));
+        assert_eq!(client.url_handlers().unwrap(), vec!["http://".to_owned()]);
+        assert!(client.music_directory().unwrap_err().is_permission_denied());
+        server.finish().unwrap();
     }
 }
@@ -0,0 +1,1296 @@
+//! An in-memory emulation of an MPD server's queue and player.
+//!
+//! The emulator runs `Command`s against a fake song database and a real queue, and keeps the
+//! player state, playlist versions and idle events consistent the way MPD does. It doesn't play
+//! anything, so time only passes when a song is `seek`ed or `song_finished` is called.
+//!
+//! Only the queue, playback option and playback control commands are supported. Anything else
+//! is rejected with an `Unknown` error. Queries on the queue support tag, file and directory
+//! matches, but not regular expressions, modification times or audio formats.
+//!
+//! `EmulatorServer` serves an emulator over TCP, so that it can be used with `Client` or any
+//! other client. Each connection is told about changes by `idle` separately, like with the
+//! real server.
+//!
+//! Available in tests, and to other crates with the `test-server` feature.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut mpd = Emulator::new(vec![Song::new("a.mp3"), Song::new("b.mp3")]);
+//! mpd.run_command(&Command::Add("".into())).unwrap();
+//! mpd.run_command(&Command::Play(1)).unwrap();
+//! assert_eq!(mpd.run_command(&Command::Idle(vec![])).unwrap(),
+//!            CommandResponse::Changed(vec![SubSystem::Playlist, SubSystem::Player]));
+//!
+//! let server = EmulatorServer::spawn(mpd).unwrap();
+//! let mut client = Client::connect(server.addr()).unwrap();
+//! client.run_command(Command::Next).unwrap();
+//! server.with_emulator(|mpd| mpd.song_finished());
+//! ```
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use nom::IResult;
+use semver::Version;
+
+use protocol::command::{Command, CommandList, CommandListResponse, CommandResponse};
+use protocol::server::{self, ClientRequest};
+use types::{CmdError, CmdErrorType, Filter, FilterTag, Query, Range, ReplayGainMode, Selector,
+    SingleOrRange, Song, State, Status, SubSystem};
+use util::Buffer;
+
+/// A song in the queue
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    song: Song,
+    id: u32,
+    priority: u8,
+    /// The playlist version when this entry was last changed (added, moved, ...)
+    version: u32,
+}
+
+/// An emulated server
+#[derive(Clone, Debug, PartialEq)]
+pub struct Emulator {
+    database: Vec<Song>,
+    queue: Vec<Entry>,
+    next_id: u32,
+    version: u32,
+    /// The id of the current song
+    current: Option<u32>,
+    state: State,
+    elapsed: Duration,
+    volume: u8,
+    repeat: bool,
+    random: bool,
+    single: bool,
+    consume: bool,
+    crossfade: u32,
+    mix_ramp_db: f32,
+    mix_ramp_delay: Option<u16>,
+    replay_gain_mode: ReplayGainMode,
+    error: Option<String>,
+    /// Changes that haven't been reported by `idle` yet
+    events: Vec<SubSystem>,
+    /// State for the (deterministic) shuffle
+    seed: u32,
+}
+
+/// An error for the command, which the caller fills in the index of
+fn error(error_type: CmdErrorType, message: &str) -> CmdError {
+    CmdError {
+        error_type: error_type,
+        command_no: 0,
+        command_name: String::new(),
+        message_text: message.to_owned(),
+    }
+}
+
+fn bad_index() -> CmdError {
+    error(CmdErrorType::Arg, "Bad song index")
+}
+
+fn no_such_song() -> CmdError {
+    error(CmdErrorType::NoExist, "No such song")
+}
+
+/// Parses a song id argument
+fn parse_id(id: &str) -> Result<u32, CmdError> {
+    id.parse().map_err(|_| error(CmdErrorType::Arg, "Integer expected"))
+}
+
+fn unsupported_query() -> CmdError {
+    error(CmdErrorType::Arg, "query not supported by the emulator")
+}
+
+/// Compares a value with a needle, exactly for `find` or ignoring case for `search`
+fn value_equals(value: &str, needle: &str, exact: bool) -> bool {
+    if exact {
+        value == needle
+    } else {
+        value.to_lowercase() == needle.to_lowercase()
+    }
+}
+
+/// Whether a value contains a needle, ignoring case unless `exact`
+fn value_contains(value: &str, needle: &str, exact: bool) -> bool {
+    if exact {
+        value.contains(needle)
+    } else {
+        value.to_lowercase().contains(&needle.to_lowercase())
+    }
+}
+
+/// The values of a song that a filter tag refers to
+fn tag_values(song: &Song, tag: FilterTag) -> Vec<&str> {
+    match tag {
+        FilterTag::Tag(tag) => song.tags.get(&tag)
+            .map(|values| values.iter().map(|value| &value[..]).collect())
+            .unwrap_or_default(),
+        FilterTag::Any => song.tags.values().flatten().map(|value| &value[..]).collect(),
+        FilterTag::File => vec![&song.file[..]],
+    }
+}
+
+/// Whether a song is in the given directory
+fn in_directory(song: &Song, dir: &str) -> bool {
+    dir.is_empty() || song.file.starts_with(&format!("{}/", dir.trim_end_matches('/')))
+}
+
+/// Whether a song matches a query. `find` (`exact`) compares whole values, and `search` looks
+/// for parts of values and ignores case.
+fn query_matches(query: &Query, song: &Song, exact: bool) -> Result<bool, CmdError> {
+    match *query {
+        Query::Tags(ref tags) => {
+            for &(selector, ref needle) in tags {
+                let tag = match selector {
+                    Selector::Tag(tag) => FilterTag::Tag(tag),
+                    Selector::Any => FilterTag::Any,
+                    Selector::File => FilterTag::File,
+                    Selector::Base => {
+                        if !in_directory(song, needle) {
+                            return Ok(false);
+                        }
+                        continue;
+                    },
+                    Selector::ModifiedSince => return Err(unsupported_query()),
+                };
+                let matched = tag_values(song, tag).into_iter().any(|value| {
+                    if exact { value == needle } else { value_contains(value, needle, false) }
+                });
+                if !matched {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        },
+        Query::Filter(ref filter) => filter_matches(filter, song, exact),
+    }
+}
+
+/// Whether a song matches a filter expression, ignoring case unless `exact`
+fn filter_matches(filter: &Filter, song: &Song, exact: bool) -> Result<bool, CmdError> {
+    Ok(match *filter {
+        Filter::Equal(tag, ref value) => {
+            tag_values(song, tag).into_iter().any(|v| value_equals(v, value, exact))
+        },
+        Filter::NotEqual(tag, ref value) => {
+            !tag_values(song, tag).into_iter().any(|v| value_equals(v, value, exact))
+        },
+        Filter::Contains(tag, ref value) => {
+            tag_values(song, tag).into_iter().any(|v| value_contains(v, value, exact))
+        },
+        Filter::Base(ref dir) => in_directory(song, dir),
+        Filter::PriorityAtLeast(priority) => song.priority.unwrap_or(0) >= priority,
+        Filter::Not(ref inner) => !filter_matches(inner, song, exact)?,
+        Filter::And(ref first, ref rest) => {
+            if !filter_matches(first, song, exact)? {
+                return Ok(false);
+            }
+            for filter in rest {
+                if !filter_matches(filter, song, exact)? {
+                    return Ok(false);
+                }
+            }
+            true
+        },
+        Filter::Matches(..)
+            | Filter::NotMatches(..)
+            | Filter::ModifiedSince(_)
+            | Filter::AudioFormat(_)
+            | Filter::AudioFormatMatches(_) => return Err(unsupported_query()),
+    })
+}
+
+impl Emulator {
+    /// Create an emulator with an empty queue, and the given songs in the database
+    pub fn new(database: Vec<Song>) -> Emulator {
+        Emulator {
+            database: database,
+            queue: Vec::new(),
+            next_id: 1,
+            version: 1,
+            current: None,
+            state: State::Stop,
+            elapsed: Duration::from_secs(0),
+            volume: 100,
+            repeat: false,
+            random: false,
+            single: false,
+            consume: false,
+            crossfade: 0,
+            mix_ramp_db: 0.0,
+            mix_ramp_delay: None,
+            replay_gain_mode: ReplayGainMode::Off,
+            error: None,
+            events: Vec::new(),
+            seed: 0x2545_f491,
+        }
+    }
+
+    /// Run a command, returning its response or the error the server would send
+    pub fn run_command(&mut self, command: &Command) -> Result<CommandResponse, CmdError> {
+        self.apply(command).map_err(|mut e| {
+            e.command_name = command.name().to_owned();
+            e
+        })
+    }
+
+    /// Run a command list, stopping at the first command that fails like the server does
+    pub fn run_commands(&mut self, commands: &CommandList) -> CommandListResponse {
+        let mut responses = Vec::new();
+        for (idx, command) in commands.iter().enumerate() {
+            match self.run_command(command) {
+                Ok(response) => responses.push(response),
+                Err(mut e) => {
+                    e.command_no = idx;
+                    return CommandListResponse { responses: responses, error: Some(e) };
+                },
+            }
+        }
+        CommandListResponse { responses: responses, error: None }
+    }
+
+    /// Whether an `idle` for the given subsystems would return straight away.
+    ///
+    /// An empty list means all subsystems.
+    pub fn has_events(&self, subsystems: &[SubSystem]) -> bool {
+        self.events.iter().any(|sub| subsystems.is_empty() || subsystems.contains(sub))
+    }
+
+    /// The current playlist version
+    pub fn playlist_version(&self) -> u32 {
+        self.version
+    }
+
+    /// Act as if the current song finished playing, moving on to the next song following the
+    /// repeat, single and consume options.
+    pub fn song_finished(&mut self) {
+        if self.state != State::Stop {
+            self.advance(false);
+        }
+    }
+
+    fn apply(&mut self, command: &Command) -> Result<CommandResponse, CmdError> {
+        use self::Command::*;
+        match *command {
+            ClearError => {
+                self.error = None;
+            },
+            CurrentSong => {
+                let song = self.current_position().map(|pos| self.song_at(pos));
+                return Ok(CommandResponse::Song(song));
+            },
+            Idle(ref subsystems) => {
+                let (changed, rest) = self.events.iter().partition(|sub| {
+                    subsystems.is_empty() || subsystems.contains(sub)
+                });
+                self.events = rest;
+                return Ok(CommandResponse::Changed(changed));
+            },
+            NoIdle => (),
+            Status => return Ok(CommandResponse::Status(self.status())),
+            Consume(on) => {
+                self.consume = on;
+                self.notify(SubSystem::Options);
+            },
+            Crossfade(secs) => {
+                self.crossfade = secs as u32;
+                self.notify(SubSystem::Options);
+            },
+            MixRampDB(db) => {
+                self.mix_ramp_db = db as f32;
+                self.notify(SubSystem::Options);
+            },
+            MixRampDelay(delay) => {
+                self.mix_ramp_delay = delay;
+                self.notify(SubSystem::Options);
+            },
+            Random(on) => {
+                self.random = on;
+                self.notify(SubSystem::Options);
+            },
+            Repeat(on) => {
+                self.repeat = on;
+                self.notify(SubSystem::Options);
+            },
+            Volume(vol) => {
+                if vol > 100 {
+                    return Err(error(CmdErrorType::Arg, "Invalid volume value"));
+                }
+                self.volume = vol;
+                self.notify(SubSystem::Mixer);
+            },
+            Single(on) => {
+                self.single = on;
+                self.notify(SubSystem::Options);
+            },
+            ReplayGainMode(mode) => {
+                self.replay_gain_mode = mode;
+                self.notify(SubSystem::Options);
+            },
+            ReplayGainStatus => {
+                return Ok(CommandResponse::ReplayGainMode(self.replay_gain_mode));
+            },
+            Next => if self.state != State::Stop {
+                self.advance(true);
+            },
+            Pause(pause) => match (self.state, pause) {
+                (State::Play, true) => self.set_state(State::Pause),
+                (State::Pause, false) => self.set_state(State::Play),
+                _ => (),
+            },
+            Play(pos) => {
+                let pos = pos as usize;
+                if pos >= self.queue.len() {
+                    return Err(bad_index());
+                }
+                self.play_at(pos, Duration::from_secs(0));
+            },
+            PlayId(ref id) => {
+                let pos = self.position_of(parse_id(id)?)?;
+                self.play_at(pos, Duration::from_secs(0));
+            },
+            Previous => if self.state != State::Stop {
+                let pos = self.current_position().unwrap_or(0);
+                let prev = if pos > 0 {
+                    pos - 1
+                } else if self.repeat {
+                    self.queue.len() - 1
+                } else {
+                    0
+                };
+                self.play_at(prev, Duration::from_secs(0));
+            },
+            Seek { song_position, time } => {
+                let pos = song_position as usize;
+                if pos >= self.queue.len() {
+                    return Err(bad_index());
+                }
+                self.play_at(pos, time);
+            },
+            SeekId { ref song_id, time } => {
+                let pos = self.position_of(parse_id(song_id)?)?;
+                self.play_at(pos, time);
+            },
+            SeekCurrent(time) => match self.current_position() {
+                Some(pos) if self.state != State::Stop => {
+                    let state = self.state;
+                    self.play_at(pos, time);
+                    self.set_state(state);
+                },
+                _ => return Err(error(CmdErrorType::PlayerSync, "Not playing")),
+            },
+            Stop => {
+                self.elapsed = Duration::from_secs(0);
+                self.set_state(State::Stop);
+            },
+            Add(ref uri) => {
+                let prefix = format!("{}/", uri);
+                let songs: Vec<Song> = self.database.iter()
+                    .filter(|song| uri.is_empty() || song.file == *uri
+                            || song.file.starts_with(&prefix))
+                    .cloned()
+                    .collect();
+                if songs.is_empty() {
+                    return Err(error(CmdErrorType::NoExist, "No such directory"));
+                }
+                self.bump_version();
+                for song in songs {
+                    let end = self.queue.len();
+                    self.insert(end, song);
+                }
+            },
+            AddId { ref uri, position } => {
+                let song = match self.database.iter().find(|song| song.file == *uri) {
+                    Some(song) => song.clone(),
+                    None => return Err(no_such_song()),
+                };
+                let pos = position.map(|pos| pos as usize).unwrap_or(self.queue.len());
+                if pos > self.queue.len() {
+                    return Err(bad_index());
+                }
+                self.bump_version();
+                let id = self.insert(pos, song);
+                self.touch(pos, self.queue.len());
+                return Ok(CommandResponse::Id(id));
+            },
+            Clear => {
+                self.queue.clear();
+                self.current = None;
+                self.bump_version();
+                self.set_state(State::Stop);
+            },
+            Delete(songs) => {
+                let (start, end) = self.range(songs)?;
+                self.delete(start, end);
+            },
+            DeleteId(ref id) => {
+                let pos = self.position_of(parse_id(id)?)?;
+                self.delete(pos, pos + 1);
+            },
+            Move { from, to } => {
+                let (start, end) = self.range(from)?;
+                self.move_range(start, end, to as usize)?;
+            },
+            MoveId { ref from, to } => {
+                let pos = self.position_of(parse_id(from)?)?;
+                self.move_range(pos, pos + 1, to as usize)?;
+            },
+            PlaylistId(id) => return match id {
+                Some(id) => {
+                    let pos = self.position_of(id)?;
+                    Ok(CommandResponse::Songs(vec![self.song_at(pos)]))
+                },
+                None => Ok(CommandResponse::Songs(self.songs(0, self.queue.len()))),
+            },
+            PlaylistFind(ref query) => {
+                return self.find_in_queue(query, true).map(CommandResponse::Songs);
+            },
+            PlaylistSearch(ref query) => {
+                return self.find_in_queue(query, false).map(CommandResponse::Songs);
+            },
+            PlaylistInfo(songs) => {
+                let (start, end) = match songs {
+                    Some(songs) => self.range(songs)?,
+                    None => (0, self.queue.len()),
+                };
+                return Ok(CommandResponse::Songs(self.songs(start, end)));
+            },
+            PlaylistChanges { ref version, range } => {
+                let positions = self.changes(version, range)?;
+                let songs = positions.into_iter().map(|pos| self.song_at(pos)).collect();
+                return Ok(CommandResponse::Songs(songs));
+            },
+            PlaylistChangesPositionId { ref version, range } => {
+                let positions = self.changes(version, range)?;
+                let changes = positions.into_iter()
+                    .map(|pos| (pos as u32, self.queue[pos].id))
+                    .collect();
+                return Ok(CommandResponse::PositionIds(changes));
+            },
+            Priority { priority, ref songs } => {
+                let mut ranges = Vec::with_capacity(songs.len());
+                for &songs in songs {
+                    ranges.push(self.range(songs)?);
+                }
+                self.bump_version();
+                for (start, end) in ranges {
+                    for pos in start..end {
+                        self.set_priority(pos, priority);
+                    }
+                }
+            },
+            PriorityId { priority, ref songs } => {
+                let mut positions = Vec::with_capacity(songs.len());
+                for id in songs {
+                    positions.push(self.position_of(parse_id(id)?)?);
+                }
+                self.bump_version();
+                for pos in positions {
+                    self.set_priority(pos, priority);
+                }
+            },
+            Shuffle(range) => {
+                let (start, end) = self.range(SingleOrRange::Range(range))?;
+                self.bump_version();
+                // Fisher-Yates, with a fixed seed so tests are repeatable
+                for idx in (start + 1..end).rev() {
+                    let other = start + self.random_below(idx - start + 1);
+                    self.queue.swap(idx, other);
+                }
+                self.touch(start, end);
+            },
+            Swap(pos1, pos2) => {
+                let (pos1, pos2) = (pos1 as usize, pos2 as usize);
+                if pos1 >= self.queue.len() || pos2 >= self.queue.len() {
+                    return Err(bad_index());
+                }
+                self.swap(pos1, pos2);
+            },
+            SwapId(ref id1, ref id2) => {
+                let pos1 = self.position_of(parse_id(id1)?)?;
+                let pos2 = self.position_of(parse_id(id2)?)?;
+                self.swap(pos1, pos2);
+            },
+            Ping => (),
+            _ => return Err(error(CmdErrorType::Unknown, "not supported by the emulator")),
+        }
+        Ok(CommandResponse::Blank)
+    }
+
+    fn notify(&mut self, subsystem: SubSystem) {
+        if !self.events.contains(&subsystem) {
+            self.events.push(subsystem);
+        }
+    }
+
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        self.notify(SubSystem::Player);
+    }
+
+    /// Starts a new playlist version, for a change to the queue
+    fn bump_version(&mut self) {
+        self.version += 1;
+        self.notify(SubSystem::Playlist);
+    }
+
+    /// Marks the entries in `start..end` as changed in the current version
+    fn touch(&mut self, start: usize, end: usize) {
+        let version = self.version;
+        for entry in &mut self.queue[start..end] {
+            entry.version = version;
+        }
+    }
+
+    /// Adds a song at the given position, returning its id
+    fn insert(&mut self, pos: usize, song: Song) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.insert(pos, Entry {
+            song: song,
+            id: id,
+            priority: 0,
+            version: self.version,
+        });
+        id
+    }
+
+    fn delete(&mut self, start: usize, end: usize) {
+        let current = self.current_position();
+        self.bump_version();
+        self.queue.drain(start..end);
+        let len = self.queue.len();
+        self.touch(start, len);
+        match current {
+            Some(pos) if pos >= start && pos < end => {
+                // the server moves on to the song after the deleted ones
+                if start < len && self.state != State::Stop {
+                    self.play_at(start, Duration::from_secs(0));
+                } else {
+                    self.current = None;
+                    self.set_state(State::Stop);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn move_range(&mut self, start: usize, end: usize, to: usize) -> Result<(), CmdError> {
+        if to + (end - start) > self.queue.len() {
+            return Err(bad_index());
+        }
+        self.bump_version();
+        let moved: Vec<Entry> = self.queue.drain(start..end).collect();
+        for (offset, entry) in moved.into_iter().enumerate() {
+            self.queue.insert(to + offset, entry);
+        }
+        let first = cmp::min(start, to);
+        let last = cmp::max(end, to + (end - start));
+        self.touch(first, last);
+        Ok(())
+    }
+
+    fn swap(&mut self, pos1: usize, pos2: usize) {
+        self.bump_version();
+        self.queue.swap(pos1, pos2);
+        self.touch(pos1, pos1 + 1);
+        self.touch(pos2, pos2 + 1);
+    }
+
+    fn set_priority(&mut self, pos: usize, priority: u8) {
+        if self.queue[pos].priority != priority {
+            self.queue[pos].priority = priority;
+            self.touch(pos, pos + 1);
+        }
+    }
+
+    /// A number in `0..bound`, from an xorshift generator
+    fn random_below(&mut self, bound: usize) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed as usize % bound
+    }
+
+    fn play_at(&mut self, pos: usize, elapsed: Duration) {
+        self.current = Some(self.queue[pos].id);
+        self.elapsed = elapsed;
+        self.error = None;
+        self.set_state(State::Play);
+    }
+
+    /// Moves to the next song, either because the user asked to (`manual`) or because the current
+    /// one finished.
+    fn advance(&mut self, manual: bool) {
+        let pos = match self.current_position() {
+            Some(pos) => pos,
+            None => return,
+        };
+        let next = if manual {
+            self.following(pos)
+        } else {
+            self.next_position(pos)
+        };
+        let next_id = next.map(|next| self.queue[next].id);
+        if self.random {
+            // move the seed on, so that a different song is picked to follow the next one
+            self.random_below(1);
+        }
+        if self.consume {
+            self.delete(pos, pos + 1);
+        }
+        match next_id.and_then(|id| self.position_of(id).ok()) {
+            Some(next) => self.play_at(next, Duration::from_secs(0)),
+            None => {
+                self.elapsed = Duration::from_secs(0);
+                self.set_state(State::Stop);
+            },
+        }
+    }
+
+    /// The position of the song after `pos` in the queue, wrapping around in repeat mode.
+    ///
+    /// In random mode it is any other song, picked by the seed so that `status` reports the
+    /// song that will really play next. Unlike the server, random mode never runs out of songs.
+    fn following(&self, pos: usize) -> Option<usize> {
+        let len = self.queue.len();
+        if self.random && len > 1 {
+            Some((pos + 1 + self.seed as usize % (len - 1)) % len)
+        } else if pos + 1 < len {
+            Some(pos + 1)
+        } else if self.repeat {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// The position of the song that will play when the song at `pos` finishes
+    fn next_position(&self, pos: usize) -> Option<usize> {
+        match (self.single, self.repeat) {
+            (true, true) => Some(pos),
+            (true, false) => None,
+            _ => self.following(pos),
+        }
+    }
+
+    fn current_position(&self) -> Option<usize> {
+        self.current.and_then(|id| self.position_of(id).ok())
+    }
+
+    fn position_of(&self, id: u32) -> Result<usize, CmdError> {
+        self.queue.iter().position(|entry| entry.id == id).ok_or_else(no_such_song)
+    }
+
+    /// Checks that positions are in the queue, returning them as `start..end`
+    fn range(&self, songs: SingleOrRange) -> Result<(usize, usize), CmdError> {
+        let len = self.queue.len();
+        let (start, end) = match songs {
+            SingleOrRange::Single(pos) => (pos as usize, pos as usize + 1),
+            SingleOrRange::Range(Range { start, end }) => {
+                (start as usize, end.map(|end| end as usize).unwrap_or(len))
+            },
+        };
+        if start > end || end > len {
+            return Err(bad_index());
+        }
+        Ok((start, end))
+    }
+
+    /// The positions of songs changed since `version`, limited to `range`
+    fn changes(&self, version: &str, range: Option<Range>) -> Result<Vec<usize>, CmdError> {
+        let version: u32 = version.parse()
+            .map_err(|_| error(CmdErrorType::Arg, "Integer expected"))?;
+        let (start, end) = match range {
+            Some(Range { start, end }) => {
+                let len = self.queue.len();
+                let end = end.map(|end| end as usize).unwrap_or(len);
+                (cmp::min(start as usize, len), cmp::min(end, len))
+            },
+            None => (0, self.queue.len()),
+        };
+        // a version from the future (e.g. from before a restart) means everything changed
+        Ok((start..end)
+           .filter(|&pos| self.queue[pos].version > version || version > self.version)
+           .collect())
+    }
+
+    fn song_at(&self, pos: usize) -> Song {
+        let entry = &self.queue[pos];
+        let mut song = entry.song.clone();
+        song.position = Some(pos as u32);
+        song.id = Some(entry.id);
+        song.priority = if entry.priority > 0 { Some(entry.priority) } else { None };
+        song
+    }
+
+    fn songs(&self, start: usize, end: usize) -> Vec<Song> {
+        (start..end).map(|pos| self.song_at(pos)).collect()
+    }
+
+    /// The songs in the queue that match a query
+    fn find_in_queue(&self, query: &Query, exact: bool) -> Result<Vec<Song>, CmdError> {
+        let mut songs = Vec::new();
+        for pos in 0..self.queue.len() {
+            let song = self.song_at(pos);
+            if query_matches(query, &song, exact)? {
+                songs.push(song);
+            }
+        }
+        Ok(songs)
+    }
+
+    fn status(&self) -> Status {
+        let song = self.current_position();
+        let next_song = song.and_then(|pos| self.next_position(pos));
+        let playing = self.state != State::Stop;
+        Status {
+            volume: self.volume,
+            repeat: self.repeat,
+            random: self.random,
+            single: self.single,
+            consume: self.consume,
+            playlist: self.version,
+            playlist_length: self.queue.len() as u32,
+            state: self.state,
+            song: song.map(|pos| pos as u32),
+            song_id: song.map(|pos| self.queue[pos].id),
+            next_song: next_song.map(|pos| pos as u32),
+            next_song_id: next_song.map(|pos| self.queue[pos].id),
+            elapsed: if playing { Some(self.elapsed) } else { None },
+            duration: if playing {
+                song.and_then(|pos| self.queue[pos].song.duration)
+            } else {
+                None
+            },
+            bitrate: None,
+            crossfade: self.crossfade,
+            mix_ramp_db: self.mix_ramp_db,
+            audio: None,
+            updating_db: None,
+            error: self.error.clone(),
+        }
+    }
+}
+
+/// The protocol version sent in the greeting
+const VERSION: &str = "0.20.0";
+/// How often an idling connection checks for changes and for `noidle`
+const IDLE_POLL_MILLIS: u64 = 10;
+
+/// The emulator, and the changes each connection hasn't been told about yet
+#[derive(Debug)]
+struct Shared {
+    emulator: Emulator,
+    pending: HashMap<usize, Vec<SubSystem>>,
+    next_connection: usize,
+}
+
+impl Shared {
+    /// Take the emulator's events and pass them on to every connection
+    fn collect_events(&mut self) {
+        let changed = match self.emulator.run_command(&Command::Idle(vec![])) {
+            Ok(CommandResponse::Changed(changed)) => changed,
+            _ => return,
+        };
+        for pending in self.pending.values_mut() {
+            for sub in &changed {
+                if !pending.contains(sub) {
+                    pending.push(*sub);
+                }
+            }
+        }
+    }
+
+    /// Take the changes to the given subsystems that the connection hasn't been told about.
+    ///
+    /// An empty list means all subsystems.
+    fn take_changes(&mut self, connection: usize, subsystems: &[SubSystem]) -> Vec<SubSystem> {
+        let pending = self.pending.entry(connection).or_insert_with(Vec::new);
+        let (changed, rest) = pending.iter().partition(|sub| {
+            subsystems.is_empty() || subsystems.contains(sub)
+        });
+        *pending = rest;
+        changed
+    }
+}
+
+/// An emulator served over TCP on a local port
+#[derive(Debug)]
+pub struct EmulatorServer {
+    addr: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl EmulatorServer {
+    /// Start serving the emulator on a free port, accepting any number of connections.
+    ///
+    /// Changes made before the server started aren't reported by `idle`.
+    pub fn spawn(emulator: Emulator) -> io::Result<EmulatorServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let mut shared = Shared {
+            emulator: emulator,
+            pending: HashMap::new(),
+            next_connection: 0,
+        };
+        shared.collect_events();
+        let shared = Arc::new(Mutex::new(shared));
+        let accepting = shared.clone();
+        thread::spawn(move || for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let shared = accepting.clone();
+            thread::spawn(move || {
+                let connection = {
+                    let mut shared = shared.lock().unwrap();
+                    let connection = shared.next_connection;
+                    shared.next_connection += 1;
+                    shared.pending.insert(connection, Vec::new());
+                    connection
+                };
+                // a client that disconnects without saying goodbye isn't an error for the test
+                let _ = serve(&shared, connection, stream);
+                shared.lock().unwrap().pending.remove(&connection);
+            });
+        });
+        Ok(EmulatorServer {
+            addr: addr,
+            shared: shared,
+        })
+    }
+
+    /// The address to connect to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Use the emulator directly, e.g. to call `song_finished`. Changes are reported to idling
+    /// connections afterwards.
+    pub fn with_emulator<F, T>(&self, f: F) -> T
+        where F: FnOnce(&mut Emulator) -> T
+    {
+        let mut shared = self.shared.lock().unwrap();
+        let result = f(&mut shared.emulator);
+        shared.collect_events();
+        result
+    }
+}
+
+/// Read the next request, returning `None` if the client disconnected
+fn read_request(stream: &mut TcpStream, buffer: &mut Buffer)
+    -> io::Result<Option<Result<ClientRequest, CmdError>>>
+{
+    match buffer.parse(server::parse_request, stream) {
+        Ok(IResult::Done((), request)) => Ok(Some(request)),
+        Ok(_) => unreachable!(),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Talk to a client until it disconnects
+fn serve(shared: &Mutex<Shared>, connection: usize, mut stream: TcpStream) -> io::Result<()> {
+    // written in one go, since clients may not wait for the rest of a partial greeting
+    let mut greeting = Vec::new();
+    server::write_greeting(&mut greeting, &Version::parse(VERSION).unwrap())?;
+    stream.write_all(&greeting)?;
+    // a client that never finishes a request is dropped rather than buffered forever
+    let mut buffer = Buffer::new().max_size(Some(server::MAX_REQUEST_SIZE));
+    loop {
+        let request = match read_request(&mut stream, &mut buffer)? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        let mut response = Vec::new();
+        match request {
+            Err(e) => server::write_error(&mut response, &e)?,
+            // the server ignores `noidle` when the client isn't idling
+            Ok(ClientRequest::Command(Command::NoIdle)) => (),
+            Ok(ClientRequest::Command(Command::Idle(subsystems))) => {
+                let changed = idle(shared, connection, &mut stream, &mut buffer, &subsystems)?;
+                let changed = match changed {
+                    Some(changed) => changed,
+                    // like the server, drop clients that send anything but `noidle` while idling
+                    None => return Ok(()),
+                };
+                server::write_result(&mut response, &Ok(CommandResponse::Changed(changed)))?;
+            },
+            Ok(ClientRequest::Command(command)) => {
+                let mut shared = shared.lock().unwrap();
+                let result = shared.emulator.run_command(&command);
+                shared.collect_events();
+                server::write_result(&mut response, &result)?;
+            },
+            Ok(ClientRequest::CommandList { commands, list_ok }) => {
+                let mut shared = shared.lock().unwrap();
+                let result = shared.emulator.run_commands(&commands);
+                shared.collect_events();
+                server::write_list_response(&mut response, &result, list_ok)?;
+            },
+        }
+        stream.write_all(&response)?;
+    }
+}
+
+/// Wait for changes to the subsystems, or for `noidle`, returning the changes.
+///
+/// Returns `None` if the client disconnected or sent something else.
+fn idle(shared: &Mutex<Shared>, connection: usize, stream: &mut TcpStream,
+        buffer: &mut Buffer, subsystems: &[SubSystem])
+    -> io::Result<Option<Vec<SubSystem>>>
+{
+    stream.set_read_timeout(Some(Duration::from_millis(IDLE_POLL_MILLIS)))?;
+    let result = loop {
+        let changed = shared.lock().unwrap().take_changes(connection, subsystems);
+        if !changed.is_empty() {
+            break Ok(Some(changed));
+        }
+        match read_request(stream, buffer) {
+            Ok(Some(Ok(ClientRequest::Command(Command::NoIdle)))) => {
+                break Ok(Some(shared.lock().unwrap().take_changes(connection, subsystems)));
+            },
+            Ok(_) => break Ok(None),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                || e.kind() == io::ErrorKind::TimedOut => (),
+            Err(e) => break Err(e),
+        }
+    };
+    stream.set_read_timeout(None)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::{Client, Error};
+    use types::TagType;
+
+    fn emulator() -> Emulator {
+        let mut songs = Vec::new();
+        for name in &["a/1.mp3", "a/2.mp3", "a/3.mp3", "b/1.mp3", "b/2.mp3"] {
+            let mut song = Song::new(*name);
+            song.duration = Some(Duration::from_secs(180));
+            songs.push(song);
+        }
+        Emulator::new(songs)
+    }
+
+    fn files(mpd: &mut Emulator) -> Vec<String> {
+        match mpd.run_command(&Command::PlaylistInfo(None)).unwrap() {
+            CommandResponse::Songs(songs) => songs.into_iter().map(|song| song.file).collect(),
+            other => panic!("expected songs, got {:?}", other),
+        }
+    }
+
+    fn status(mpd: &mut Emulator) -> Status {
+        match mpd.run_command(&Command::Status).unwrap() {
+            CommandResponse::Status(status) => status,
+            other => panic!("expected status, got {:?}", other),
+        }
+    }
+
+    fn changed(mpd: &mut Emulator) -> Vec<SubSystem> {
+        match mpd.run_command(&Command::Idle(vec![])).unwrap() {
+            CommandResponse::Changed(changed) => changed,
+            other => panic!("expected changes, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn queue() {
+        let mut mpd = emulator();
+        mpd.run_command(&Command::Add("a".into())).unwrap();
+        assert_eq!(
+            mpd.run_command(&Command::AddId { uri: "b/2.mp3".into(), position: Some(1) }),
+            Ok(CommandResponse::Id(4))
+        );
+        assert_eq!(files(&mut mpd), vec!["a/1.mp3", "b/2.mp3", "a/2.mp3", "a/3.mp3"]);
+        mpd.run_command(&Command::Move { from: 0.into(), to: 3 }).unwrap();
+        mpd.run_command(&Command::Swap(0, 1)).unwrap();
+        assert_eq!(files(&mut mpd), vec!["a/2.mp3", "b/2.mp3", "a/3.mp3", "a/1.mp3"]);
+        mpd.run_command(&Command::DeleteId("4".into())).unwrap();
+        mpd.run_command(&Command::Delete(Range { start: 1, end: None }.into())).unwrap();
+        assert_eq!(files(&mut mpd), vec!["a/2.mp3"]);
+        assert_eq!(changed(&mut mpd), vec![SubSystem::Playlist]);
+        assert_eq!(status(&mut mpd).playlist, 7);
+    }
+
+    #[test]
+    fn errors() {
+        let mut mpd = emulator();
+        let mut list = CommandList::new();
+        list.push(Command::Add("b".into()));
+        list.push(Command::Play(5));
+        list.push(Command::Clear);
+        let response = mpd.run_commands(&list);
+        assert_eq!(response.responses, vec![CommandResponse::Blank]);
+        assert_eq!(response.error, Some(CmdError {
+            error_type: CmdErrorType::Arg,
+            command_no: 1,
+            command_name: "play".to_owned(),
+            message_text: "Bad song index".to_owned(),
+        }));
+        assert_eq!(
+            mpd.run_command(&Command::Add("c".into())).unwrap_err().error_type,
+            CmdErrorType::NoExist
+        );
+        assert_eq!(
+            mpd.run_command(&Command::PlayId("12".into())).unwrap_err().error_type,
+            CmdErrorType::NoExist
+        );
+        assert_eq!(
+            mpd.run_command(&Command::ListPlaylists).unwrap_err().error_type,
+            CmdErrorType::Unknown
+        );
+        assert_eq!(files(&mut mpd), vec!["b/1.mp3", "b/2.mp3"]);
+    }
+
+    #[test]
+    fn playback() {
+        let mut mpd = emulator();
+        mpd.run_command(&Command::Add("".into())).unwrap();
+        let stopped = status(&mut mpd);
+        assert_eq!((stopped.state, stopped.song, stopped.elapsed), (State::Stop, None, None));
+        mpd.run_command(&Command::Seek {
+            song_position: 3,
+            time: Duration::from_secs(30)
+        }).unwrap();
+        let playing = status(&mut mpd);
+        assert_eq!(playing.state, State::Play);
+        assert_eq!((playing.song, playing.song_id), (Some(3), Some(4)));
+        assert_eq!((playing.next_song, playing.next_song_id), (Some(4), Some(5)));
+        assert_eq!(playing.elapsed, Some(Duration::from_secs(30)));
+        assert_eq!(playing.duration, Some(Duration::from_secs(180)));
+        mpd.run_command(&Command::Pause(true)).unwrap();
+        mpd.run_command(&Command::Next).unwrap();
+        assert_eq!(status(&mut mpd).next_song, None);
+        mpd.song_finished();
+        let stopped = status(&mut mpd);
+        assert_eq!((stopped.state, stopped.song), (State::Stop, Some(4)));
+        match mpd.run_command(&Command::CurrentSong).unwrap() {
+            CommandResponse::Song(Some(song)) => assert_eq!(song.file, "b/2.mp3"),
+            other => panic!("expected current song, got {:?}", other),
+        }
+        assert_eq!(changed(&mut mpd), vec![SubSystem::Playlist, SubSystem::Player]);
+    }
+
+    #[test]
+    fn options() {
+        let mut mpd = emulator();
+        mpd.run_command(&Command::Add("a".into())).unwrap();
+        mpd.run_command(&Command::Repeat(true)).unwrap();
+        mpd.run_command(&Command::Consume(true)).unwrap();
+        mpd.run_command(&Command::Volume(50)).unwrap();
+        assert_eq!(
+            mpd.run_command(&Command::Volume(150)).unwrap_err().message_text,
+            "Invalid volume value"
+        );
+        assert_eq!(
+            mpd.run_command(&Command::Idle(vec![SubSystem::Mixer, SubSystem::Player])),
+            Ok(CommandResponse::Changed(vec![SubSystem::Mixer]))
+        );
+        assert!(mpd.has_events(&[SubSystem::Options]));
+        assert!(!mpd.has_events(&[SubSystem::Player]));
+        assert_eq!(status(&mut mpd).volume, 50);
+
+        // consume removes songs as they finish, and repeat wraps around to the start
+        mpd.run_command(&Command::Play(2)).unwrap();
+        mpd.song_finished();
+        assert_eq!(files(&mut mpd), vec!["a/1.mp3", "a/2.mp3"]);
+        let wrapped = status(&mut mpd);
+        assert_eq!((wrapped.state, wrapped.song), (State::Play, Some(0)));
+
+        mpd.run_command(&Command::Consume(false)).unwrap();
+        mpd.run_command(&Command::Single(true)).unwrap();
+        mpd.run_command(&Command::Play(1)).unwrap();
+        mpd.song_finished();
+        let single = status(&mut mpd);
+        assert_eq!((single.state, single.song), (State::Play, Some(1)));
+        mpd.run_command(&Command::Next).unwrap();
+        assert_eq!(status(&mut mpd).song, Some(0));
+
+        mpd.run_command(&Command::ReplayGainMode(ReplayGainMode::Album)).unwrap();
+        assert_eq!(
+            mpd.run_command(&Command::ReplayGainStatus),
+            Ok(CommandResponse::ReplayGainMode(ReplayGainMode::Album))
+        );
+    }
+
+    #[test]
+    fn queue_queries() {
+        let mut songs = Vec::new();
+        for &(file, title) in &[("a/1.mp3", "Dogs"), ("a/2.mp3", "Pigs"), ("b/1.mp3", "Hot Dogs")] {
+            let mut song = Song::new(file);
+            song.tags.insert(TagType::Title, vec![title.to_owned()]);
+            songs.push(song);
+        }
+        let mut mpd = Emulator::new(songs);
+        mpd.run_command(&Command::Add("".into())).unwrap();
+        let mut found = |command| match mpd.run_command(&command) {
+            Ok(CommandResponse::Songs(songs)) => {
+                songs.into_iter().map(|song| song.file).collect::<Vec<_>>()
+            },
+            other => panic!("unexpected response {:?}", other),
+        };
+        let dogs = vec![(TagType::Title.into(), "dogs".to_owned())];
+        assert!(found(Command::PlaylistFind(dogs.clone().into())).is_empty());
+        assert_eq!(found(Command::PlaylistSearch(dogs.into())), vec!["a/1.mp3", "b/1.mp3"]);
+        let query = !Filter::equal(TagType::Title, "Dogs") & !Filter::Base("b".into());
+        assert_eq!(found(Command::PlaylistFind(query.into())), vec!["a/2.mp3"]);
+        let query = Filter::contains(TagType::Title, "dogs") & Filter::Base("b".into());
+        assert_eq!(found(Command::PlaylistSearch(query.into())), vec!["b/1.mp3"]);
+
+        let query = Filter::matches(TagType::Title, "^D").into();
+        assert_eq!(
+            mpd.run_command(&Command::PlaylistFind(query)).unwrap_err().error_type,
+            CmdErrorType::Arg
+        );
+    }
+
+    #[test]
+    fn random() {
+        let mut mpd = emulator();
+        mpd.run_command(&Command::Add("".into())).unwrap();
+        mpd.run_command(&Command::Random(true)).unwrap();
+        mpd.run_command(&Command::Play(0)).unwrap();
+        let mut played = vec![0];
+        for _ in 0..10 {
+            let before = status(&mut mpd);
+            mpd.run_command(&Command::Next).unwrap();
+            let after = status(&mut mpd);
+            // the song reported as next is the one that plays, and never the same one again
+            assert_eq!(after.song, before.next_song);
+            assert!(after.song != before.song);
+            played.push(after.song.unwrap());
+        }
+        assert!(played.windows(2).any(|pair| pair[1] != pair[0] + 1), "{:?}", played);
+    }
+
+    #[test]
+    fn playlist_changes() {
+        let mut mpd = emulator();
+        mpd.run_command(&Command::Add("a".into())).unwrap();
+        let version = status(&mut mpd).playlist.to_string();
+        mpd.run_command(&Command::Priority {
+            priority: 10,
+            songs: vec![Range { start: 1, end: Some(3) }.into()]
+        }).unwrap();
+        mpd.run_command(&Command::AddId { uri: "b/1.mp3".into(), position: None }).unwrap();
+        assert_eq!(
+            mpd.run_command(&Command::PlaylistChangesPositionId {
+                version: version.clone(),
+                range: None
+            }),
+            Ok(CommandResponse::PositionIds(vec![(1, 2), (2, 3), (3, 4)]))
+        );
+        match mpd.run_command(&Command::PlaylistChanges {
+            version: version,
+            range: Some(Range { start: 0, end: Some(2) }),
+        }).unwrap() {
+            CommandResponse::Songs(songs) => {
+                assert_eq!(songs.len(), 1);
+                assert_eq!(songs[0].file, "a/2.mp3");
+                assert_eq!(songs[0].priority, Some(10));
+            },
+            other => panic!("expected songs, got {:?}", other),
+        }
+        // everything has changed since version 0
+        mpd.run_command(&Command::Shuffle(Range { start: 0, end: None })).unwrap();
+        match mpd.run_command(&Command::PlaylistChanges { version: "0".into(), range: None }) {
+            Ok(CommandResponse::Songs(ref songs)) if songs.len() == 4 => (),
+            other => panic!("expected 4 songs, got {:?}", other),
+        }
+        let mut shuffled = files(&mut mpd);
+        shuffled.sort();
+        assert_eq!(shuffled, vec!["a/1.mp3", "a/2.mp3", "a/3.mp3", "b/1.mp3"]);
+    }
+
+    #[test]
+    fn served() {
+        let server = EmulatorServer::spawn(emulator()).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        let mut list = CommandList::new();
+        list.push(Command::Add("a".into()));
+        list.push(Command::Play(1));
+        client.run_commands(list).unwrap();
+        match client.run_command(Command::Status).unwrap() {
+            CommandResponse::Status(status) => {
+                assert_eq!((status.state, status.song), (State::Play, Some(1)));
+            },
+            other => panic!("expected status, got {:?}", other),
+        }
+        match client.run_command(Command::Volume(150)) {
+            Err(Error::Command(e)) => {
+                assert_eq!(e.error_type, CmdErrorType::Arg);
+                assert_eq!(e.command_name, "setvol");
+            },
+            other => panic!("expected an error, got {:?}", other),
+        }
+        server.with_emulator(|mpd| mpd.song_finished());
+        match client.run_command(Command::CurrentSong).unwrap() {
+            CommandResponse::Song(Some(song)) => assert_eq!(song.file, "a/3.mp3"),
+            other => panic!("expected a song, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn served_idle() {
+        let server = EmulatorServer::spawn(emulator()).unwrap();
+        let mut idler = Client::connect(server.addr()).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        client.run_command(Command::Add("a".into())).unwrap();
+
+        // each connection hears about every change, however long ago it was made
+        assert_eq!(idler.idle(&[]).unwrap(), vec![SubSystem::Playlist]);
+        let waiting = thread::spawn(move || {
+            let changed = idler.idle(&[SubSystem::Player]).unwrap();
+            (idler, changed)
+        });
+        thread::sleep(Duration::from_millis(50));
+        client.run_command(Command::Volume(20)).unwrap();
+        client.run_command(Command::Play(0)).unwrap();
+        let (mut idler, changed) = waiting.join().unwrap();
+        assert_eq!(changed, vec![SubSystem::Player]);
+        assert_eq!(idler.idle(&[]).unwrap(), vec![SubSystem::Mixer]);
+
+        let handle = idler.idle_handle().unwrap();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.noidle().unwrap();
+        });
+        assert_eq!(idler.idle(&[]).unwrap(), vec![]);
+        assert_eq!(client.idle(&[SubSystem::Playlist]).unwrap(), vec![SubSystem::Playlist]);
+    }
+
+    #[test]
+    fn served_oversized_request() {
+        let server = EmulatorServer::spawn(emulator()).unwrap();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut greeting = [0; 14];
+        stream.read_exact(&mut greeting).unwrap();
+        // a command list that never ends
+        let mut request = b"command_list_begin\n".to_vec();
+        request.extend(b"ping\n".repeat(server::MAX_REQUEST_SIZE / 5));
+        stream.write_all(&request).unwrap();
+        // the client is dropped, rather than the server buffering forever
+        let mut rest = Vec::new();
+        match stream.read_to_end(&mut rest) {
+            Ok(_) => assert!(rest.is_empty()),
+            Err(ref e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+        }
+    }
+}
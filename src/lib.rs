@@ -3,6 +3,8 @@
 #[macro_use] extern crate nom;
 extern crate semver;
 extern crate tokio_core;
+extern crate tokio_codec;
+extern crate bytes;
 extern crate futures;
 extern crate chrono;
 
@@ -10,7 +12,14 @@ extern crate chrono;
 pub mod types;
 pub mod protocol;
 pub mod client;
+pub mod reconnect;
+pub mod sticker;
+pub mod async_client;
 pub mod util;
+#[cfg(any(test, feature = "test-server"))]
+pub mod test_server;
+#[cfg(any(test, feature = "test-server"))]
+pub mod emulator;
 
 #[cfg(test)]
 mod tests {
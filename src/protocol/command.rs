@@ -1,14 +1,18 @@
+use std::collections::HashMap;
 use std::io;
+use std::slice;
 use std::str;
 use std::time::Duration;
 
 use nom::*;
-use chrono::{UTC, TimeZone};
+use chrono::{DateTime, UTC, TimeZone};
 
-use super::{Dispatch, ParseResponse, parse_ok, parse_list_ok, parse_num_bool, parse_f32};
+use super::{Dispatch, ParseResponse, parse_ok, parse_list_ok, parse_num_bool, parse_f32,
+    parse_result, parse_kv, quote, format_seconds};
 use util::{parse_bytes};
-use types::{SubSystem, ReplayGainMode, State, Status, MaybeStatus, Stats, MaybeStats,
-    Range, SingleOrRange, TagType};
+use types::{CmdError, SubSystem, ReplayGainMode, State, Status, MaybeStatus, Stats, MaybeStats,
+    Range, SingleOrRange, TagType, Tag, Song, TimeRange, TagValues, Query, Playlist,
+    SongCount, Output, Mount, Neighbor, Message, Decoder};
 
 /// Of form name: value\n
 macro_rules! parse_status_line (
@@ -44,6 +48,11 @@ impl CommandList {
     pub fn push(&mut self, c: Command) {
         self.0.push(c);
     }
+
+    /// Iterate over the commands in the list, in order
+    pub fn iter(&self) -> slice::Iter<'_, Command> {
+        self.0.iter()
+    }
 }
 
 impl Dispatch for CommandList {
@@ -60,26 +69,59 @@ impl Dispatch for CommandList {
 }
 
 impl ParseResponse for CommandList {
-    type ResponseType = Vec<CommandResponse>;
+    type ResponseType = CommandListResponse;
 
     fn parse_response<'a>(&self, i: &'a [u8]) -> IResult<&'a [u8], Self::ResponseType> {
-        let mut response = Vec::with_capacity(self.0.len());
+        let mut responses = Vec::with_capacity(self.0.len());
         let mut i_inner = i;
         for cmd in &self.0 {
-            let command_response = match cmd.parse_response(i_inner) {
-                IResult::Done(i, res) => {
+            let command_response = match parse_result(i_inner, |i| cmd.parse_response(i)) {
+                IResult::Done(i, Ok(res)) => {
                     i_inner = i;
                     res
                 },
+                IResult::Done(i, Err(e)) => {
+                    // the server stops processing the list at the first error
+                    return IResult::Done(i, CommandListResponse {
+                        responses: responses,
+                        error: Some(e)
+                    });
+                },
                 IResult::Error(e) => { return IResult::Error(e) },
                 IResult::Incomplete(n) => { return IResult::Incomplete(n) }
             };
-            response.push(command_response);
+            responses.push(command_response);
             let (i, _) = try_parse!(i_inner, parse_list_ok);
             i_inner = i;
         }
-        let (i, _) = try_parse!(i_inner, dbg!(parse_ok));
-        IResult::Done(i, response)
+        let (i, _) = try_parse!(i_inner, parse_ok);
+        IResult::Done(i, CommandListResponse {
+            responses: responses,
+            error: None
+        })
+    }
+}
+
+/// The response to a command list.
+///
+/// The server stops running a command list at the first command that fails, so this contains the
+/// responses of the commands that ran successfully, along with the error if there was one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandListResponse {
+    /// The responses of the successful commands, in the order they were sent
+    pub responses: Vec<CommandResponse>,
+    /// The error from the failed command, if any. `error.command_no` is the index of the failed
+    /// command in the list, and is equal to `responses.len()`
+    pub error: Option<CmdError>
+}
+
+impl CommandListResponse {
+    /// Get the responses if all commands succeeded, or the error if any failed
+    pub fn into_result(self) -> Result<Vec<CommandResponse>, CmdError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.responses)
+        }
     }
 }
 
@@ -98,6 +140,11 @@ pub enum Command {
     ///
     /// If list of subsystems is empty, all subsystem changes are subscribed to
     Idle(Vec<SubSystem>),
+    /// Cancels an `Idle` command. The server then responds to the `Idle` immediately.
+    ///
+    /// This is the only command that may be sent while idling. If the client isn't idling, it is
+    /// ignored by the server and has no response.
+    NoIdle,
     /// Reports the current status of the player and the volume level.
     Status,
     /// Displays statistics.
@@ -180,21 +227,15 @@ pub enum Command {
         from: String,
         to: u32
     },
-    /// Finds songs in current playlist with strict matching
-    PlaylistFind {
-        tag: String,
-        needle: String,
-    },
+    /// Finds songs in the current playlist that exactly match the query
+    PlaylistFind(Query),
     /// Displays either a list of songs, or the song with id if Some
     PlaylistId(Option<u32>),
     /// Displays a list of all songs in playlist, or if position/range is passed,
     /// displays information only for the songs in range/at position
     PlaylistInfo(Option<SingleOrRange>),
-    /// Searches case-insensitively for partial matches in the current playlist
-    PlaylistSearch {
-        tag: TagType,
-        needle: String
-    },
+    /// Like `PlaylistFind`, but matches case-insensitively and on parts of values
+    PlaylistSearch(Query),
     /// Displays changes songs currently in the playlist since given version.
     ///
     /// Start and end positions may be given to limit the output to changes in the
@@ -293,8 +334,7 @@ pub enum Command {
     Remove(String),
     /// Saves the current playlist to the given name.
     Save(String),
-    /// Counts the number of songs and their total playtime in the database matching the given tag
-    /// exactly.
+    /// Counts the number of songs and their total playtime in the database matching the query.
     ///
     /// The group option can be used to sum over the given tag, rather than to a single value.
     ///
@@ -304,7 +344,7 @@ pub enum Command {
     ///
     /// ```ignore
     /// Count {
-    ///     tag: (TagType::Artist, "The Beatles".into()),
+    ///     query: vec![(TagType::Artist.into(), "The Beatles".into())].into(),
     ///     group: None,
     /// }
     /// ```
@@ -313,12 +353,12 @@ pub enum Command {
     ///
     /// ```ignore
     /// Count {
-    ///     tag: (TagType::Genre, "Rock".into()),
-    ///     group: TagType::Artist,
+    ///     query: Filter::equal(TagType::Genre, "Rock").into(),
+    ///     group: Some(TagType::Artist),
     /// }
     /// ```
     Count {
-        tag: (TagType, String),
+        query: Query,
         group: Option<TagType>,
     },
     /// Counts the number of songs and groups them using the given tag type.
@@ -326,7 +366,232 @@ pub enum Command {
     /// > **Aside**: In the underlying protocol this is a variant of the previous command, but it is
     /// > easier to provide type safety by splitting it out (don't have to introduce a new enum).
     GroupCount(TagType),
+    /// Lists all songs in the database, or under the given directory, with their metadata.
+    ///
+    /// Directories and playlists in the response are skipped. On a big database the response is
+    /// very large, so consider reading it a song at a time with `Client::iter_songs`.
+    ListAllInfo(Option<String>),
+    /// Finds songs in the database that exactly match the query.
+    ///
+    /// If a window is given, only that part of the results is returned.
+    Find {
+        query: Query,
+        window: Option<Range>,
+    },
+    /// Finds songs in the database exactly matching the query and adds them to the current
+    /// playlist.
+    FindAdd(Query),
+    /// Lists the unique values of the given tag, for songs matching the query.
+    ///
+    /// The results can be grouped by the values of one or more other tags.
+    ///
+    /// # Examples
+    ///
+    /// List all album names, grouped by album artist:
+    ///
+    /// ```ignore
+    /// List {
+    ///     tag: TagType::Album,
+    ///     query: Query::all(),
+    ///     group: vec![TagType::AlbumArtist],
+    /// }
+    /// ```
+    List {
+        tag: TagType,
+        query: Query,
+        group: Vec<TagType>,
+    },
+    /// Like `Find`, but matches case-insensitively and on parts of values.
+    Search {
+        query: Query,
+        window: Option<Range>,
+    },
+    /// Like `FindAdd`, but matches case-insensitively and on parts of values.
+    SearchAdd(Query),
+    /// Searches for songs like `Search` and adds them to the given stored playlist, creating it
+    /// if it doesn't exist.
+    SearchAddPlaylist {
+        playlist: String,
+        query: Query,
+    },
+    /// Authenticates with the server, giving the connection the permissions that go with the
+    /// password.
+    Password(String),
+    /// Lists the commands the current connection is allowed to run.
+    Commands,
+    /// Lists the commands the current connection is not allowed to run.
+    NotCommands,
+    /// Lists the tag types the server reads from songs.
+    TagTypes,
+    /// Lists the uri schemes the server can play, e.g. `http://`.
+    UrlHandlers,
+    /// Lists the decoder plugins, and the file types they decode.
+    Decoders,
+    /// Gets the server's music directory. Only allowed on local (unix socket) connections.
+    Config,
+    /// Subscribes to a channel for client-to-client messages, creating it if nobody else is
+    /// subscribed to it.
+    Subscribe(String),
+    /// Unsubscribes from a channel.
+    Unsubscribe(String),
+    /// Lists the channels that have subscribers.
+    Channels,
+    /// Reads the messages sent to the channels this connection is subscribed to since they were
+    /// last read. Idling on `SubSystem::Message` waits for new ones.
+    ReadMessages,
+    /// Sends a message to the subscribers of a channel. Fails with `CmdErrorType::NoExist` if
+    /// nobody is subscribed to it.
+    SendMessage {
+        channel: String,
+        text: String,
+    },
+    /// Does nothing. Used to keep the connection from timing out.
+    Ping,
+    /// Reads a sticker (a name and value stored by clients in the server's sticker database) from
+    /// a song. Fails with `CmdErrorType::NoExist` if the song doesn't have the sticker.
+    StickerGet {
+        uri: String,
+        name: String,
+    },
+    /// Sets a sticker on a song, replacing any existing value.
+    StickerSet {
+        uri: String,
+        name: String,
+        value: String,
+    },
+    /// Deletes a sticker from a song, or all of its stickers if no name is given.
+    StickerDelete {
+        uri: String,
+        name: Option<String>,
+    },
+    /// Lists the stickers on a song.
+    StickerList(String),
+    /// Finds the songs in a directory (and its subdirectories) that have the given sticker,
+    /// along with its values.
+    StickerFind {
+        uri: String,
+        name: String,
+    },
+    /// Lists the audio outputs.
+    Outputs,
+    /// Turns on an audio output, by its id.
+    EnableOutput(u32),
+    /// Turns off an audio output, by its id.
+    DisableOutput(u32),
+    /// Turns an audio output off if it is on, and on if it is off.
+    ToggleOutput(u32),
+    /// Mounts a storage, e.g. `smb://server/share`, at a path in the music directory.
+    Mount {
+        path: String,
+        uri: String,
+    },
+    /// Unmounts the storage mounted at a path.
+    Unmount(String),
+    /// Lists the mounted storages, including the music directory itself.
+    ListMounts,
+    /// Lists the storages found on the network that could be mounted. Fails if the server
+    /// isn't configured to look for any.
+    ListNeighbors,
+}
 
+impl Command {
+    /// The name of the command in the protocol, e.g. `"setvol"` for `Volume`
+    pub fn name(&self) -> &'static str {
+        use self::Command::*;
+        match *self {
+            ClearError => "clearerror",
+            CurrentSong => "currentsong",
+            Idle(_) => "idle",
+            NoIdle => "noidle",
+            Status => "status",
+            Stats => "stats",
+            Consume(_) => "consume",
+            Crossfade(_) => "crossfade",
+            MixRampDB(_) => "mixrampdb",
+            MixRampDelay(_) => "mixrampdelay",
+            Random(_) => "random",
+            Repeat(_) => "repeat",
+            Volume(_) => "setvol",
+            Single(_) => "single",
+            ReplayGainMode(_) => "replay_gain_mode",
+            ReplayGainStatus => "replay_gain_status",
+            Next => "next",
+            Pause(_) => "pause",
+            Play(_) => "play",
+            PlayId(_) => "playid",
+            Previous => "previous",
+            Seek { .. } => "seek",
+            SeekId { .. } => "seekid",
+            SeekCurrent(_) => "seekcur",
+            Stop => "stop",
+            Add(_) => "add",
+            AddId { .. } => "addid",
+            Clear => "clear",
+            Delete(_) => "delete",
+            DeleteId(_) => "deleteid",
+            Move { .. } => "move",
+            MoveId { .. } => "moveid",
+            PlaylistFind(_) => "playlistfind",
+            PlaylistId(_) => "playlistid",
+            PlaylistInfo(_) => "playlistinfo",
+            PlaylistSearch(_) => "playlistsearch",
+            PlaylistChanges { .. } => "plchanges",
+            PlaylistChangesPositionId { .. } => "plchangesposid",
+            Priority { .. } => "prio",
+            PriorityId { .. } => "prioid",
+            RangeId { .. } => "rangeid",
+            Shuffle(_) => "shuffle",
+            Swap(..) => "swap",
+            SwapId(..) => "swapid",
+            AddTagId { .. } => "addtagid",
+            ClearTagId { .. } => "cleartagid",
+            ListPlaylist(_) => "listplaylist",
+            ListPlaylistInfo(_) => "listplaylistinfo",
+            ListPlaylists => "listplaylists",
+            Load { .. } => "load",
+            PlaylistAdd { .. } => "playlistadd",
+            PlaylistClear(_) => "playlistclear",
+            PlaylistDelete { .. } => "playlistdelete",
+            PlaylistMove { .. } => "playlistmove",
+            Rename { .. } => "rename",
+            Remove(_) => "rm",
+            Save(_) => "save",
+            Count { .. } | GroupCount(_) => "count",
+            ListAllInfo(_) => "listallinfo",
+            Find { .. } => "find",
+            FindAdd(_) => "findadd",
+            List { .. } => "list",
+            Search { .. } => "search",
+            SearchAdd(_) => "searchadd",
+            SearchAddPlaylist { .. } => "searchaddpl",
+            Password(_) => "password",
+            Commands => "commands",
+            NotCommands => "notcommands",
+            TagTypes => "tagtypes",
+            UrlHandlers => "urlhandlers",
+            Decoders => "decoders",
+            Config => "config",
+            Subscribe(_) => "subscribe",
+            Unsubscribe(_) => "unsubscribe",
+            Channels => "channels",
+            ReadMessages => "readmessages",
+            SendMessage { .. } => "sendmessage",
+            Ping => "ping",
+            StickerGet { .. }
+                | StickerSet { .. }
+                | StickerDelete { .. }
+                | StickerList(_)
+                | StickerFind { .. } => "sticker",
+            Outputs => "outputs",
+            EnableOutput(_) => "enableoutput",
+            DisableOutput(_) => "disableoutput",
+            ToggleOutput(_) => "toggleoutput",
+            Mount { .. } => "mount",
+            Unmount(_) => "unmount",
+            ListMounts => "listmounts",
+            ListNeighbors => "listneighbors",
+        }
+    }
 }
 
 impl Dispatch for Command {
@@ -336,7 +601,14 @@ impl Dispatch for Command {
         match *self {
             Cmd::ClearError => write!(w, "clearerror\n"),
             Cmd::CurrentSong => write!(w, "currentsong\n"),
-            Cmd::Idle(ref sub) => unimplemented!(),
+            Cmd::Idle(ref subs) => {
+                write!(w, "idle")?;
+                for sub in subs {
+                    write!(w, " {}", sub)?;
+                }
+                write!(w, "\n")
+            },
+            Cmd::NoIdle => write!(w, "noidle\n"),
             Cmd::Status => write!(w, "status\n"),
             Cmd::Stats => write!(w, "stats\n"),
             Cmd::Consume(on) => if on {
@@ -380,29 +652,29 @@ impl Dispatch for Command {
                 write!(w, "pause 0\n")
             },
             Cmd::Play(pos) => write!(w, "play {}\n", pos),
-            Cmd::PlayId(ref id) => write!(w, "playid {}\n", id),
+            Cmd::PlayId(ref id) => write!(w, "playid {}\n", quote(id)?),
             Cmd::Previous => write!(w, "previous\n"),
             Cmd::Seek {
                 song_position: pos,
                 time: time
-            } => unimplemented!(),
+            } => write!(w, "seek {} {}\n", pos, format_seconds(time)),
             Cmd::SeekId {
                 song_id: ref song_id,
                 time: time
-            } => unimplemented!(),
-            Cmd::SeekCurrent(pos) => unimplemented!(),
+            } => write!(w, "seekid {} {}\n", quote(song_id)?, format_seconds(time)),
+            Cmd::SeekCurrent(time) => write!(w, "seekcur {}\n", format_seconds(time)),
             Cmd::Stop => write!(w, "stop\n"),
-            Cmd::Add(ref uri) => write!(w, "add {}\n", uri),
+            Cmd::Add(ref uri) => write!(w, "add {}\n", quote(uri)?),
             Cmd::AddId {
                 uri: ref uri,
                 position: ref position
             } => match *position {
-                Some(pos) => write!(w, "addid {} {}\n", uri, pos),
-                None => write!(w, "addid {}\n", uri),
+                Some(pos) => write!(w, "addid {} {}\n", quote(uri)?, pos),
+                None => write!(w, "addid {}\n", quote(uri)?),
             },
             Cmd::Clear => write!(w, "clear\n"),
             Cmd::Delete(s_or_r) => write!(w, "delete {}\n", s_or_r),
-            Cmd::DeleteId(ref id) => write!(w, "deleteid {}\n", id),
+            Cmd::DeleteId(ref id) => write!(w, "deleteid {}\n", quote(id)?),
             Cmd::Move {
                 from: from,
                 to: to
@@ -410,11 +682,12 @@ impl Dispatch for Command {
             Cmd::MoveId {
                 from: ref from,
                 to: to
-            } => write!(w, "moveid {} {}\n", from, to),
-            Cmd::PlaylistFind {
-                tag: ref tag,
-                needle: ref needle,
-            } => write!(w, "playlistfind {} {}\n", tag, needle),
+            } => write!(w, "moveid {} {}\n", quote(from)?, to),
+            Cmd::PlaylistFind(ref query) => {
+                write!(w, "playlistfind")?;
+                write_query(w, query)?;
+                write!(w, "\n")
+            },
             Cmd::PlaylistId(song) => match song {
                 Some(song) => write!(w, "playlistid {}\n", song),
                 None => write!(w, "playlistid\n"),
@@ -423,23 +696,24 @@ impl Dispatch for Command {
                 Some(s_or_r) => write!(w, "playlistinfo {}\n", s_or_r),
                 None => write!(w, "playlistinfo\n"),
             },
-            Cmd::PlaylistSearch {
-                tag: ref tag,
-                needle: ref needle
-            } => write!(w, "playlistfind {} {}\n", tag, needle),
+            Cmd::PlaylistSearch(ref query) => {
+                write!(w, "playlistsearch")?;
+                write_query(w, query)?;
+                write!(w, "\n")
+            },
             Cmd::PlaylistChanges {
                 version: ref version,
                 range: range
             } => match range {
-                Some(range) => write!(w, "plchanges {} {}\n", version, range),
-                None => write!(w, "plchanges {}\n", version),
+                Some(range) => write!(w, "plchanges {} {}\n", quote(version)?, range),
+                None => write!(w, "plchanges {}\n", quote(version)?),
             },
             Cmd::PlaylistChangesPositionId {
                 version: ref version,
                 range: range,
             } => match range {
-                Some(range) => write!(w, "plchangesposid {} {}\n", version, range),
-                None => write!(w, "plchangesposid {}\n", version),
+                Some(range) => write!(w, "plchangesposid {} {}\n", quote(version)?, range),
+                None => write!(w, "plchangesposid {}\n", quote(version)?),
             },
             Cmd::Priority {
                 priority: ref priority,
@@ -457,70 +731,188 @@ impl Dispatch for Command {
             } => {
                 write!(w, "prioid {}", priority)?;
                 for song_group in songs {
-                    write!(w, " {}", song_group)?;
+                    write!(w, " {}", quote(song_group)?)?;
                 }
                 write!(w, "\n")
             },
             Cmd::RangeId {
                 id: ref id,
                 range: ref range,
-            } => write!(w, "rangeid {} {}\n", id, range),
-            Cmd::Shuffle(range) => write!(w, "suffle {}\n", range),
+            } => write!(w, "rangeid {} {}\n", quote(id)?, range),
+            Cmd::Shuffle(range) => write!(w, "shuffle {}\n", range),
             Cmd::Swap(pos1, pos2) => write!(w, "swap {} {}\n", pos1, pos2),
-            Cmd::SwapId(ref id1, ref id2) => write!(w, "swapid {} {}\n", id1, id2),
+            Cmd::SwapId(ref id1, ref id2) => write!(w, "swapid {} {}\n", quote(id1)?, quote(id2)?),
             Cmd::AddTagId {
                 id: ref id,
                 tag: ref tag
-            } => write!(w, "addtagid {} {} {}\n", id, tag.0, tag.1),
+            } => write!(w, "addtagid {} {} {}\n", quote(id)?, tag.0, quote(&tag.1)?),
             Cmd::ClearTagId {
                 id: ref id,
                 tag: tag,
-            } => write!(w, "cleartagid {} {}\n", id, tag),
-            Cmd::ListPlaylist(ref name) => write!(w, "listplaylist {}\n", name),
-            Cmd::ListPlaylistInfo(ref name) => write!(w, "listplaylistinfo {}\n", name),
+            } => write!(w, "cleartagid {} {}\n", quote(id)?, tag),
+            Cmd::ListPlaylist(ref name) => write!(w, "listplaylist {}\n", quote(name)?),
+            Cmd::ListPlaylistInfo(ref name) => write!(w, "listplaylistinfo {}\n", quote(name)?),
             Cmd::ListPlaylists => write!(w, "listplaylists\n"),
             Cmd::Load {
                 name: ref name,
                 range: range
             } => match range {
-                Some(range) => write!(w, "load {} {}\n", name, range),
-                None => write!(w, "load {}\n", name),
+                Some(range) => write!(w, "load {} {}\n", quote(name)?, range),
+                None => write!(w, "load {}\n", quote(name)?),
             },
             Cmd::PlaylistAdd {
                 playlist: ref playlist,
                 song: ref song,
-            } => write!(w, "playlistadd {} {}\n", playlist, song),
-            Cmd::PlaylistClear(ref name) => write!(w, "playlistclear {}\n", name),
+            } => write!(w, "playlistadd {} {}\n", quote(playlist)?, quote(song)?),
+            Cmd::PlaylistClear(ref name) => write!(w, "playlistclear {}\n", quote(name)?),
             Cmd::PlaylistDelete {
                 playlist: ref playlist,
                 song: song,
-            } => write!(w, "playlistdelete {} {}\n", playlist, song),
+            } => write!(w, "playlistdelete {} {}\n", quote(playlist)?, song),
             Cmd::PlaylistMove {
                 playlist: ref playlist,
                 from: from,
                 to: to,
-            } => write!(w, "playlistmove {} {} {}\n", playlist, from, to),
+            } => write!(w, "playlistmove {} {} {}\n", quote(playlist)?, from, to),
             Cmd::Rename {
                 old_name: ref old_name,
                 new_name: ref new_name,
-            } => write!(w, "rename {} {}\n", old_name, new_name),
-            Cmd::Remove(ref name) => write!(w, "rm {}\n", name),
-            Cmd::Save(ref name) => write!(w, "save {}\n", name),
+            } => write!(w, "rename {} {}\n", quote(old_name)?, quote(new_name)?),
+            Cmd::Remove(ref name) => write!(w, "rm {}\n", quote(name)?),
+            Cmd::Save(ref name) => write!(w, "save {}\n", quote(name)?),
             Cmd::Count {
-                tag: ref tag,
+                query: ref query,
                 group: group,
-            } => match group {
-                 Some(group) => write!(w, "count {} {} group {}\n", tag.0, tag.1, group),
-                 None => write!(w, "count {} {}\n", tag.0, tag.1),
+            } => {
+                write!(w, "count")?;
+                write_query(w, query)?;
+                match group {
+                    Some(group) => write!(w, " group {}\n", group),
+                    None => write!(w, "\n"),
+                }
             },
             Cmd::GroupCount(tag) => write!(w, "count group {}\n", tag),
-            /*
-            */
-            _ => unimplemented!(),
+            Cmd::ListAllInfo(None) => write!(w, "listallinfo\n"),
+            Cmd::ListAllInfo(Some(ref uri)) => write!(w, "listallinfo {}\n", quote(uri)?),
+            Cmd::Find {
+                query: ref query,
+                window: window,
+            } => {
+                write!(w, "find")?;
+                write_query(w, query)?;
+                write_window(w, window)
+            },
+            Cmd::FindAdd(ref query) => {
+                write!(w, "findadd")?;
+                write_query(w, query)?;
+                write!(w, "\n")
+            },
+            Cmd::List {
+                tag: tag,
+                query: ref query,
+                group: ref group,
+            } => {
+                write!(w, "list {}", tag)?;
+                write_query(w, query)?;
+                for group_tag in group {
+                    write!(w, " group {}", group_tag)?;
+                }
+                write!(w, "\n")
+            },
+            Cmd::Search {
+                query: ref query,
+                window: window,
+            } => {
+                write!(w, "search")?;
+                write_query(w, query)?;
+                write_window(w, window)
+            },
+            Cmd::SearchAdd(ref query) => {
+                write!(w, "searchadd")?;
+                write_query(w, query)?;
+                write!(w, "\n")
+            },
+            Cmd::SearchAddPlaylist {
+                playlist: ref playlist,
+                query: ref query,
+            } => {
+                write!(w, "searchaddpl {}", quote(playlist)?)?;
+                write_query(w, query)?;
+                write!(w, "\n")
+            },
+            Cmd::Password(ref password) => write!(w, "password {}\n", quote(password)?),
+            Cmd::Commands => write!(w, "commands\n"),
+            Cmd::NotCommands => write!(w, "notcommands\n"),
+            Cmd::TagTypes => write!(w, "tagtypes\n"),
+            Cmd::UrlHandlers => write!(w, "urlhandlers\n"),
+            Cmd::Decoders => write!(w, "decoders\n"),
+            Cmd::Config => write!(w, "config\n"),
+            Cmd::Subscribe(ref channel) => write!(w, "subscribe {}\n", quote(channel)?),
+            Cmd::Unsubscribe(ref channel) => write!(w, "unsubscribe {}\n", quote(channel)?),
+            Cmd::Channels => write!(w, "channels\n"),
+            Cmd::ReadMessages => write!(w, "readmessages\n"),
+            Cmd::SendMessage { ref channel, ref text } => {
+                write!(w, "sendmessage {} {}\n", quote(channel)?, quote(text)?)
+            },
+            Cmd::Ping => write!(w, "ping\n"),
+            Cmd::StickerGet { ref uri, ref name } => {
+                write!(w, "sticker get song {} {}\n", quote(uri)?, quote(name)?)
+            },
+            Cmd::StickerSet { ref uri, ref name, ref value } => write!(
+                w,
+                "sticker set song {} {} {}\n",
+                quote(uri)?,
+                quote(name)?,
+                quote(value)?
+            ),
+            Cmd::StickerDelete { ref uri, ref name } => {
+                write!(w, "sticker delete song {}", quote(uri)?)?;
+                if let Some(ref name) = *name {
+                    write!(w, " {}", quote(name)?)?;
+                }
+                write!(w, "\n")
+            },
+            Cmd::StickerList(ref uri) => write!(w, "sticker list song {}\n", quote(uri)?),
+            Cmd::StickerFind { ref uri, ref name } => {
+                write!(w, "sticker find song {} {}\n", quote(uri)?, quote(name)?)
+            },
+            Cmd::Outputs => write!(w, "outputs\n"),
+            Cmd::EnableOutput(id) => write!(w, "enableoutput {}\n", id),
+            Cmd::DisableOutput(id) => write!(w, "disableoutput {}\n", id),
+            Cmd::ToggleOutput(id) => write!(w, "toggleoutput {}\n", id),
+            Cmd::Mount { ref path, ref uri } => {
+                write!(w, "mount {} {}\n", quote(path)?, quote(uri)?)
+            },
+            Cmd::Unmount(ref path) => write!(w, "unmount {}\n", quote(path)?),
+            Cmd::ListMounts => write!(w, "listmounts\n"),
+            Cmd::ListNeighbors => write!(w, "listneighbors\n"),
         }
     }
 }
 
+/// Writes the (selector, needle) pairs or filter expression of a database query.
+///
+/// The filter expression is quoted as a whole, so the values inside it end up escaped twice.
+fn write_query(w: &mut io::Write, query: &Query) -> io::Result<()> {
+    match *query {
+        Query::Tags(ref tags) => {
+            for &(selector, ref needle) in tags {
+                write!(w, " {} {}", selector, quote(needle)?)?;
+            }
+            Ok(())
+        },
+        Query::Filter(ref filter) => write!(w, " {}", quote(&filter.to_string())?),
+    }
+}
+
+/// Writes the optional window of a database query, and ends the command
+fn write_window(w: &mut io::Write, window: Option<Range>) -> io::Result<()> {
+    match window {
+        Some(window) => write!(w, " window {}\n", window),
+        None => write!(w, "\n"),
+    }
+}
+
 impl ParseResponse for Command {
     type ResponseType = CommandResponse;
 
@@ -530,8 +922,9 @@ impl ParseResponse for Command {
         use self::Command::*;
         match *self {
             ClearError => IResult::Done(i, CommandResponse::Blank),
-            CurrentSong => IResult::Done(i, CommandResponse::Blank),
-            Idle(ref subs) => unimplemented!(),
+            CurrentSong => parse_songs(i).map(|mut songs| CommandResponse::Song(songs.pop())),
+            Idle(_) => parse_idle_response(i),
+            NoIdle => IResult::Done(i, CommandResponse::Blank),
             Status => parse_status_response(i),
             Stats => parse_stats_response(i),
             Consume(_) => IResult::Done(i, CommandResponse::Blank),
@@ -543,7 +936,7 @@ impl ParseResponse for Command {
             Volume(_) => IResult::Done(i, CommandResponse::Blank),
             Single(_) => IResult::Done(i, CommandResponse::Blank),
             ReplayGainMode(_) => IResult::Done(i, CommandResponse::Blank),
-            ReplayGainStatus => unimplemented!(),
+            ReplayGainStatus => parse_replay_gain_status(i),
             Next => IResult::Done(i, CommandResponse::Blank),
             Pause(_) => IResult::Done(i, CommandResponse::Blank),
             Play(_) => IResult::Done(i, CommandResponse::Blank),
@@ -559,7 +952,74 @@ impl ParseResponse for Command {
             } => IResult::Done(i, CommandResponse::Blank),
             SeekCurrent(Duration) => IResult::Done(i, CommandResponse::Blank),
             Stop => IResult::Done(i, CommandResponse::Blank),
-            _ => unimplemented!()
+            AddId { .. } => map!(
+                i,
+                map_res!(map_res!(parse_status_line!(b"Id"), str::from_utf8), str::parse::<u32>),
+                CommandResponse::Id
+            ),
+            Add(_)
+                | Clear
+                | Delete(_)
+                | DeleteId(_)
+                | Move { .. }
+                | MoveId { .. }
+                | Priority { .. }
+                | PriorityId { .. }
+                | RangeId { .. }
+                | Shuffle(_)
+                | Swap(..)
+                | SwapId(..)
+                | AddTagId { .. }
+                | ClearTagId { .. } => IResult::Done(i, CommandResponse::Blank),
+            PlaylistChangesPositionId { .. } => parse_position_ids(i),
+            PlaylistFind(_)
+                | PlaylistId(_)
+                | PlaylistInfo(_)
+                | PlaylistSearch(_)
+                | PlaylistChanges { .. }
+                | ListPlaylist(_)
+                | ListPlaylistInfo(_)
+                | Find { .. }
+                | Search { .. } => parse_songs(i).map(CommandResponse::Songs),
+            ListAllInfo(_) => parse_song_entries(i).map(CommandResponse::Songs),
+            FindAdd(_) => IResult::Done(i, CommandResponse::Blank),
+            List { tag, ref group, .. } => parse_list_response(i, tag, group),
+            Count { group, .. } => parse_count_response(i, group),
+            GroupCount(tag) => parse_count_response(i, Some(tag)),
+            SearchAdd(_) => IResult::Done(i, CommandResponse::Blank),
+            SearchAddPlaylist { .. } => IResult::Done(i, CommandResponse::Blank),
+            ListPlaylists => parse_playlists(i),
+            Load { .. }
+                | PlaylistAdd { .. }
+                | PlaylistClear(_)
+                | PlaylistDelete { .. }
+                | PlaylistMove { .. }
+                | Rename { .. }
+                | Remove(_)
+                | Save(_) => IResult::Done(i, CommandResponse::Blank),
+            Password(_)
+                | Subscribe(_)
+                | Unsubscribe(_)
+                | SendMessage { .. }
+                | Ping
+                | StickerSet { .. }
+                | StickerDelete { .. } => IResult::Done(i, CommandResponse::Blank),
+            StickerGet { .. } | StickerList(_) => parse_sticker_values(i),
+            StickerFind { ref name, .. } => parse_sticker_matches(i, name),
+            Outputs => parse_outputs(i),
+            EnableOutput(_)
+                | DisableOutput(_)
+                | ToggleOutput(_) => IResult::Done(i, CommandResponse::Blank),
+            Mount { .. } | Unmount(_) => IResult::Done(i, CommandResponse::Blank),
+            ListMounts => parse_mounts(i),
+            ListNeighbors => parse_neighbors(i),
+            Commands | NotCommands => parse_command_names(i),
+            TagTypes => parse_tag_types(i),
+            UrlHandlers => parse_url_handlers(i),
+            Decoders => parse_decoders(i),
+            Config => parse_config(i),
+            Channels => parse_channels(i),
+            ReadMessages => parse_messages(i),
         }
         //IResult::Done(i, res)
     }
@@ -622,6 +1082,12 @@ fn parse_single_status_response<'a>(i: &'a[u8], status: &mut MaybeStatus) -> IRe
         | map_res!(parse_status_line!(b"nextsongid"), parse_bytes::<u32>) => { |o| {
             status.next_song_id = Some(o);
         }}
+        | map_res!(parse_status_line!(b"updating_db"), parse_bytes::<u32>) => { |o| {
+            status.updating_db = Some(o);
+        }}
+        | map_res!(parse_status_line!(b"error"), str::from_utf8) => { |o: &str| {
+            status.error = Some(o.to_owned());
+        }}
     )
 }
 
@@ -679,20 +1145,47 @@ list_OK
             mix_ramp_db: 0.0,
             state: State::Play,
             crossfade: 1_000_000_000,
-            song: 0,
-            song_id: 9,
-            elapsed: Duration::new(80, 74_000_000),
+            song: Some(0),
+            song_id: Some(9),
+            elapsed: Some(Duration::new(80, 74_000_000)),
             duration: None,
-            bitrate: 320,
-            audio: (44100, 24, 2),
-            next_song: 0,
-            next_song_id: 9,
+            bitrate: Some(320),
+            audio: Some((44100, 24, 2)),
+            next_song: Some(0),
+            next_song_id: Some(9),
             updating_db: None,
             error: None
         }))
     );
 }
 
+#[test]
+fn test_parse_status_response_stopped() {
+    let input = b"volume: 100
+repeat: 0
+random: 0
+single: 0
+consume: 0
+playlist: 1
+playlistlength: 0
+mixrampdb: 0.000000
+state: stop
+error: Failed to open \"alsa\"
+OK
+";
+    match parse_status_response(&input[..]) {
+        IResult::Done(rest, CommandResponse::Status(status)) => {
+            assert_eq!(rest, &b"OK\n"[..]);
+            assert_eq!(status.state, State::Stop);
+            assert_eq!(status.song, None);
+            assert_eq!(status.elapsed, None);
+            assert_eq!(status.crossfade, 0);
+            assert_eq!(status.error, Some("Failed to open \"alsa\"".to_owned()));
+        },
+        other => panic!("expected status, got {:?}", other),
+    }
+}
+
 fn parse_single_stats_response<'a>(i: &'a[u8], stats: &mut MaybeStats) -> IResult<&'a[u8], ()> {
     alt!(i,
         map_res!(parse_status_line!(b"artists"), parse_bytes::<u64>) => { |o| {
@@ -739,6 +1232,965 @@ fn parse_stats_response(i: &[u8]) -> IResult<&[u8], CommandResponse> {
     }
 }
 
+/// Parses a number of seconds, with or without a fractional part
+pub fn parse_seconds(s: &str) -> Option<Duration> {
+    let mut parts = s.splitn(2, '.');
+    let secs = try_opt!(parts.next().and_then(|secs| secs.parse::<u64>().ok()));
+    let nanos = match parts.next() {
+        Some(frac) if frac.len() > 0 && frac.len() <= 9 => {
+            try_opt!(frac.parse::<u32>().ok()) * 10u32.pow(9 - frac.len() as u32)
+        },
+        Some(_) => { return None; },
+        None => 0,
+    };
+    Some(Duration::new(secs, nanos))
+}
+
+/// Sets a field of `song` from a line of a song response
+///
+/// Returns `None` if the value could not be parsed
+fn apply_song_field(song: &mut Song, key: &[u8], value: &[u8]) -> Option<()> {
+    let value = try_opt!(str::from_utf8(value).ok());
+    match key {
+        b"Last-Modified" => {
+            song.last_modified = Some(try_opt!(value.parse::<DateTime<UTC>>().ok()));
+        },
+        b"duration" => { song.duration = Some(try_opt!(parse_seconds(value))); },
+        // deprecated integer version of duration
+        b"Time" => if song.duration.is_none() {
+            song.duration = Some(try_opt!(parse_seconds(value)));
+        },
+        b"Pos" => { song.position = Some(try_opt!(value.parse().ok())); },
+        b"Id" => { song.id = Some(try_opt!(value.parse().ok())); },
+        b"Prio" => { song.priority = Some(try_opt!(value.parse().ok())); },
+        b"Range" => {
+            let mut parts = value.splitn(2, '-');
+            let start = try_opt!(parts.next().and_then(parse_seconds));
+            let end = match parts.next() {
+                Some("") | None => None,
+                Some(end) => Some(try_opt!(parse_seconds(end))),
+            };
+            song.range = Some(TimeRange { start: start, end: end });
+        },
+        key => if let Some(tag_type) = TagType::from_name(key) {
+            song.add_tag(Tag { value: value.to_owned(), tag_type: tag_type });
+        }
+        // anything else (e.g. Format) we don't know about is skipped
+    }
+    Some(())
+}
+
+/// Parses one song, starting at its `file` line and ending before the next entry (a song,
+/// directory or playlist) starts.
+pub fn parse_song(i: &[u8]) -> IResult<&[u8], Song> {
+    let (mut i_inner, file) = try_parse!(i, map_res!(parse_status_line!(b"file"), str::from_utf8));
+    let mut song = Song::new(file);
+
+    loop {
+        match parse_kv(i_inner) {
+            IResult::Done(_, (b"file", _))
+                | IResult::Done(_, (b"directory", _))
+                | IResult::Done(_, (b"playlist", _)) => { break; }
+            IResult::Done(i, (key, value)) => {
+                if apply_song_field(&mut song, key, value).is_none() {
+                    return IResult::Error(error_position!(ErrorKind::Custom(0), i_inner));
+                }
+                i_inner = i;
+            }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+    }
+    IResult::Done(i_inner, song)
+}
+
+/// Parses a list of songs
+pub fn parse_songs(i: &[u8]) -> IResult<&[u8], Vec<Song>> {
+    let mut songs = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        // the list ends at the first line that doesn't start a song
+        match parse_status_line!(i_inner, b"file") {
+            IResult::Done(..) => (),
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+        let (i, song) = try_parse!(i_inner, parse_song);
+        i_inner = i;
+        songs.push(song);
+    }
+    IResult::Done(i_inner, songs)
+}
+
+/// Parses the next song in a list, skipping anything else before it (e.g. directories).
+///
+/// Returns `None` at the first line that isn't a `key: value` pair, which ends the list.
+pub fn parse_next_song(i: &[u8]) -> IResult<&[u8], Option<Song>> {
+    let mut i_inner = i;
+    loop {
+        match parse_kv(i_inner) {
+            IResult::Done(_, (b"file", _)) => { return parse_song(i_inner).map(Some); }
+            IResult::Done(i, _) => { i_inner = i; }
+            IResult::Error(_) => { return IResult::Done(i_inner, None); }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+    }
+}
+
+/// Parses the songs in a list that can also contain other entries, such as directories
+pub fn parse_song_entries(i: &[u8]) -> IResult<&[u8], Vec<Song>> {
+    let mut songs = Vec::new();
+    let mut i_inner = i;
+    loop {
+        match try_parse!(i_inner, parse_next_song) {
+            (i, Some(song)) => {
+                songs.push(song);
+                i_inner = i;
+            },
+            (i, None) => { return IResult::Done(i, songs); }
+        }
+    }
+}
+
+#[test]
+fn test_parse_songs() {
+    let input = b"file: Pink Floyd/Animals/01 Pigs on the Wing 1.flac
+Last-Modified: 2016-11-23T19:03:48Z
+Time: 85
+duration: 85.333
+Artist: Pink Floyd
+Album: Animals
+Title: Pigs on the Wing 1
+Genre: Progressive Rock
+Genre: Art Rock
+MUSICBRAINZ_TRACKID: 3e1e1e1e-1e1e-1e1e-1e1e-1e1e1e1e1e1e
+Pos: 0
+Id: 12
+file: http://example.com/stream
+Prio: 10
+Range: 1.5-
+Pos: 1
+Id: 13
+list_OK
+";
+    let mut first = Song::new("Pink Floyd/Animals/01 Pigs on the Wing 1.flac");
+    first.last_modified = Some(UTC.ymd(2016, 11, 23).and_hms(19, 3, 48));
+    first.duration = Some(Duration::new(85, 333_000_000));
+    first.position = Some(0);
+    first.id = Some(12);
+    first.tags.insert(TagType::Artist, vec!["Pink Floyd".into()]);
+    first.tags.insert(TagType::Album, vec!["Animals".into()]);
+    first.tags.insert(TagType::Title, vec!["Pigs on the Wing 1".into()]);
+    first.tags.insert(TagType::Genre, vec!["Progressive Rock".into(), "Art Rock".into()]);
+    first.tags.insert(
+        TagType::MusicbrainzTrackId,
+        vec!["3e1e1e1e-1e1e-1e1e-1e1e-1e1e1e1e1e1e".into()]
+    );
+    let mut second = Song::new("http://example.com/stream");
+    second.priority = Some(10);
+    second.range = Some(TimeRange { start: Duration::new(1, 500_000_000), end: None });
+    second.position = Some(1);
+    second.id = Some(13);
+    assert_eq!(
+        parse_songs(&input[..]),
+        IResult::Done(&b"list_OK\n"[..], vec![first, second])
+    );
+    assert_eq!(parse_songs(&b"OK\n"[..]), IResult::Done(&b"OK\n"[..], vec![]));
+    assert!(parse_songs(&b"file: a.mp3\nTitle: A"[..]).is_incomplete());
+    assert!(parse_songs(&b"file: a.mp3\nPos: x\nOK\n"[..]).is_err());
+}
+
+#[test]
+fn test_parse_song_entries() {
+    let input = b"directory: Pink Floyd
+Last-Modified: 2016-11-23T19:03:48Z
+directory: Pink Floyd/Animals
+Last-Modified: 2016-11-23T19:03:48Z
+file: Pink Floyd/Animals/01 Pigs on the Wing 1.flac
+Title: Pigs on the Wing 1
+playlist: Pink Floyd/Animals/animals.m3u
+Last-Modified: 2016-11-23T19:03:48Z
+file: Pink Floyd/Animals/02 Dogs.flac
+OK
+";
+    let mut first = Song::new("Pink Floyd/Animals/01 Pigs on the Wing 1.flac");
+    first.tags.insert(TagType::Title, vec!["Pigs on the Wing 1".into()]);
+    let second = Song::new("Pink Floyd/Animals/02 Dogs.flac");
+    assert_eq!(
+        parse_song_entries(&input[..]),
+        IResult::Done(&b"OK\n"[..], vec![first, second])
+    );
+    assert_eq!(parse_next_song(&b"OK\n"[..]), IResult::Done(&b"OK\n"[..], None));
+    assert!(parse_next_song(&b"directory: a\nfile: a/b.mp3\n"[..]).is_incomplete());
+}
+
+/// Parses the response to `List`, grouping values by the given group tags.
+///
+/// The server sends the value of each group tag when it changes, followed by the values of the
+/// listed tag in that group.
+fn parse_list_response<'a>(i: &'a [u8], tag: TagType, group: &[TagType])
+    -> IResult<&'a [u8], CommandResponse>
+{
+    let mut groups: Vec<TagValues> = Vec::new();
+    let mut current: Vec<(TagType, String)> = group.iter()
+        .map(|&group_tag| (group_tag, String::new()))
+        .collect();
+    let mut i_inner = i;
+
+    loop {
+        match parse_kv(i_inner) {
+            IResult::Done(i, (key, value)) => {
+                let value = match str::from_utf8(value) {
+                    Ok(value) => value.to_owned(),
+                    Err(_) => {
+                        return IResult::Error(error_position!(ErrorKind::Custom(0), i_inner));
+                    }
+                };
+                let tag_type = TagType::from_name(key);
+                if tag_type == Some(tag) {
+                    if groups.last().map(|last| last.group != current).unwrap_or(true) {
+                        groups.push(TagValues {
+                            tag: tag,
+                            group: current.clone(),
+                            values: Vec::new(),
+                        });
+                    }
+                    groups.last_mut().unwrap().values.push(value);
+                } else if let Some(idx) = group.iter().position(|&t| Some(t) == tag_type) {
+                    current[idx].1 = value;
+                }
+                i_inner = i;
+            }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+    }
+    IResult::Done(i_inner, CommandResponse::TagValues(groups))
+}
+
+/// Parses the `songs` and `playtime` lines from a `count` response, each group starting with a
+/// line for the group tag
+fn parse_count_response<'a>(i: &'a [u8], group: Option<TagType>)
+    -> IResult<&'a [u8], CommandResponse>
+{
+    let mut counts: Vec<SongCount> = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        match parse_kv(i_inner) {
+            IResult::Done(i, (key, value)) => {
+                let value = match str::from_utf8(value) {
+                    Ok(value) => value,
+                    Err(_) => {
+                        return IResult::Error(error_position!(ErrorKind::Custom(0), i_inner));
+                    }
+                };
+                let tag_type = TagType::from_name(key);
+                if group.is_some() && tag_type == group {
+                    counts.push(SongCount {
+                        group: Some((group.unwrap(), value.to_owned())),
+                        songs: 0,
+                        playtime: Duration::from_secs(0),
+                    });
+                } else if key == b"songs" || key == b"playtime" {
+                    if counts.is_empty() {
+                        counts.push(SongCount {
+                            group: None,
+                            songs: 0,
+                            playtime: Duration::from_secs(0),
+                        });
+                    }
+                    let count = counts.last_mut().unwrap();
+                    let parsed = if key == b"songs" {
+                        value.parse().ok().map(|songs| count.songs = songs)
+                    } else {
+                        parse_seconds(value).map(|playtime| count.playtime = playtime)
+                    };
+                    if parsed.is_none() {
+                        return IResult::Error(error_position!(ErrorKind::Custom(0), i_inner));
+                    }
+                }
+                i_inner = i;
+            }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+    }
+    IResult::Done(i_inner, CommandResponse::SongCounts(counts))
+}
+
+#[test]
+fn test_parse_count_response() {
+    assert_eq!(
+        parse_count_response(&b"songs: 12\nplaytime: 3021\nOK\n"[..], None),
+        IResult::Done(&b"OK\n"[..], CommandResponse::SongCounts(vec![SongCount {
+            group: None,
+            songs: 12,
+            playtime: Duration::from_secs(3021),
+        }]))
+    );
+    let input = b"Artist: Beach House\nsongs: 2\nplaytime: 540\n\
+                  Artist: Low\nsongs: 1\nplaytime: 181\nOK\n";
+    assert_eq!(
+        parse_count_response(&input[..], Some(TagType::Artist)),
+        IResult::Done(&b"OK\n"[..], CommandResponse::SongCounts(vec![
+            SongCount {
+                group: Some((TagType::Artist, "Beach House".into())),
+                songs: 2,
+                playtime: Duration::from_secs(540),
+            },
+            SongCount {
+                group: Some((TagType::Artist, "Low".into())),
+                songs: 1,
+                playtime: Duration::from_secs(181),
+            },
+        ]))
+    );
+    assert!(parse_count_response(&b"songs: x\nOK\n"[..], None).is_err());
+    assert!(parse_count_response(&b"songs: 1\nplayt"[..], None).is_incomplete());
+}
+
+/// Parses the `replay_gain_mode` line from a `replay_gain_status` response
+fn parse_replay_gain_status(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    map!(
+        i,
+        map_opt!(parse_status_line!(b"replay_gain_mode"), ReplayGainMode::from_name),
+        CommandResponse::ReplayGainMode
+    )
+}
+
+#[test]
+fn test_parse_replay_gain_status() {
+    assert_eq!(
+        parse_replay_gain_status(&b"replay_gain_mode: album\nOK\n"[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::ReplayGainMode(ReplayGainMode::Album))
+    );
+    assert!(parse_replay_gain_status(&b"replay_gain_mode: loud\nOK\n"[..]).is_err());
+}
+
+#[test]
+fn test_parse_list_response() {
+    let input = b"AlbumArtist: Pink Floyd
+Album: Animals
+Album: The Wall
+AlbumArtist: Sigur R\xc3\xb3s
+Album: ()
+OK
+";
+    assert_eq!(
+        parse_list_response(&input[..], TagType::Album, &[TagType::AlbumArtist]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::TagValues(vec![
+            TagValues {
+                tag: TagType::Album,
+                group: vec![(TagType::AlbumArtist, "Pink Floyd".into())],
+                values: vec!["Animals".into(), "The Wall".into()],
+            },
+            TagValues {
+                tag: TagType::Album,
+                group: vec![(TagType::AlbumArtist, "Sigur Rós".into())],
+                values: vec!["()".into()],
+            },
+        ]))
+    );
+    assert_eq!(
+        parse_list_response(&b"Genre: Jazz\nGenre: Rock\nOK\n"[..], TagType::Genre, &[]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::TagValues(vec![
+            TagValues {
+                tag: TagType::Genre,
+                group: vec![],
+                values: vec!["Jazz".into(), "Rock".into()],
+            },
+        ]))
+    );
+}
+
+/// Parses the `cpos` and `Id` lines of `plchangesposid`
+fn parse_position_ids(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut changes = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        match parse_status_line!(i_inner, b"cpos") {
+            IResult::Done(..) => (),
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+        let (i, change) = try_parse!(i_inner, do_parse!(
+            pos: map_res!(map_res!(parse_status_line!(b"cpos"), str::from_utf8), str::parse) >>
+            id: map_res!(map_res!(parse_status_line!(b"Id"), str::from_utf8), str::parse) >>
+            ((pos, id))
+        ));
+        i_inner = i;
+        changes.push(change);
+    }
+    IResult::Done(i_inner, CommandResponse::PositionIds(changes))
+}
+
+#[test]
+fn test_parse_position_ids() {
+    assert_eq!(
+        parse_position_ids(&b"cpos: 0\nId: 12\ncpos: 3\nId: 7\nOK\n"[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::PositionIds(vec![(0, 12), (3, 7)]))
+    );
+    assert!(parse_position_ids(&b"cpos: 0\nOK\n"[..]).is_err());
+}
+
+/// Parses the stored playlists from a `listplaylists` response
+fn parse_playlists(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut playlists = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        let name = match map_res!(i_inner, parse_status_line!(b"playlist"), str::from_utf8) {
+            IResult::Done(i, name) => { i_inner = i; name }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        };
+        let last_modified = match map_res!(
+            i_inner,
+            map_res!(parse_status_line!(b"Last-Modified"), str::from_utf8),
+            str::parse::<DateTime<UTC>>
+        ) {
+            IResult::Done(i, modified) => { i_inner = i; Some(modified) }
+            IResult::Error(_) => None,
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        };
+        playlists.push(Playlist {
+            name: name.to_owned(),
+            last_modified: last_modified,
+        });
+    }
+    IResult::Done(i_inner, CommandResponse::Playlists(playlists))
+}
+
+#[test]
+fn test_parse_playlists() {
+    let input = b"playlist: Favourites\nLast-Modified: 2017-03-01T10:00:00Z\nplaylist: New\nOK\n";
+    assert_eq!(
+        parse_playlists(&input[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::Playlists(vec![
+            Playlist {
+                name: "Favourites".into(),
+                last_modified: Some("2017-03-01T10:00:00Z".parse().unwrap()),
+            },
+            Playlist {
+                name: "New".into(),
+                last_modified: None,
+            },
+        ]))
+    );
+}
+
+/// Parses the command names from a `commands` or `notcommands` response
+fn parse_command_names(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut names = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        match map_res!(i_inner, parse_status_line!(b"command"), str::from_utf8) {
+            IResult::Done(i, name) => {
+                i_inner = i;
+                names.push(name.to_owned());
+            }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+    }
+    IResult::Done(i_inner, CommandResponse::CommandNames(names))
+}
+
+/// Parses the tag types from a `tagtypes` response, keeping the names of unknown ones
+fn parse_tag_types(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut known = Vec::new();
+    let mut unknown = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        match map_res!(i_inner, parse_status_line!(b"tagtype"), str::from_utf8) {
+            IResult::Done(i, name) => {
+                i_inner = i;
+                match TagType::from_name(name.as_bytes()) {
+                    Some(tag_type) => known.push(tag_type),
+                    None => unknown.push(name.to_owned()),
+                }
+            }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+    }
+    IResult::Done(i_inner, CommandResponse::TagTypes(known, unknown))
+}
+
+/// Parses the uri schemes from an `urlhandlers` response
+fn parse_url_handlers(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut handlers = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        match map_res!(i_inner, parse_status_line!(b"handler"), str::from_utf8) {
+            IResult::Done(i, handler) => {
+                i_inner = i;
+                handlers.push(handler.to_owned());
+            }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+    }
+    IResult::Done(i_inner, CommandResponse::UrlHandlers(handlers))
+}
+
+/// Parses the plugins from a `decoders` response, each followed by its `suffix` and
+/// `mime_type` lines
+fn parse_decoders(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut decoders = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        let plugin = match map_res!(i_inner, parse_status_line!(b"plugin"), str::from_utf8) {
+            IResult::Done(i, plugin) => { i_inner = i; plugin }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        };
+        let mut decoder = Decoder {
+            plugin: plugin.to_owned(),
+            suffixes: Vec::new(),
+            mime_types: Vec::new(),
+        };
+        loop {
+            let (i, (key, value)) = match parse_kv(i_inner) {
+                IResult::Done(i, (key, value)) if key != b"plugin" => (i, (key, value)),
+                IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+                _ => { break; }
+            };
+            let value = match str::from_utf8(value) {
+                Ok(value) => value.to_owned(),
+                Err(_) => { return IResult::Error(error_position!(ErrorKind::Custom(0), i_inner)); }
+            };
+            match key {
+                b"suffix" => decoder.suffixes.push(value),
+                b"mime_type" => decoder.mime_types.push(value),
+                _ => (),
+            }
+            i_inner = i;
+        }
+        decoders.push(decoder);
+    }
+    IResult::Done(i_inner, CommandResponse::Decoders(decoders))
+}
+
+/// Parses the music directory from a `config` response, skipping any other settings
+fn parse_config(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut music_directory = None;
+    let mut i_inner = i;
+
+    loop {
+        match parse_kv(i_inner) {
+            IResult::Done(i, (b"music_directory", value)) => {
+                match str::from_utf8(value) {
+                    Ok(value) => music_directory = Some(value.to_owned()),
+                    Err(_) => {
+                        return IResult::Error(error_position!(ErrorKind::Custom(0), i_inner));
+                    }
+                }
+                i_inner = i;
+            }
+            IResult::Done(i, _) => { i_inner = i; }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+    }
+    IResult::Done(i_inner, CommandResponse::MusicDirectory(music_directory))
+}
+
+#[test]
+fn test_parse_reflection() {
+    assert_eq!(
+        parse_tag_types(&b"tagtype: Artist\ntagtype: MUSICBRAINZ_WORKID\ntagtype: Title\nOK\n"[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::TagTypes(
+            vec![TagType::Artist, TagType::Title],
+            vec!["MUSICBRAINZ_WORKID".to_owned()]
+        ))
+    );
+    assert_eq!(
+        parse_url_handlers(&b"handler: http://\nhandler: nfs://\nOK\n"[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::UrlHandlers(vec![
+            "http://".to_owned(),
+            "nfs://".to_owned(),
+        ]))
+    );
+    let input = b"plugin: mad\nsuffix: mp3\nsuffix: mp2\nmime_type: audio/mpeg\n\
+                  plugin: pcm\nmime_type: audio/L16\nOK\n";
+    assert_eq!(
+        parse_decoders(&input[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::Decoders(vec![
+            Decoder {
+                plugin: "mad".into(),
+                suffixes: vec!["mp3".into(), "mp2".into()],
+                mime_types: vec!["audio/mpeg".into()],
+            },
+            Decoder {
+                plugin: "pcm".into(),
+                suffixes: vec![],
+                mime_types: vec!["audio/L16".into()],
+            },
+        ]))
+    );
+    assert!(parse_decoders(&b"plugin: mad\nsuffix: mp"[..]).is_incomplete());
+    assert_eq!(
+        parse_config(&b"music_directory: /srv/music\npcre: 1\nOK\n"[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::MusicDirectory(Some("/srv/music".into())))
+    );
+    assert_eq!(
+        parse_config(&b"OK\n"[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::MusicDirectory(None))
+    );
+}
+
+/// Parses the channel names from a `channels` response
+fn parse_channels(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut channels = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        match map_res!(i_inner, parse_status_line!(b"channel"), str::from_utf8) {
+            IResult::Done(i, channel) => {
+                i_inner = i;
+                channels.push(channel.to_owned());
+            }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+    }
+    IResult::Done(i_inner, CommandResponse::Channels(channels))
+}
+
+/// Parses the `channel` and `message` line pairs from `readmessages`
+fn parse_messages(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut messages = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        let channel = match map_res!(i_inner, parse_status_line!(b"channel"), str::from_utf8) {
+            IResult::Done(i, channel) => { i_inner = i; channel }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        };
+        let (i, text) = try_parse!(
+            i_inner,
+            map_res!(parse_status_line!(b"message"), str::from_utf8)
+        );
+        i_inner = i;
+        messages.push(Message {
+            channel: channel.to_owned(),
+            text: text.to_owned(),
+        });
+    }
+    IResult::Done(i_inner, CommandResponse::Messages(messages))
+}
+
+#[test]
+fn test_parse_messages() {
+    assert_eq!(
+        parse_channels(&b"channel: party\nchannel: lobby\nOK\n"[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::Channels(vec![
+            "party".to_owned(),
+            "lobby".to_owned(),
+        ]))
+    );
+    let input = b"channel: party\nmessage: skip: too slow\nchannel: party\nmessage: \nOK\n";
+    assert_eq!(
+        parse_messages(&input[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::Messages(vec![
+            Message { channel: "party".into(), text: "skip: too slow".into() },
+            Message { channel: "party".into(), text: "".into() },
+        ]))
+    );
+    assert!(parse_messages(&b"channel: party\nOK\n"[..]).is_err());
+    assert!(parse_messages(&b"channel: party\nmess"[..]).is_incomplete());
+}
+
+/// Parses a `sticker: name=value` line
+fn parse_sticker(i: &[u8]) -> IResult<&[u8], (String, String)> {
+    let (i_inner, sticker) = try_parse!(
+        i,
+        map_res!(parse_status_line!(b"sticker"), str::from_utf8)
+    );
+    match sticker.find('=') {
+        Some(idx) => IResult::Done(
+            i_inner,
+            (sticker[..idx].to_owned(), sticker[idx + 1..].to_owned())
+        ),
+        None => IResult::Error(error_position!(ErrorKind::Custom(0), i)),
+    }
+}
+
+fn parse_sticker_values(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut stickers = HashMap::new();
+    let mut i_inner = i;
+
+    loop {
+        match parse_status_line!(i_inner, b"sticker") {
+            IResult::Done(..) => (),
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+        let (i, (name, value)) = try_parse!(i_inner, parse_sticker);
+        i_inner = i;
+        stickers.insert(name, value);
+    }
+    IResult::Done(i_inner, CommandResponse::StickerValues(stickers))
+}
+
+/// Parses the `file` and `sticker` line pairs from `sticker find`
+fn parse_sticker_matches<'a>(i: &'a [u8], name: &str) -> IResult<&'a [u8], CommandResponse> {
+    let mut matches = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        let uri = match map_res!(i_inner, parse_status_line!(b"file"), str::from_utf8) {
+            IResult::Done(i, uri) => { i_inner = i; uri }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        };
+        let (i, (_, value)) = try_parse!(i_inner, parse_sticker);
+        i_inner = i;
+        matches.push((uri.to_owned(), value));
+    }
+    IResult::Done(i_inner, CommandResponse::StickerMatches(name.to_owned(), matches))
+}
+
+#[test]
+fn test_parse_stickers() {
+    let mut stickers = HashMap::new();
+    stickers.insert("rating".to_owned(), "8".to_owned());
+    stickers.insert("comment".to_owned(), "a=b".to_owned());
+    assert_eq!(
+        parse_sticker_values(&b"sticker: rating=8\nsticker: comment=a=b\nOK\n"[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::StickerValues(stickers))
+    );
+    assert!(parse_sticker_values(&b"sticker: rating\nOK\n"[..]).is_err());
+    assert_eq!(
+        parse_sticker_matches(
+            &b"file: a/1.mp3\nsticker: rating=8\nfile: a/2.mp3\nsticker: rating=3\nOK\n"[..],
+            "rating"
+        ),
+        IResult::Done(&b"OK\n"[..], CommandResponse::StickerMatches("rating".to_owned(), vec![
+            ("a/1.mp3".to_owned(), "8".to_owned()),
+            ("a/2.mp3".to_owned(), "3".to_owned()),
+        ]))
+    );
+    assert!(parse_sticker_matches(&b"file: a/1.mp3\nsticker: rat"[..], "rating").is_incomplete());
+}
+
+/// Sets a field of `output` from a line of an `outputs` response
+///
+/// Returns `None` if the value could not be parsed
+fn apply_output_field(output: &mut Output, key: &[u8], value: &[u8]) -> Option<()> {
+    let value = try_opt!(str::from_utf8(value).ok());
+    match key {
+        b"outputname" => { output.name = value.to_owned(); },
+        b"plugin" => { output.plugin = Some(value.to_owned()); },
+        b"outputenabled" => {
+            output.enabled = match parse_num_bool(value.as_bytes()) {
+                IResult::Done(b"", enabled) => enabled,
+                _ => return None,
+            };
+        },
+        b"attribute" => {
+            let idx = try_opt!(value.find('='));
+            output.attributes.insert(value[..idx].to_owned(), value[idx + 1..].to_owned());
+        },
+        // anything else we don't know about is skipped
+        _ => (),
+    }
+    Some(())
+}
+
+/// Parses one output, starting at its `outputid` line and ending before the next one starts
+fn parse_output(i: &[u8]) -> IResult<&[u8], Output> {
+    let (mut i_inner, id) = try_parse!(
+        i,
+        map_res!(map_res!(parse_status_line!(b"outputid"), str::from_utf8), str::parse::<u32>)
+    );
+    let mut output = Output::new(id, "");
+
+    loop {
+        match parse_kv(i_inner) {
+            IResult::Done(_, (b"outputid", _)) => { break; }
+            IResult::Done(i, (key, value)) => {
+                if apply_output_field(&mut output, key, value).is_none() {
+                    return IResult::Error(error_position!(ErrorKind::Custom(0), i_inner));
+                }
+                i_inner = i;
+            }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+    }
+    IResult::Done(i_inner, output)
+}
+
+/// Parses the audio outputs from an `outputs` response
+fn parse_outputs(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut outputs = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        match parse_status_line!(i_inner, b"outputid") {
+            IResult::Done(..) => (),
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+        let (i, output) = try_parse!(i_inner, parse_output);
+        i_inner = i;
+        outputs.push(output);
+    }
+    IResult::Done(i_inner, CommandResponse::Outputs(outputs))
+}
+
+#[test]
+fn test_parse_outputs() {
+    let input = b"outputid: 0\noutputname: Kitchen\nplugin: alsa\noutputenabled: 1\n\
+                  attribute: dop=0\nattribute: allowed_formats=\n\
+                  outputid: 1\noutputname: Stream\noutputenabled: 0\nOK\n";
+    let mut kitchen = Output::new(0, "Kitchen");
+    kitchen.plugin = Some("alsa".into());
+    kitchen.enabled = true;
+    kitchen.attributes.insert("dop".into(), "0".into());
+    kitchen.attributes.insert("allowed_formats".into(), "".into());
+    assert_eq!(
+        parse_outputs(&input[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::Outputs(vec![
+            kitchen,
+            Output::new(1, "Stream"),
+        ]))
+    );
+    assert!(parse_outputs(&b"outputid: 0\noutputenabled: yes\nOK\n"[..]).is_err());
+    assert!(parse_outputs(&b"outputid: 0\noutputname: Kit"[..]).is_incomplete());
+}
+
+/// Parses the mounted storages from a `listmounts` response
+fn parse_mounts(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut mounts = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        let path = match map_res!(i_inner, parse_status_line!(b"mount"), str::from_utf8) {
+            IResult::Done(i, path) => { i_inner = i; path }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        };
+        let storage = match map_res!(i_inner, parse_status_line!(b"storage"), str::from_utf8) {
+            IResult::Done(i, storage) => { i_inner = i; Some(storage) }
+            IResult::Error(_) => None,
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        };
+        mounts.push(Mount {
+            path: path.to_owned(),
+            storage: storage.map(|s| s.to_owned()),
+        });
+    }
+    IResult::Done(i_inner, CommandResponse::Mounts(mounts))
+}
+
+/// Parses the storages from a `listneighbors` response
+fn parse_neighbors(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut neighbors = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        let uri = match map_res!(i_inner, parse_status_line!(b"neighbor"), str::from_utf8) {
+            IResult::Done(i, uri) => { i_inner = i; uri }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        };
+        let (i, name) = try_parse!(i_inner, map_res!(parse_status_line!(b"name"), str::from_utf8));
+        i_inner = i;
+        neighbors.push(Neighbor {
+            uri: uri.to_owned(),
+            name: name.to_owned(),
+        });
+    }
+    IResult::Done(i_inner, CommandResponse::Neighbors(neighbors))
+}
+
+#[test]
+fn test_parse_mounts() {
+    let input = b"mount: \nstorage: /home/music\nmount: nas\nstorage: nfs://nas/export/mp3\n\
+                  mount: usb\nOK\n";
+    assert_eq!(
+        parse_mounts(&input[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::Mounts(vec![
+            Mount { path: "".into(), storage: Some("/home/music".into()) },
+            Mount { path: "nas".into(), storage: Some("nfs://nas/export/mp3".into()) },
+            Mount { path: "usb".into(), storage: None },
+        ]))
+    );
+    assert!(parse_mounts(&b"mount: nas\nstor"[..]).is_incomplete());
+}
+
+#[test]
+fn test_parse_neighbors() {
+    let input = b"neighbor: smb://FOO\nname: FOO (Samba 4.1.11-Debian)\nOK\n";
+    assert_eq!(
+        parse_neighbors(&input[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::Neighbors(vec![
+            Neighbor { uri: "smb://FOO".into(), name: "FOO (Samba 4.1.11-Debian)".into() },
+        ]))
+    );
+    assert!(parse_neighbors(&b"neighbor: smb://FOO\nOK\n"[..]).is_err());
+}
+
+#[test]
+fn test_parse_command_names() {
+    assert_eq!(
+        parse_command_names(&b"command: close\ncommand: password\nOK\n"[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::CommandNames(vec![
+            "close".to_owned(),
+            "password".to_owned(),
+        ]))
+    );
+}
+
+/// Parses the subsystems that changed from an idle response.
+///
+/// Subsystems this library doesn't know about are skipped.
+fn parse_idle_response(i: &[u8]) -> IResult<&[u8], CommandResponse> {
+    let mut changed = Vec::new();
+    let mut i_inner = i;
+
+    loop {
+        match parse_status_line!(i_inner, b"changed") {
+            IResult::Done(i, name) => {
+                i_inner = i;
+                if let Some(sub) = SubSystem::from_name(name) {
+                    changed.push(sub);
+                }
+            }
+            IResult::Error(_) => { break; }
+            IResult::Incomplete(n) => { return IResult::Incomplete(n); }
+        }
+    }
+    IResult::Done(i_inner, CommandResponse::Changed(changed))
+}
+
+#[test]
+fn test_parse_idle_response() {
+    let input = b"changed: player\nchanged: partition\nchanged: mixer\nOK\n";
+    assert_eq!(
+        parse_idle_response(&input[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::Changed(vec![
+            SubSystem::Player,
+            SubSystem::Mixer
+        ]))
+    );
+    assert_eq!(
+        parse_idle_response(&b"OK\n"[..]),
+        IResult::Done(&b"OK\n"[..], CommandResponse::Changed(vec![]))
+    );
+}
+
 named!(parse_status_state<State>,
     alt!(
         map!(tag!("play"), |_| State::Play) |
@@ -793,6 +2245,48 @@ pub enum CommandResponse {
     Tmp,
     Status(Status),
     Stats(Stats),
+    /// The subsystems that changed while idling
+    Changed(Vec<SubSystem>),
+    /// A song, or `None` if there is no song (e.g. for `CurrentSong` when stopped)
+    Song(Option<Song>),
+    /// A list of songs
+    Songs(Vec<Song>),
+    /// Tag values from `List`, grouped if groups were requested
+    TagValues(Vec<TagValues>),
+    /// Song counts from `Count` or `GroupCount`, one per group if a group was requested
+    SongCounts(Vec<SongCount>),
+    /// The replay gain mode from `ReplayGainStatus`
+    ReplayGainMode(ReplayGainMode),
+    /// The id of a song added with `AddId`
+    Id(u32),
+    /// The (position, id) pairs of changed songs from `PlaylistChangesPositionId`
+    PositionIds(Vec<(u32, u32)>),
+    /// The stored playlists from `ListPlaylists`
+    Playlists(Vec<Playlist>),
+    /// The command names from `Commands` or `NotCommands`
+    CommandNames(Vec<String>),
+    /// The tag types from `TagTypes`, and the names of any that this library doesn't know
+    TagTypes(Vec<TagType>, Vec<String>),
+    /// The uri schemes from `UrlHandlers`
+    UrlHandlers(Vec<String>),
+    /// The decoder plugins from `Decoders`
+    Decoders(Vec<Decoder>),
+    /// The music directory from `Config`, if the server has one on the local file system
+    MusicDirectory(Option<String>),
+    /// Sticker names and values, from `StickerGet` or `StickerList`
+    StickerValues(HashMap<String, String>),
+    /// The sticker name, and the (uri, value) pairs from `StickerFind`
+    StickerMatches(String, Vec<(String, String)>),
+    /// The audio outputs from `Outputs`
+    Outputs(Vec<Output>),
+    /// The channels from `Channels`
+    Channels(Vec<String>),
+    /// The messages from `ReadMessages`
+    Messages(Vec<Message>),
+    /// The mounted storages from `ListMounts`
+    Mounts(Vec<Mount>),
+    /// The storages that could be mounted, from `ListNeighbors`
+    Neighbors(Vec<Neighbor>),
 }
 
 
@@ -800,7 +2294,269 @@ pub enum CommandResponse {
 mod tests {
     use super::*;
     use std::str;
-    use protocol::Dispatch;
+    use protocol::{Dispatch, Quoted};
+    use protocol::server::split_args;
+    use types::{CmdErrorType, Filter, FilterTag, Selector};
+
+    #[test]
+    fn command_list_response() {
+        let mut cmd_list = CommandList::new();
+        cmd_list.push(Command::Stop);
+        cmd_list.push(Command::Random(true));
+        assert_eq!(
+            cmd_list.parse_response(&b"list_OK\nlist_OK\nOK\n"[..]),
+            IResult::Done(&b""[..], CommandListResponse {
+                responses: vec![CommandResponse::Blank, CommandResponse::Blank],
+                error: None
+            })
+        );
+    }
+
+    #[test]
+    fn command_list_response_error() {
+        let mut cmd_list = CommandList::new();
+        cmd_list.push(Command::Stop);
+        cmd_list.push(Command::Play(10240));
+        cmd_list.push(Command::Random(true));
+        let input = b"list_OK\nACK [50@1] {play} song doesn't exist: \"10240\"\n";
+        assert_eq!(
+            cmd_list.parse_response(&input[..]),
+            IResult::Done(&b""[..], CommandListResponse {
+                responses: vec![CommandResponse::Blank],
+                error: Some(CmdError {
+                    error_type: CmdErrorType::NoExist,
+                    command_no: 1,
+                    command_name: "play".to_owned(),
+                    message_text: "song doesn't exist: \"10240\"".to_owned()
+                })
+            })
+        );
+    }
+
+    #[test]
+    fn idle_dispatch() {
+        let mut s_raw: Vec<u8> = Vec::new();
+        Command::Idle(vec![SubSystem::Player, SubSystem::StoredPlaylist]).dispatch(&mut s_raw)
+            .unwrap();
+        assert_eq!(str::from_utf8(&s_raw[..]).unwrap(), "idle player stored_playlist\n");
+        let mut s_raw: Vec<u8> = Vec::new();
+        Command::Idle(vec![]).dispatch(&mut s_raw).unwrap();
+        assert_eq!(str::from_utf8(&s_raw[..]).unwrap(), "idle\n");
+    }
+
+    /// Filenames that have broken commands in the past
+    const TRICKY_NAMES: &'static [&'static str] = &[
+        "Artist/Album/01 Track.flac",
+        "AC\\DC/Back in Black.mp3",
+        "Guns N' Roses/Appetite for Destruction/07 Paradise City.mp3",
+        "Weird Al/\"Weird Al\" Yankovic - \"Eat It\".ogg",
+        "Trailing backslash\\",
+        "\\\"\\\"",
+        "Tab\tseparated.mp3",
+        "Sigur Rós/( )/Untitled #1.flac",
+        "command_list_end",
+        "http://example.com/stream?a=1&b=\"2\"",
+    ];
+
+    fn dispatch_str(cmd: Command) -> io::Result<String> {
+        let mut s_raw: Vec<u8> = Vec::new();
+        cmd.dispatch(&mut s_raw)?;
+        Ok(String::from_utf8(s_raw).unwrap())
+    }
+
+    #[test]
+    fn quoted_args_round_trip() {
+        for &name in TRICKY_NAMES {
+            let line = dispatch_str(Command::Add(name.into())).unwrap();
+            assert_eq!(line.matches('\n').count(), 1);
+            assert_eq!(split_args(&line).unwrap(), vec!["add".to_owned(), name.to_owned()]);
+
+            let line = dispatch_str(Command::PlaylistAdd {
+                playlist: name.into(),
+                song: name.into(),
+            }).unwrap();
+            assert_eq!(
+                split_args(&line).unwrap(),
+                vec!["playlistadd".to_owned(), name.to_owned(), name.to_owned()]
+            );
+
+            let line = dispatch_str(Command::PlaylistSearch(
+                vec![(TagType::Title.into(), name.into())].into()
+            )).unwrap();
+            assert_eq!(
+                split_args(&line).unwrap(),
+                vec!["playlistsearch".to_owned(), "title".to_owned(), name.to_owned()]
+            );
+        }
+    }
+
+    #[test]
+    fn quoted_args() {
+        assert_eq!(
+            dispatch_str(Command::Rename {
+                old_name: "My \"Best\" Of".into(),
+                new_name: "C:\\Music".into(),
+            }).unwrap(),
+            "rename \"My \\\"Best\\\" Of\" \"C:\\\\Music\"\n"
+        );
+        assert_eq!(
+            dispatch_str(Command::Load {
+                name: "Road Trip".into(),
+                range: Some(Range { start: 1, end: Some(3) }),
+            }).unwrap(),
+            "load \"Road Trip\" 1:3\n"
+        );
+    }
+
+    #[test]
+    fn database_dispatch() {
+        assert_eq!(
+            dispatch_str(Command::Find {
+                query: vec![
+                    (Selector::Tag(TagType::Artist), "Pink Floyd".into()),
+                    (Selector::Base, "Pink Floyd/Animals".into()),
+                ].into(),
+                window: Some(Range { start: 0, end: Some(10) }),
+            }).unwrap(),
+            "find artist \"Pink Floyd\" base \"Pink Floyd/Animals\" window 0:10\n"
+        );
+        assert_eq!(
+            dispatch_str(Command::Search {
+                query: vec![
+                    (Selector::Any, "floyd".into()),
+                    (Selector::ModifiedSince, "2017-01-01T00:00:00Z".into()),
+                ].into(),
+                window: None,
+            }).unwrap(),
+            "search any \"floyd\" modified-since \"2017-01-01T00:00:00Z\"\n"
+        );
+        assert_eq!(
+            dispatch_str(Command::FindAdd(vec![(Selector::File, "a.mp3".into())].into())).unwrap(),
+            "findadd file \"a.mp3\"\n"
+        );
+        assert_eq!(
+            dispatch_str(Command::SearchAdd(vec![(TagType::Title.into(), "pigs".into())].into()))
+                .unwrap(),
+            "searchadd title \"pigs\"\n"
+        );
+        assert_eq!(
+            dispatch_str(Command::SearchAddPlaylist {
+                playlist: "Pigs".into(),
+                query: vec![(TagType::Title.into(), "pigs".into())].into(),
+            }).unwrap(),
+            "searchaddpl \"Pigs\" title \"pigs\"\n"
+        );
+        assert_eq!(
+            dispatch_str(Command::PlaylistFind(vec![
+                (Selector::Tag(TagType::Album), "Animals".into()),
+                (Selector::File, "Pink Floyd/Animals/02 Dogs.flac".into()),
+            ].into())).unwrap(),
+            "playlistfind album \"Animals\" file \"Pink Floyd/Animals/02 Dogs.flac\"\n"
+        );
+        assert_eq!(
+            dispatch_str(Command::List {
+                tag: TagType::Album,
+                query: vec![(TagType::Genre.into(), "Rock".into())].into(),
+                group: vec![TagType::AlbumArtist, TagType::Date],
+            }).unwrap(),
+            "list album genre \"Rock\" group albumartist group date\n"
+        );
+    }
+
+    #[test]
+    fn filter_dispatch() {
+        let filter = Filter::equal(TagType::Artist, "Pink Floyd")
+            & !Filter::contains(TagType::Title, "live");
+        assert_eq!(
+            filter.to_string(),
+            r#"((artist == "Pink Floyd") AND (!(title contains "live")))"#
+        );
+        assert_eq!(
+            dispatch_str(Command::Find { query: filter.into(), window: None }).unwrap(),
+            r#"find "((artist == \"Pink Floyd\") AND (!(title contains \"live\")))""#.to_owned()
+                + "\n"
+        );
+        // values are escaped inside the expression, then the whole expression is escaped again
+        let filter = Filter::equal(FilterTag::File, r#"a "b" \c"#);
+        assert_eq!(filter.to_string(), r#"(file == "a \"b\" \\c")"#);
+        assert_eq!(
+            dispatch_str(Command::Count { query: filter.into(), group: Some(TagType::Artist) })
+                .unwrap(),
+            r#"count "(file == \"a \\\"b\\\" \\\\c\")" group artist"#.to_owned() + "\n"
+        );
+        assert_eq!(
+            dispatch_str(Command::PlaylistSearch(Filter::contains(TagType::Title, "dogs").into()))
+                .unwrap(),
+            r#"playlistsearch "(title contains \"dogs\")""#.to_owned() + "\n"
+        );
+        for &name in TRICKY_NAMES {
+            let line = dispatch_str(Command::Search {
+                query: Filter::equal(FilterTag::Any, name).into(),
+                window: None,
+            }).unwrap();
+            assert_eq!(
+                split_args(&line).unwrap(),
+                vec!["search".to_owned(), format!("(any == {})", Quoted(name))]
+            );
+        }
+    }
+
+    #[test]
+    fn filter_expressions() {
+        let filter = Filter::matches(TagType::Date, "^199")
+            .and(Filter::AudioFormatMatches("*:24:*".into()))
+            .and(Filter::PriorityAtLeast(5) & Filter::Base("Rock".into()));
+        assert_eq!(
+            filter.to_string(),
+            r#"((date =~ "^199") AND (AudioFormat =~ "*:24:*") AND (prio >= 5) AND (base "Rock"))"#
+        );
+        assert_eq!(
+            Filter::And(Box::new(Filter::not_equal(TagType::Genre, "Pop")), vec![]).to_string(),
+            r#"(genre != "Pop")"#
+        );
+        assert_eq!(
+            (!Filter::not_matches(FilterTag::File, r"\.flac$")).to_string(),
+            r#"(!(file !~ "\\.flac$"))"#
+        );
+        let query = Filter::contains(FilterTag::Any, "a\nb").into();
+        let err = dispatch_str(Command::SearchAdd(query)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn seek_dispatch() {
+        assert_eq!(
+            dispatch_str(Command::Seek { song_position: 3, time: Duration::from_millis(12_500) })
+                .unwrap(),
+            "seek 3 12.500\n"
+        );
+        assert_eq!(
+            dispatch_str(Command::SeekId { song_id: "7".into(), time: Duration::from_secs(60) })
+                .unwrap(),
+            "seekid \"7\" 60.000\n"
+        );
+        assert_eq!(
+            dispatch_str(Command::SeekCurrent(Duration::from_millis(5))).unwrap(),
+            "seekcur 0.005\n"
+        );
+    }
+
+    #[test]
+    fn add_id_response() {
+        let cmd = Command::AddId { uri: "a.mp3".into(), position: None };
+        assert_eq!(
+            cmd.parse_response(&b"Id: 42\nOK\n"[..]),
+            IResult::Done(&b"OK\n"[..], CommandResponse::Id(42))
+        );
+    }
+
+    #[test]
+    fn newline_in_arg() {
+        let err = dispatch_str(Command::Add("evil\nclear".into())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = dispatch_str(Command::Save("line\n".into())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
 
     #[test]
     fn command_list_dispatch() {
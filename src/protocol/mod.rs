@@ -1,7 +1,10 @@
 pub mod command;
+pub mod server;
 
 use std::str;
 use std::io;
+use std::fmt;
+use std::time::Duration;
 
 use nom::*;
 use semver::Version;
@@ -21,6 +24,46 @@ pub trait ParseResponse {
     fn parse_response<'a>(&self, i: &'a [u8]) -> IResult<&'a [u8], Self::ResponseType>;
 }
 
+/// A string argument to a command, displayed in double quotes with `"` and `\` escaped.
+///
+/// This doesn't check for newlines, use `quote` to create one for a command argument.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quoted<'a>(pub &'a str);
+
+impl<'a> fmt::Display for Quoted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\"")?;
+        let mut rest = self.0;
+        while let Some(idx) = rest.find(|c| c == '"' || c == '\\') {
+            f.write_str(&rest[..idx])?;
+            f.write_str("\\")?;
+            f.write_str(&rest[idx..idx + 1])?;
+            rest = &rest[idx + 1..];
+        }
+        f.write_str(rest)?;
+        f.write_str("\"")
+    }
+}
+
+/// Quote and escape a string argument so it can be sent to the server.
+///
+/// A newline would end the command early, so arguments containing one are rejected with an
+/// `InvalidInput` error.
+pub fn quote(arg: &str) -> io::Result<Quoted<'_>> {
+    if arg.contains('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "command arguments cannot contain a newline"
+        ));
+    }
+    Ok(Quoted(arg))
+}
+
+/// Formats a duration as fractional seconds, to millisecond precision
+pub fn format_seconds(time: Duration) -> String {
+    format!("{}.{:03}", time.as_secs(), time.subsec_nanos() / 1_000_000)
+}
+
 /// Parses a line from the server into a version
 named!(pub parse_handshake<Version>,
     do_parse!(
@@ -76,6 +119,24 @@ named!(pub parse_error<CmdError>,
     )
 );
 
+/// Parses a server error if there is one, otherwise runs the given parser.
+///
+/// The output is `Err` if the server sent an `ACK`, and `Ok` with the parsed value otherwise.
+pub fn parse_result<'a, F, O>(i: &'a [u8], mut parser: F)
+    -> IResult<&'a [u8], Result<O, CmdError>>
+    where F: FnMut(&'a [u8]) -> IResult<&'a [u8], O>
+{
+    match parse_error(i) {
+        IResult::Done(i, e) => IResult::Done(i, Err(e)),
+        IResult::Incomplete(n) => IResult::Incomplete(n),
+        IResult::Error(_) => match parser(i) {
+            IResult::Done(i, o) => IResult::Done(i, Ok(o)),
+            IResult::Error(e) => IResult::Error(e),
+            IResult::Incomplete(n) => IResult::Incomplete(n),
+        }
+    }
+}
+
 /// Parses the tag "OK\n". This tag is at the end of every successful response
 named!(pub parse_ok, tag!(b"OK\n"));
 
@@ -83,6 +144,17 @@ named!(pub parse_ok, tag!(b"OK\n"));
 /// the list is started with "command_list_ok_begin".
 named!(pub parse_list_ok, tag!(b"list_OK\n"));
 
+/// Parses a `key: value` line, the format of most responses
+named!(pub parse_kv<(&[u8], &[u8])>,
+    do_parse!(
+        key: is_not!(":\n") >>
+        tag!(b": ") >>
+        value: not_line_ending >>
+        tag!(b"\n") >>
+        ((key, value))
+    )
+);
+
 /// Parses a number "0" or "1" and converts it to a bool. This is how booleans are transmitted.
 named!(pub parse_num_bool<bool>, alt!(
     map!(tag!(b"0"), |_| false) |
@@ -96,7 +168,7 @@ enum Sign {
 
 named!(parse_sign<Sign>,
     do_parse!(
-        sign: one_of!(b"+-") >>
+        sign: one_of!("+-") >>
         (match sign {
             '+' => Sign::Pos,
             '-' => Sign::Neg,
@@ -156,6 +228,49 @@ mod tests {
         );
     }
 
+    #[test]
+    fn result() {
+        let i = b"ACK [5@0] {foo} unknown command \"foo\"\n";
+        assert_eq!(
+            parse_result(&i[..], parse_ok),
+            IResult::Done(&b""[..], Err(CmdError {
+                error_type: CmdErrorType::Unknown,
+                command_no: 0,
+                command_name: "foo".to_owned(),
+                message_text: "unknown command \"foo\"".to_owned()
+            }))
+        );
+        let i = b"OK\n";
+        assert_eq!(
+            parse_result(&i[..], parse_ok),
+            IResult::Done(&b""[..], Ok(&b"OK\n"[..]))
+        );
+    }
+
+    #[test]
+    fn quoted() {
+        assert_eq!(quote("simple").unwrap().to_string(), "\"simple\"");
+        assert_eq!(
+            quote(r#"say "hi" \o/"#).unwrap().to_string(),
+            r#""say \"hi\" \\o/""#
+        );
+        assert_eq!(quote("").unwrap().to_string(), "\"\"");
+        assert_eq!(
+            quote("two\nlines").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn kv() {
+        assert_eq!(
+            parse_kv(&b"Title: Ratio: 1:2\nOK\n"[..]),
+            IResult::Done(&b"OK\n"[..], (&b"Title"[..], &b"Ratio: 1:2"[..]))
+        );
+        assert!(parse_kv(&b"OK\n"[..]).is_err());
+        assert!(parse_kv(&b"Title: Incompl"[..]).is_incomplete());
+    }
+
     #[test]
     fn num_bool() {
         let i = b"0";
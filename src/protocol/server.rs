@@ -0,0 +1,1406 @@
+//! The server side of the protocol: parsing requests from clients into `Command`s, and writing
+//! responses out in the format the server sends them.
+//!
+//! This is the reverse of `Dispatch` and `ParseResponse`, so that servers, proxies and test
+//! doubles can be written using the same types as the clients.
+
+use std::io;
+use std::str;
+use std::str::FromStr;
+use std::time::Duration;
+use std::vec;
+
+use nom::{IResult, Needed};
+use semver::Version;
+
+use super::format_seconds;
+use super::command::{Command, CommandList, CommandListResponse, CommandResponse, parse_seconds};
+use types::{CmdError, CmdErrorType, Filter, FilterTag, Query, Range, ReplayGainMode, Selector,
+    SingleOrRange, Song, State, Stats, Status, SubSystem, TagType, TagValues};
+
+/// A request from a client
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientRequest {
+    /// A single command
+    Command(Command),
+    /// A command list, between `command_list_begin` or `command_list_ok_begin` and
+    /// `command_list_end`
+    CommandList {
+        commands: CommandList,
+        /// Whether the client asked for `list_OK` after each response
+        /// (`command_list_ok_begin`)
+        list_ok: bool,
+    },
+}
+
+fn error(error_type: CmdErrorType, command_name: &str, message: String) -> CmdError {
+    CmdError {
+        error_type: error_type,
+        command_no: 0,
+        command_name: command_name.to_owned(),
+        message_text: message,
+    }
+}
+
+/// Splits a request line into its arguments, undoing the quoting.
+///
+/// Arguments are separated by whitespace, and can be put in double quotes, in which `\`
+/// escapes the next character.
+pub fn split_args(line: &str) -> Result<Vec<String>, CmdError> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+        match chars.next() {
+            None => break,
+            Some('"') => {
+                let mut arg = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => arg.push(c),
+                            None => return Err(error(
+                                CmdErrorType::Arg, "", "Unterminated quotation".to_owned()
+                            )),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(error(
+                            CmdErrorType::Arg, "", "Unterminated quotation".to_owned()
+                        )),
+                    }
+                }
+                args.push(arg);
+            },
+            Some(c) => {
+                let mut arg = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    arg.push(c);
+                    chars.next();
+                }
+                args.push(arg);
+            },
+        }
+    }
+    Ok(args)
+}
+
+/// The arguments of a command, with helpers to parse them
+struct Args {
+    name: String,
+    args: vec::IntoIter<String>,
+}
+
+impl Args {
+    fn error(&self, error_type: CmdErrorType, message: String) -> CmdError {
+        error(error_type, &self.name, message)
+    }
+
+    fn wrong_number(&self) -> CmdError {
+        let message = format!("wrong number of arguments for \"{}\"", self.name);
+        self.error(CmdErrorType::Arg, message)
+    }
+
+    fn next(&mut self) -> Result<String, CmdError> {
+        match self.args.next() {
+            Some(arg) => Ok(arg),
+            None => Err(self.wrong_number()),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.args.as_slice().first().map(|arg| &arg[..])
+    }
+
+    /// Fails if there are arguments left over
+    fn end(&self) -> Result<(), CmdError> {
+        match self.peek() {
+            Some(_) => Err(self.wrong_number()),
+            None => Ok(()),
+        }
+    }
+
+    fn parse_arg<T: FromStr>(&self, arg: &str) -> Result<T, CmdError> {
+        arg.parse().map_err(|_| {
+            self.error(CmdErrorType::Arg, format!("Integer expected: {}", arg))
+        })
+    }
+
+    fn number<T: FromStr>(&mut self) -> Result<T, CmdError> {
+        let arg = self.next()?;
+        self.parse_arg(&arg)
+    }
+
+    fn bool(&mut self) -> Result<bool, CmdError> {
+        match &self.next()?[..] {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            arg => Err(self.error(CmdErrorType::Arg, format!("Boolean (0/1) expected: {}", arg))),
+        }
+    }
+
+    fn seconds(&mut self) -> Result<Duration, CmdError> {
+        let arg = self.next()?;
+        match parse_seconds(&arg) {
+            Some(time) => Ok(time),
+            None => Err(self.error(CmdErrorType::Arg, format!("Number expected: {}", arg))),
+        }
+    }
+
+    fn tag(&mut self) -> Result<TagType, CmdError> {
+        let arg = self.next()?;
+        match TagType::from_name(arg.as_bytes()) {
+            Some(tag) => Ok(tag),
+            None => Err(self.error(CmdErrorType::Arg, format!("Unknown tag type: {}", arg))),
+        }
+    }
+
+    fn range(&mut self) -> Result<Range, CmdError> {
+        let arg = self.next()?;
+        self.parse_range(&arg)
+    }
+
+    fn parse_range(&self, arg: &str) -> Result<Range, CmdError> {
+        let mut parts = arg.splitn(2, ':');
+        let start = self.parse_arg(parts.next().unwrap_or(""))?;
+        let end = match parts.next() {
+            Some("") => None,
+            Some(end) => Some(self.parse_arg(end)?),
+            None => {
+                return Err(self.error(CmdErrorType::Arg, format!("Range expected: {}", arg)));
+            },
+        };
+        Ok(Range { start: start, end: end })
+    }
+
+    fn single_or_range(&mut self) -> Result<SingleOrRange, CmdError> {
+        let arg = self.next()?;
+        if arg.contains(':') {
+            self.parse_range(&arg).map(SingleOrRange::Range)
+        } else {
+            self.parse_arg(&arg).map(SingleOrRange::Single)
+        }
+    }
+
+    fn optional<T, F>(&mut self, mut parse: F) -> Result<Option<T>, CmdError>
+        where F: FnMut(&mut Args) -> Result<T, CmdError>
+    {
+        match self.peek() {
+            Some(_) => parse(self).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn rest(&mut self) -> Vec<String> {
+        self.args.by_ref().collect()
+    }
+
+    /// Parses a database query: either a filter expression or (selector, needle) pairs,
+    /// stopping at any of the given keywords
+    fn query(&mut self, keywords: &[&str]) -> Result<Query, CmdError> {
+        if self.peek().map(|arg| arg.starts_with('(')).unwrap_or(false) {
+            let expr = self.next()?;
+            return match parse_filter(&expr) {
+                Some(filter) => Ok(Query::Filter(filter)),
+                None => Err(self.error(
+                    CmdErrorType::Arg,
+                    format!("Invalid filter expression: {}", expr)
+                )),
+            };
+        }
+        let mut tags = Vec::new();
+        loop {
+            match self.peek() {
+                Some(arg) if !keywords.contains(&arg) => (),
+                _ => break,
+            }
+            let name = self.next()?;
+            let selector = match Selector::from_name(name.as_bytes()) {
+                Some(selector) => selector,
+                None => {
+                    let message = format!("Unknown tag type: {}", name);
+                    return Err(self.error(CmdErrorType::Arg, message));
+                },
+            };
+            let needle = self.next()?;
+            tags.push((selector, needle));
+        }
+        Ok(Query::Tags(tags))
+    }
+
+    fn window(&mut self) -> Result<Option<Range>, CmdError> {
+        match self.peek() {
+            Some("window") => {
+                self.next()?;
+                self.range().map(Some)
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Parses a request line (without the newline) into a command
+///
+/// Commands that `Command` can't represent are rejected with an `Unknown` error, as the server
+/// would for a command it doesn't know.
+pub fn parse_command(line: &str) -> Result<Command, CmdError> {
+    use self::Command::*;
+
+    let mut args = split_args(line)?.into_iter();
+    let name = match args.next() {
+        Some(name) => name,
+        None => return Err(error(CmdErrorType::Unknown, "", "No command given".to_owned())),
+    };
+    let mut args = Args { name: name, args: args };
+    let command = match &args.name.clone()[..] {
+        "clearerror" => ClearError,
+        "currentsong" => CurrentSong,
+        "idle" => {
+            let mut subsystems = Vec::new();
+            for name in args.rest() {
+                match SubSystem::from_name(name.as_bytes()) {
+                    Some(sub) => subsystems.push(sub),
+                    None => return Err(args.error(
+                        CmdErrorType::Arg,
+                        format!("Unrecognized idle event: {}", name)
+                    )),
+                }
+            }
+            Idle(subsystems)
+        },
+        "noidle" => NoIdle,
+        "status" => Status,
+        "stats" => Stats,
+        "consume" => Consume(args.bool()?),
+        "crossfade" => Crossfade(args.number()?),
+        "mixrampdb" => MixRampDB(args.number()?),
+        "mixrampdelay" => match &args.next()?[..] {
+            "nan" => MixRampDelay(None),
+            delay => MixRampDelay(Some(args.parse_arg(delay)?)),
+        },
+        "random" => Random(args.bool()?),
+        "repeat" => Repeat(args.bool()?),
+        "setvol" => Volume(args.number()?),
+        "single" => Single(args.bool()?),
+        "replay_gain_mode" => {
+            let mode = args.next()?;
+            match self::ReplayGainMode::from_name(mode.as_bytes()) {
+                Some(mode) => ReplayGainMode(mode),
+                None => return Err(args.error(
+                    CmdErrorType::Arg,
+                    format!("Unrecognized replay gain mode: {}", mode)
+                )),
+            }
+        },
+        "replay_gain_status" => ReplayGainStatus,
+        "next" => Next,
+        "pause" => Pause(args.bool()?),
+        "play" => Play(args.number()?),
+        "playid" => PlayId(args.next()?),
+        "previous" => Previous,
+        "seek" => Seek {
+            song_position: args.number()?,
+            time: args.seconds()?,
+        },
+        "seekid" => SeekId {
+            song_id: args.next()?,
+            time: args.seconds()?,
+        },
+        "seekcur" => SeekCurrent(args.seconds()?),
+        "stop" => Stop,
+        "add" => Add(args.next()?),
+        "addid" => AddId {
+            uri: args.next()?,
+            position: args.optional(|args| args.number())?,
+        },
+        "clear" => Clear,
+        "delete" => Delete(args.single_or_range()?),
+        "deleteid" => DeleteId(args.next()?),
+        "move" => Move {
+            from: args.single_or_range()?,
+            to: args.number()?,
+        },
+        "moveid" => MoveId {
+            from: args.next()?,
+            to: args.number()?,
+        },
+        "playlistfind" => PlaylistFind(args.query(&[])?),
+        "playlistid" => PlaylistId(args.optional(|args| args.number())?),
+        "playlistinfo" => PlaylistInfo(args.optional(|args| args.single_or_range())?),
+        "playlistsearch" => PlaylistSearch(args.query(&[])?),
+        "plchanges" => PlaylistChanges {
+            version: args.next()?,
+            range: args.optional(|args| args.range())?,
+        },
+        "plchangesposid" => PlaylistChangesPositionId {
+            version: args.next()?,
+            range: args.optional(|args| args.range())?,
+        },
+        "prio" => {
+            let priority = args.number()?;
+            let mut songs = vec![args.single_or_range()?];
+            while args.peek().is_some() {
+                songs.push(args.single_or_range()?);
+            }
+            Priority { priority: priority, songs: songs }
+        },
+        "prioid" => {
+            let priority = args.number()?;
+            let mut songs = vec![args.next()?];
+            songs.extend(args.rest());
+            PriorityId { priority: priority, songs: songs }
+        },
+        "rangeid" => RangeId {
+            id: args.next()?,
+            range: args.range()?,
+        },
+        "shuffle" => {
+            let range = args.optional(|args| args.range())?;
+            Shuffle(range.unwrap_or(Range { start: 0, end: None }))
+        },
+        "swap" => Swap(args.number()?, args.number()?),
+        "swapid" => SwapId(args.next()?, args.next()?),
+        "addtagid" => AddTagId {
+            id: args.next()?,
+            tag: (args.tag()?, args.next()?),
+        },
+        "cleartagid" => ClearTagId {
+            id: args.next()?,
+            tag: args.tag()?,
+        },
+        "listplaylist" => ListPlaylist(args.next()?),
+        "listplaylistinfo" => ListPlaylistInfo(args.next()?),
+        "listplaylists" => ListPlaylists,
+        "load" => Load {
+            name: args.next()?,
+            range: args.optional(|args| args.range())?,
+        },
+        "playlistadd" => PlaylistAdd {
+            playlist: args.next()?,
+            song: args.next()?,
+        },
+        "playlistclear" => PlaylistClear(args.next()?),
+        "playlistdelete" => PlaylistDelete {
+            playlist: args.next()?,
+            song: args.number()?,
+        },
+        "playlistmove" => PlaylistMove {
+            playlist: args.next()?,
+            from: args.number()?,
+            to: args.number()?,
+        },
+        "rename" => Rename {
+            old_name: args.next()?,
+            new_name: args.next()?,
+        },
+        "rm" => Remove(args.next()?),
+        "save" => Save(args.next()?),
+        "count" => if args.peek() == Some("group") {
+            args.next()?;
+            GroupCount(args.tag()?)
+        } else {
+            let query = args.query(&["group"])?;
+            let group = match args.peek() {
+                Some("group") => {
+                    args.next()?;
+                    Some(args.tag()?)
+                },
+                _ => None,
+            };
+            Count { query: query, group: group }
+        },
+        "listallinfo" => ListAllInfo(args.optional(|args| args.next())?),
+        "find" => Find {
+            query: args.query(&["window"])?,
+            window: args.window()?,
+        },
+        "findadd" => FindAdd(args.query(&[])?),
+        "list" => {
+            let tag = args.tag()?;
+            let query = args.query(&["group"])?;
+            let mut group = Vec::new();
+            while args.peek().is_some() {
+                if args.next()? != "group" {
+                    return Err(args.wrong_number());
+                }
+                group.push(args.tag()?);
+            }
+            List { tag: tag, query: query, group: group }
+        },
+        "search" => Search {
+            query: args.query(&["window"])?,
+            window: args.window()?,
+        },
+        "searchadd" => SearchAdd(args.query(&[])?),
+        "searchaddpl" => SearchAddPlaylist {
+            playlist: args.next()?,
+            query: args.query(&[])?,
+        },
+        "password" => Password(args.next()?),
+        "commands" => Commands,
+        "notcommands" => NotCommands,
+        "tagtypes" => TagTypes,
+        "urlhandlers" => UrlHandlers,
+        "decoders" => Decoders,
+        "config" => Config,
+        "subscribe" => Subscribe(args.next()?),
+        "unsubscribe" => Unsubscribe(args.next()?),
+        "channels" => Channels,
+        "readmessages" => ReadMessages,
+        "sendmessage" => SendMessage { channel: args.next()?, text: args.next()? },
+        "ping" => Ping,
+        "sticker" => {
+            let action = args.next()?;
+            if args.next()? != "song" {
+                return Err(args.error(CmdErrorType::Arg, "unknown sticker domain".to_owned()));
+            }
+            let uri = args.next()?;
+            match &action[..] {
+                "get" => StickerGet { uri: uri, name: args.next()? },
+                "set" => StickerSet { uri: uri, name: args.next()?, value: args.next()? },
+                "delete" => StickerDelete { uri: uri, name: args.optional(|args| args.next())? },
+                "list" => StickerList(uri),
+                "find" => StickerFind { uri: uri, name: args.next()? },
+                _ => return Err(args.error(CmdErrorType::Arg, "bad request".to_owned())),
+            }
+        },
+        "outputs" => Outputs,
+        "enableoutput" => EnableOutput(args.number()?),
+        "disableoutput" => DisableOutput(args.number()?),
+        "toggleoutput" => ToggleOutput(args.number()?),
+        "mount" => Mount { path: args.next()?, uri: args.next()? },
+        "unmount" => Unmount(args.next()?),
+        "listmounts" => ListMounts,
+        "listneighbors" => ListNeighbors,
+        name => {
+            return Err(error(CmdErrorType::Unknown, "", format!("unknown command \"{}\"", name)));
+        },
+    };
+    args.end()?;
+    Ok(command)
+}
+
+/// Parses a filter expression, as created by `Filter`'s `Display`
+pub fn parse_filter(expr: &str) -> Option<Filter> {
+    let mut parser = FilterParser { rest: expr };
+    let filter = try_opt!(parser.expression());
+    parser.skip_whitespace();
+    if parser.rest.is_empty() {
+        Some(filter)
+    } else {
+        None
+    }
+}
+
+struct FilterParser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> FilterParser<'a> {
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    /// Consumes `token` if it is next
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest.starts_with(token) {
+            self.rest = &self.rest[token.len()..];
+            true
+        } else {
+            false
+        }
+    }
+
+    /// An unquoted word, ending at whitespace or a bracket
+    fn word(&mut self) -> Option<&'a str> {
+        self.skip_whitespace();
+        let end = self.rest
+            .find(|c: char| c.is_whitespace() || c == '(' || c == ')')
+            .unwrap_or(self.rest.len());
+        if end == 0 {
+            return None;
+        }
+        let (word, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Some(word)
+    }
+
+    /// A value in single or double quotes, in which `\` escapes the next character
+    fn quoted(&mut self) -> Option<String> {
+        self.skip_whitespace();
+        let mut chars = self.rest.char_indices();
+        let quote = match chars.next() {
+            Some((_, c)) if c == '"' || c == '\'' => c,
+            _ => return None,
+        };
+        let mut value = String::new();
+        loop {
+            match try_opt!(chars.next()) {
+                (idx, c) if c == quote => {
+                    self.rest = &self.rest[idx + 1..];
+                    return Some(value);
+                },
+                (_, '\\') => value.push(try_opt!(chars.next()).1),
+                (_, c) => value.push(c),
+            }
+        }
+    }
+
+    fn expression(&mut self) -> Option<Filter> {
+        if !self.eat("(") {
+            return None;
+        }
+        let filter = if self.eat("!") {
+            Filter::Not(Box::new(try_opt!(self.expression())))
+        } else if self.rest.trim_start().starts_with('(') {
+            let mut filter = try_opt!(self.expression());
+            while self.eat("AND") {
+                filter = filter.and(try_opt!(self.expression()));
+            }
+            filter
+        } else {
+            try_opt!(self.comparison())
+        };
+        if self.eat(")") {
+            Some(filter)
+        } else {
+            None
+        }
+    }
+
+    fn comparison(&mut self) -> Option<Filter> {
+        let name = try_opt!(self.word());
+        match name {
+            "base" => return self.quoted().map(Filter::Base),
+            "modified-since" => return self.quoted().map(Filter::ModifiedSince),
+            "AudioFormat" => {
+                return match try_opt!(self.word()) {
+                    "==" => self.quoted().map(Filter::AudioFormat),
+                    "=~" => self.quoted().map(Filter::AudioFormatMatches),
+                    _ => None,
+                };
+            },
+            "prio" => {
+                if try_opt!(self.word()) != ">=" {
+                    return None;
+                }
+                return self.word().and_then(|prio| prio.parse().ok()).map(Filter::PriorityAtLeast);
+            },
+            _ => (),
+        }
+        let tag = match name {
+            "any" => FilterTag::Any,
+            "file" => FilterTag::File,
+            name => FilterTag::Tag(try_opt!(TagType::from_name(name.as_bytes()))),
+        };
+        let op = try_opt!(self.word());
+        let value = try_opt!(self.quoted());
+        match op {
+            "==" => Some(Filter::Equal(tag, value)),
+            "!=" => Some(Filter::NotEqual(tag, value)),
+            "contains" => Some(Filter::Contains(tag, value)),
+            "=~" => Some(Filter::Matches(tag, value)),
+            "!~" => Some(Filter::NotMatches(tag, value)),
+            _ => None,
+        }
+    }
+}
+
+/// Splits the first line from the input, without its newline
+fn next_line(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    match i.iter().position(|&b| b == b'\n') {
+        Some(idx) => IResult::Done(&i[idx + 1..], &i[..idx]),
+        None => IResult::Incomplete(Needed::Unknown),
+    }
+}
+
+fn parse_line(line: &[u8]) -> Result<Command, CmdError> {
+    match str::from_utf8(line) {
+        Ok(line) => parse_command(line),
+        Err(_) => Err(error(CmdErrorType::Arg, "", "Malformed UTF-8".to_owned())),
+    }
+}
+
+/// The most a server should buffer of an unfinished request, in bytes, like MPD's default
+/// `max_command_list_size`. A client that sends more without finishing the request should be
+/// disconnected.
+pub const MAX_REQUEST_SIZE: usize = 2 * 1024 * 1024;
+
+/// Parses a request from a client: a single command, or a whole command list.
+///
+/// The output is `Err` if a command could not be parsed. For command lists, the whole list is
+/// consumed, and `command_no` of the error is the index of the first bad command.
+pub fn parse_request(i: &[u8]) -> IResult<&[u8], Result<ClientRequest, CmdError>> {
+    let (mut i_inner, line) = try_parse!(i, next_line);
+    let list_ok = match line {
+        b"command_list_begin" => false,
+        b"command_list_ok_begin" => true,
+        line => return IResult::Done(i_inner, parse_line(line).map(ClientRequest::Command)),
+    };
+    let mut commands = CommandList::new();
+    let mut first_error = None;
+    let mut command_no = 0;
+    loop {
+        let (i, line) = try_parse!(i_inner, next_line);
+        i_inner = i;
+        if line == b"command_list_end" {
+            break;
+        }
+        match parse_line(line) {
+            Ok(command) => commands.push(command),
+            Err(mut e) => if first_error.is_none() {
+                e.command_no = command_no;
+                first_error = Some(e);
+            },
+        }
+        command_no += 1;
+    }
+    let request = match first_error {
+        Some(e) => Err(e),
+        None => Ok(ClientRequest::CommandList { commands: commands, list_ok: list_ok }),
+    };
+    IResult::Done(i_inner, request)
+}
+
+/// Writes the greeting sent when a client connects
+pub fn write_greeting(w: &mut io::Write, version: &Version) -> io::Result<()> {
+    write!(w, "OK MPD {}\n", version)
+}
+
+/// Writes an `ACK` line for an error
+pub fn write_error(w: &mut io::Write, e: &CmdError) -> io::Result<()> {
+    write!(w, "ACK [{}@{}] {{{}}} {}\n",
+           e.error_type.code(), e.command_no, e.command_name, e.message_text)
+}
+
+/// Writes the response to a single command, including the final `OK` or `ACK`
+pub fn write_result(w: &mut io::Write, result: &Result<CommandResponse, CmdError>)
+    -> io::Result<()>
+{
+    match *result {
+        Ok(ref response) => {
+            write_response(w, response)?;
+            write!(w, "OK\n")
+        },
+        Err(ref e) => write_error(w, e),
+    }
+}
+
+/// Writes the responses to a command list, including the final `OK` or `ACK`
+pub fn write_list_response(w: &mut io::Write, response: &CommandListResponse, list_ok: bool)
+    -> io::Result<()>
+{
+    for command_response in &response.responses {
+        write_response(w, command_response)?;
+        if list_ok {
+            write!(w, "list_OK\n")?;
+        }
+    }
+    match response.error {
+        Some(ref e) => write_error(w, e),
+        None => write!(w, "OK\n"),
+    }
+}
+
+/// Writes the body of a response to a command, without the final `OK`
+pub fn write_response(w: &mut io::Write, response: &CommandResponse) -> io::Result<()> {
+    match *response {
+        CommandResponse::Blank | CommandResponse::Tmp => Ok(()),
+        CommandResponse::Status(ref status) => write_status(w, status),
+        CommandResponse::Stats(ref stats) => write_stats(w, stats),
+        CommandResponse::Changed(ref changed) => {
+            for sub in changed {
+                write!(w, "changed: {}\n", sub)?;
+            }
+            Ok(())
+        },
+        CommandResponse::Song(ref song) => match *song {
+            Some(ref song) => write_song(w, song),
+            None => Ok(()),
+        },
+        CommandResponse::Songs(ref songs) => {
+            for song in songs {
+                write_song(w, song)?;
+            }
+            Ok(())
+        },
+        CommandResponse::TagValues(ref groups) => {
+            for group in groups {
+                write_tag_values(w, group)?;
+            }
+            Ok(())
+        },
+        CommandResponse::SongCounts(ref counts) => {
+            for count in counts {
+                if let Some((tag, ref value)) = count.group {
+                    write!(w, "{}: {}\n", tag.response_name(), value)?;
+                }
+                write!(w, "songs: {}\n", count.songs)?;
+                write!(w, "playtime: {}\n", count.playtime.as_secs())?;
+            }
+            Ok(())
+        },
+        CommandResponse::ReplayGainMode(mode) => write!(w, "replay_gain_mode: {}\n", mode),
+        CommandResponse::Id(id) => write!(w, "Id: {}\n", id),
+        CommandResponse::PositionIds(ref changes) => {
+            for &(pos, id) in changes {
+                write!(w, "cpos: {}\nId: {}\n", pos, id)?;
+            }
+            Ok(())
+        },
+        CommandResponse::CommandNames(ref names) => {
+            for name in names {
+                write!(w, "command: {}\n", name)?;
+            }
+            Ok(())
+        },
+        CommandResponse::TagTypes(ref known, ref unknown) => {
+            for tag_type in known {
+                write!(w, "tagtype: {}\n", tag_type.response_name())?;
+            }
+            for name in unknown {
+                write!(w, "tagtype: {}\n", name)?;
+            }
+            Ok(())
+        },
+        CommandResponse::UrlHandlers(ref handlers) => {
+            for handler in handlers {
+                write!(w, "handler: {}\n", handler)?;
+            }
+            Ok(())
+        },
+        CommandResponse::Decoders(ref decoders) => {
+            for decoder in decoders {
+                write!(w, "plugin: {}\n", decoder.plugin)?;
+                for suffix in &decoder.suffixes {
+                    write!(w, "suffix: {}\n", suffix)?;
+                }
+                for mime_type in &decoder.mime_types {
+                    write!(w, "mime_type: {}\n", mime_type)?;
+                }
+            }
+            Ok(())
+        },
+        CommandResponse::MusicDirectory(ref music_directory) => match *music_directory {
+            Some(ref music_directory) => write!(w, "music_directory: {}\n", music_directory),
+            None => Ok(()),
+        },
+        CommandResponse::Playlists(ref playlists) => {
+            for playlist in playlists {
+                write!(w, "playlist: {}\n", playlist.name)?;
+                if let Some(modified) = playlist.last_modified {
+                    write!(w, "Last-Modified: {}\n", modified.format("%Y-%m-%dT%H:%M:%SZ"))?;
+                }
+            }
+            Ok(())
+        },
+        CommandResponse::StickerValues(ref stickers) => {
+            let mut stickers: Vec<_> = stickers.iter().collect();
+            stickers.sort();
+            for (name, value) in stickers {
+                write!(w, "sticker: {}={}\n", name, value)?;
+            }
+            Ok(())
+        },
+        CommandResponse::StickerMatches(ref name, ref matches) => {
+            for &(ref uri, ref value) in matches {
+                write!(w, "file: {}\nsticker: {}={}\n", uri, name, value)?;
+            }
+            Ok(())
+        },
+        CommandResponse::Outputs(ref outputs) => {
+            for output in outputs {
+                write!(w, "outputid: {}\noutputname: {}\n", output.id, output.name)?;
+                if let Some(ref plugin) = output.plugin {
+                    write!(w, "plugin: {}\n", plugin)?;
+                }
+                write!(w, "outputenabled: {}\n", output.enabled as u8)?;
+                let mut attributes: Vec<_> = output.attributes.iter().collect();
+                attributes.sort();
+                for (name, value) in attributes {
+                    write!(w, "attribute: {}={}\n", name, value)?;
+                }
+            }
+            Ok(())
+        },
+        CommandResponse::Channels(ref channels) => {
+            for channel in channels {
+                write!(w, "channel: {}\n", channel)?;
+            }
+            Ok(())
+        },
+        CommandResponse::Messages(ref messages) => {
+            for message in messages {
+                write!(w, "channel: {}\nmessage: {}\n", message.channel, message.text)?;
+            }
+            Ok(())
+        },
+        CommandResponse::Mounts(ref mounts) => {
+            for mount in mounts {
+                write!(w, "mount: {}\n", mount.path)?;
+                if let Some(ref storage) = mount.storage {
+                    write!(w, "storage: {}\n", storage)?;
+                }
+            }
+            Ok(())
+        },
+        CommandResponse::Neighbors(ref neighbors) => {
+            for neighbor in neighbors {
+                write!(w, "neighbor: {}\nname: {}\n", neighbor.uri, neighbor.name)?;
+            }
+            Ok(())
+        },
+    }
+}
+
+fn write_song(w: &mut io::Write, song: &Song) -> io::Result<()> {
+    write!(w, "file: {}\n", song.file)?;
+    if let Some(modified) = song.last_modified {
+        write!(w, "Last-Modified: {}\n", modified.format("%Y-%m-%dT%H:%M:%SZ"))?;
+    }
+    if let Some(range) = song.range {
+        write!(w, "Range: {}-", format_seconds(range.start))?;
+        if let Some(end) = range.end {
+            write!(w, "{}", format_seconds(end))?;
+        }
+        write!(w, "\n")?;
+    }
+    let mut tags: Vec<_> = song.tags.iter().collect();
+    tags.sort_by_key(|&(tag, _)| tag.response_name());
+    for (tag, values) in tags {
+        for value in values {
+            write!(w, "{}: {}\n", tag.response_name(), value)?;
+        }
+    }
+    if let Some(duration) = song.duration {
+        // `Time` is the deprecated whole seconds version of `duration`
+        let secs = duration.as_secs() + if duration.subsec_nanos() >= 500_000_000 { 1 } else { 0 };
+        write!(w, "Time: {}\nduration: {}\n", secs, format_seconds(duration))?;
+    }
+    if let Some(pos) = song.position {
+        write!(w, "Pos: {}\n", pos)?;
+    }
+    if let Some(id) = song.id {
+        write!(w, "Id: {}\n", id)?;
+    }
+    if let Some(priority) = song.priority {
+        write!(w, "Prio: {}\n", priority)?;
+    }
+    Ok(())
+}
+
+fn write_tag_values(w: &mut io::Write, group: &TagValues) -> io::Result<()> {
+    for &(tag, ref value) in &group.group {
+        write!(w, "{}: {}\n", tag.response_name(), value)?;
+    }
+    for value in &group.values {
+        write!(w, "{}: {}\n", group.tag.response_name(), value)?;
+    }
+    Ok(())
+}
+
+fn write_status(w: &mut io::Write, status: &Status) -> io::Result<()> {
+    write!(w, "volume: {}\n", status.volume)?;
+    write!(w, "repeat: {}\n", status.repeat as u8)?;
+    write!(w, "random: {}\n", status.random as u8)?;
+    write!(w, "single: {}\n", status.single as u8)?;
+    write!(w, "consume: {}\n", status.consume as u8)?;
+    write!(w, "playlist: {}\n", status.playlist)?;
+    write!(w, "playlistlength: {}\n", status.playlist_length)?;
+    write!(w, "mixrampdb: {:.6}\n", status.mix_ramp_db)?;
+    write!(w, "state: {}\n", match status.state {
+        State::Play => "play",
+        State::Pause => "pause",
+        State::Stop => "stop",
+    })?;
+    if let (Some(song), Some(song_id)) = (status.song, status.song_id) {
+        write!(w, "song: {}\nsongid: {}\n", song, song_id)?;
+    }
+    if let Some(elapsed) = status.elapsed {
+        let total = status.duration.map(|duration| duration.as_secs()).unwrap_or(0);
+        write!(w, "time: {}:{}\n", elapsed.as_secs(), total)?;
+        write!(w, "elapsed: {}\n", format_seconds(elapsed))?;
+    }
+    if let Some(duration) = status.duration {
+        write!(w, "duration: {}\n", format_seconds(duration))?;
+    }
+    if let Some(bitrate) = status.bitrate {
+        write!(w, "bitrate: {}\n", bitrate)?;
+    }
+    if status.crossfade > 0 {
+        write!(w, "xfade: {}\n", status.crossfade)?;
+    }
+    if let Some((rate, bits, channels)) = status.audio {
+        write!(w, "audio: {}:{}:{}\n", rate, bits, channels)?;
+    }
+    if let (Some(next_song), Some(next_song_id)) = (status.next_song, status.next_song_id) {
+        write!(w, "nextsong: {}\nnextsongid: {}\n", next_song, next_song_id)?;
+    }
+    if let Some(job) = status.updating_db {
+        write!(w, "updating_db: {}\n", job)?;
+    }
+    if let Some(ref error) = status.error {
+        write!(w, "error: {}\n", error)?;
+    }
+    Ok(())
+}
+
+fn write_stats(w: &mut io::Write, stats: &Stats) -> io::Result<()> {
+    write!(w, "uptime: {}\n", stats.uptime.as_secs())?;
+    write!(w, "playtime: {}\n", stats.playtime.as_secs())?;
+    write!(w, "artists: {}\n", stats.artists)?;
+    write!(w, "albums: {}\n", stats.albums)?;
+    write!(w, "songs: {}\n", stats.songs)?;
+    write!(w, "db_playtime: {}\n", stats.db_playtime.as_secs())?;
+    write!(w, "db_update: {}\n", stats.db_update.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use chrono::{UTC, TimeZone};
+    use protocol::{Dispatch, ParseResponse, parse_ok, parse_result};
+    use types::{Decoder, Message, Mount, Neighbor, Output, Playlist, SongCount, TimeRange, Tag};
+
+    fn round_trip(cmd: Command) {
+        let mut line = Vec::new();
+        cmd.dispatch(&mut line).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        assert_eq!(parse_command(line.trim_end_matches('\n')), Ok(cmd), "{:?}", line);
+    }
+
+    #[test]
+    fn commands() {
+        round_trip(Command::Status);
+        round_trip(Command::Idle(vec![SubSystem::Player, SubSystem::StoredPlaylist]));
+        round_trip(Command::MixRampDelay(None));
+        round_trip(Command::ReplayGainMode(ReplayGainMode::Album));
+        round_trip(Command::Seek { song_position: 2, time: Duration::from_millis(1500) });
+        round_trip(Command::AddId { uri: "a \"b\".mp3".into(), position: Some(3) });
+        round_trip(Command::AddId { uri: "a.mp3".into(), position: None });
+        round_trip(Command::Delete(Range { start: 2, end: None }.into()));
+        round_trip(Command::Move { from: 4.into(), to: 0 });
+        round_trip(Command::PlaylistInfo(Some(Range { start: 0, end: Some(5) }.into())));
+        round_trip(Command::PlaylistFind(vec![(Selector::File, "a \"b\".mp3".into())].into()));
+        round_trip(Command::PlaylistSearch(
+            (Filter::contains(TagType::Title, "dogs") & Filter::PriorityAtLeast(1)).into()
+        ));
+        round_trip(Command::PlaylistChangesPositionId { version: "12".into(), range: None });
+        round_trip(Command::Priority {
+            priority: 200,
+            songs: vec![1.into(), Range { start: 3, end: Some(4) }.into()],
+        });
+        round_trip(Command::PriorityId { priority: 1, songs: vec!["4".into(), "5".into()] });
+        round_trip(Command::AddTagId { id: "2".into(), tag: (TagType::Genre, "Jazz".into()) });
+        round_trip(Command::Load { name: "Road Trip".into(), range: None });
+        round_trip(Command::Count {
+            query: vec![(TagType::Artist.into(), "Björk".into())].into(),
+            group: Some(TagType::Album),
+        });
+        round_trip(Command::GroupCount(TagType::Genre));
+        round_trip(Command::ListAllInfo(None));
+        round_trip(Command::ListAllInfo(Some("Pink Floyd/Animals".into())));
+        round_trip(Command::Find {
+            query: vec![(Selector::ModifiedSince, "1490000000".into())].into(),
+            window: Some(Range { start: 0, end: Some(20) }),
+        });
+        round_trip(Command::Search {
+            query: (Filter::contains(FilterTag::Any, "floyd")
+                & !Filter::equal(TagType::Album, "The \"Wall\"")).into(),
+            window: None,
+        });
+        round_trip(Command::List {
+            tag: TagType::Album,
+            query: Query::all(),
+            group: vec![TagType::AlbumArtist, TagType::Date],
+        });
+        round_trip(Command::SearchAddPlaylist {
+            playlist: "Pigs".into(),
+            query: vec![(Selector::Any, "pigs".into())].into(),
+        });
+        round_trip(Command::Password("open sesame".into()));
+        round_trip(Command::TagTypes);
+        round_trip(Command::UrlHandlers);
+        round_trip(Command::Decoders);
+        round_trip(Command::Config);
+        round_trip(Command::Subscribe("party mode".into()));
+        round_trip(Command::Channels);
+        round_trip(Command::ReadMessages);
+        round_trip(Command::SendMessage {
+            channel: "party mode".into(),
+            text: "vote \"skip\"".into(),
+        });
+        round_trip(Command::Ping);
+        round_trip(Command::StickerGet { uri: "a b.mp3".into(), name: "rating".into() });
+        round_trip(Command::StickerSet {
+            uri: "a b.mp3".into(),
+            name: "comment".into(),
+            value: "\"great\" song".into(),
+        });
+        round_trip(Command::StickerDelete { uri: "a.mp3".into(), name: None });
+        round_trip(Command::StickerDelete { uri: "a.mp3".into(), name: Some("rating".into()) });
+        round_trip(Command::StickerList("a.mp3".into()));
+        round_trip(Command::StickerFind { uri: "".into(), name: "playCount".into() });
+        round_trip(Command::Outputs);
+        round_trip(Command::EnableOutput(0));
+        round_trip(Command::DisableOutput(1));
+        round_trip(Command::ToggleOutput(2));
+        round_trip(Command::Mount { path: "nas".into(), uri: "smb://nas/My Music".into() });
+        round_trip(Command::Unmount("nas".into()));
+        round_trip(Command::ListMounts);
+        round_trip(Command::ListNeighbors);
+    }
+
+    #[test]
+    fn command_errors() {
+        assert_eq!(parse_command("fly me to the moon"), Err(CmdError {
+            error_type: CmdErrorType::Unknown,
+            command_no: 0,
+            command_name: "".to_owned(),
+            message_text: "unknown command \"fly\"".to_owned(),
+        }));
+        assert_eq!(parse_command("play"), Err(CmdError {
+            error_type: CmdErrorType::Arg,
+            command_no: 0,
+            command_name: "play".to_owned(),
+            message_text: "wrong number of arguments for \"play\"".to_owned(),
+        }));
+        assert_eq!(
+            parse_command("play one").unwrap_err().message_text,
+            "Integer expected: one"
+        );
+        assert_eq!(parse_command("stop now").unwrap_err().error_type, CmdErrorType::Arg);
+        assert_eq!(parse_command("add \"open").unwrap_err().error_type, CmdErrorType::Arg);
+        assert_eq!(parse_command("find \"(artist ==\"").unwrap_err().error_type,
+                   CmdErrorType::Arg);
+        assert_eq!(parse_command("").unwrap_err().error_type, CmdErrorType::Unknown);
+    }
+
+    #[test]
+    fn filters() {
+        let filters = vec![
+            Filter::equal(TagType::Artist, r#"AC\DC "live""#),
+            Filter::not_equal(FilterTag::File, "a.mp3") & Filter::matches(TagType::Date, "^19"),
+            !(Filter::Base("Pink Floyd".into()) & Filter::PriorityAtLeast(3)),
+            Filter::ModifiedSince("2018-01-01T00:00:00Z".into())
+                & Filter::AudioFormat("44100:16:2".into())
+                & Filter::AudioFormatMatches("*:24:*".into())
+                & Filter::not_matches(FilterTag::Any, "(?i)demo"),
+        ];
+        for filter in filters {
+            assert_eq!(parse_filter(&filter.to_string()), Some(filter));
+        }
+        assert_eq!(
+            parse_filter("(Artist == 'single quoted')"),
+            Some(Filter::equal(TagType::Artist, "single quoted"))
+        );
+        assert_eq!(parse_filter("((genre contains \"rock\"))"),
+                   Some(Filter::contains(TagType::Genre, "rock")));
+        assert_eq!(parse_filter("(artist == \"a\") trailing"), None);
+        assert_eq!(parse_filter("(colour == \"red\")"), None);
+        assert_eq!(parse_filter("(artist ~~ \"a\")"), None);
+    }
+
+    #[test]
+    fn requests() {
+        assert_eq!(
+            parse_request(&b"status\nstop\n"[..]),
+            IResult::Done(&b"stop\n"[..], Ok(ClientRequest::Command(Command::Status)))
+        );
+        assert!(parse_request(&b"stat"[..]).is_incomplete());
+        assert!(parse_request(&b"command_list_begin\nstop\n"[..]).is_incomplete());
+
+        let mut commands = CommandList::new();
+        commands.push(Command::Stop);
+        commands.push(Command::Clear);
+        assert_eq!(
+            parse_request(&b"command_list_ok_begin\nstop\nclear\ncommand_list_end\n"[..]),
+            IResult::Done(&b""[..], Ok(ClientRequest::CommandList {
+                commands: commands.clone(),
+                list_ok: true,
+            }))
+        );
+        assert_eq!(
+            parse_request(&b"command_list_begin\nstop\nclear\ncommand_list_end\nping"[..]),
+            IResult::Done(&b"ping"[..], Ok(ClientRequest::CommandList {
+                commands: commands,
+                list_ok: false,
+            }))
+        );
+        // the whole list is consumed, and the first bad command is reported
+        match parse_request(&b"command_list_begin\nstop\nplay x\nfoo\ncommand_list_end\n"[..]) {
+            IResult::Done(rest, Err(e)) => {
+                assert_eq!(rest, &b""[..]);
+                assert_eq!((e.command_no, &e.command_name[..]), (1, "play"));
+            },
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    /// Writes the result, and checks the client parses it back into the same value
+    fn response_round_trip(cmd: Command, response: CommandResponse) {
+        let mut buf = Vec::new();
+        write_result(&mut buf, &Ok(response.clone())).unwrap();
+        let parsed = parse_result(&buf, |i| {
+            let (i, response) = try_parse!(i, |i| cmd.parse_response(i));
+            let (i, _) = try_parse!(i, parse_ok);
+            IResult::Done(i, response)
+        });
+        assert_eq!(parsed, IResult::Done(&b""[..], Ok(response)), "{}",
+                   String::from_utf8_lossy(&buf));
+    }
+
+    #[test]
+    fn responses() {
+        let mut song = Song::new("Pink Floyd/Animals/01 Pigs on the Wing 1.flac");
+        song.last_modified = Some(UTC.ymd(2016, 11, 23).and_hms(19, 3, 48));
+        song.duration = Some(Duration::from_millis(85_333));
+        song.position = Some(0);
+        song.id = Some(12);
+        song.priority = Some(3);
+        song.range = Some(TimeRange { start: Duration::from_secs(1), end: None });
+        song.add_tag(Tag { value: "Pink Floyd".into(), tag_type: TagType::Artist });
+        song.add_tag(Tag { value: "Progressive Rock".into(), tag_type: TagType::Genre });
+        song.add_tag(Tag { value: "Art Rock".into(), tag_type: TagType::Genre });
+        response_round_trip(Command::CurrentSong, CommandResponse::Song(Some(song.clone())));
+        response_round_trip(Command::CurrentSong, CommandResponse::Song(None));
+        response_round_trip(
+            Command::PlaylistInfo(None),
+            CommandResponse::Songs(vec![song, Song::new("b.mp3")])
+        );
+
+        let status = Status {
+            volume: 80,
+            repeat: true,
+            random: false,
+            single: false,
+            consume: true,
+            playlist: 4,
+            playlist_length: 2,
+            state: State::Pause,
+            song: Some(1),
+            song_id: Some(9),
+            next_song: Some(0),
+            next_song_id: Some(8),
+            elapsed: Some(Duration::from_millis(80_074)),
+            duration: Some(Duration::from_millis(302_000)),
+            bitrate: Some(320),
+            crossfade: 5,
+            mix_ramp_db: -17.0,
+            audio: Some((44100, 24, 2)),
+            updating_db: Some(3),
+            error: Some("something broke".into()),
+        };
+        response_round_trip(Command::Status, CommandResponse::Status(status.clone()));
+        response_round_trip(Command::Status, CommandResponse::Status(Status {
+            state: State::Stop,
+            song: None,
+            song_id: None,
+            next_song: None,
+            next_song_id: None,
+            elapsed: None,
+            duration: None,
+            bitrate: None,
+            crossfade: 0,
+            audio: None,
+            updating_db: None,
+            error: None,
+            ..status
+        }));
+        response_round_trip(Command::Stats, CommandResponse::Stats(Stats {
+            artists: 3,
+            albums: 4,
+            songs: 50,
+            uptime: Duration::from_secs(1000),
+            db_playtime: Duration::from_secs(12345),
+            db_update: UTC.timestamp(1_490_000_000, 0),
+            playtime: Duration::from_secs(600),
+        }));
+
+        response_round_trip(
+            Command::Idle(vec![]),
+            CommandResponse::Changed(vec![SubSystem::Playlist, SubSystem::Player])
+        );
+        response_round_trip(Command::Stop, CommandResponse::Blank);
+        response_round_trip(
+            Command::AddId { uri: "a.mp3".into(), position: None },
+            CommandResponse::Id(7)
+        );
+        response_round_trip(
+            Command::PlaylistChangesPositionId { version: "3".into(), range: None },
+            CommandResponse::PositionIds(vec![(0, 7), (1, 3)])
+        );
+        response_round_trip(
+            Command::NotCommands,
+            CommandResponse::CommandNames(vec!["add".into(), "config".into()])
+        );
+        response_round_trip(
+            Command::TagTypes,
+            CommandResponse::TagTypes(
+                vec![TagType::Artist, TagType::MusicbrainzTrackId],
+                vec!["MUSICBRAINZ_WORKID".into()]
+            )
+        );
+        response_round_trip(
+            Command::UrlHandlers,
+            CommandResponse::UrlHandlers(vec!["http://".into(), "smb://".into()])
+        );
+        response_round_trip(
+            Command::Decoders,
+            CommandResponse::Decoders(vec![
+                Decoder {
+                    plugin: "flac".into(),
+                    suffixes: vec!["flac".into()],
+                    mime_types: vec!["application/flac".into(), "audio/flac".into()],
+                },
+                Decoder { plugin: "gme".into(), suffixes: vec!["nsf".into()], mime_types: vec![] },
+            ])
+        );
+        response_round_trip(
+            Command::Config,
+            CommandResponse::MusicDirectory(Some("/srv/music".into()))
+        );
+        response_round_trip(Command::Config, CommandResponse::MusicDirectory(None));
+        let mut stickers = HashMap::new();
+        stickers.insert("rating".to_owned(), "8".to_owned());
+        stickers.insert("comment".to_owned(), "x=y".to_owned());
+        response_round_trip(
+            Command::StickerList("a.mp3".into()),
+            CommandResponse::StickerValues(stickers)
+        );
+        response_round_trip(
+            Command::StickerFind { uri: "".into(), name: "rating".into() },
+            CommandResponse::StickerMatches("rating".into(), vec![
+                ("a.mp3".into(), "8".into()),
+                ("b.mp3".into(), "2".into()),
+            ])
+        );
+        let mut speakers = Output::new(0, "Living room");
+        speakers.plugin = Some("pulse".into());
+        speakers.enabled = true;
+        speakers.attributes.insert("sink".into(), "living_room".into());
+        response_round_trip(
+            Command::Outputs,
+            CommandResponse::Outputs(vec![speakers, Output::new(1, "Stream")])
+        );
+        response_round_trip(
+            Command::Channels,
+            CommandResponse::Channels(vec!["party".into(), "lobby".into()])
+        );
+        response_round_trip(
+            Command::ReadMessages,
+            CommandResponse::Messages(vec![
+                Message { channel: "party".into(), text: "hello".into() },
+                Message { channel: "lobby".into(), text: "".into() },
+            ])
+        );
+        response_round_trip(
+            Command::ListMounts,
+            CommandResponse::Mounts(vec![
+                Mount { path: "".into(), storage: Some("/srv/music".into()) },
+                Mount { path: "usb".into(), storage: None },
+            ])
+        );
+        response_round_trip(
+            Command::ListNeighbors,
+            CommandResponse::Neighbors(vec![
+                Neighbor { uri: "smb://nas".into(), name: "NAS".into() },
+            ])
+        );
+        response_round_trip(
+            Command::ListPlaylists,
+            CommandResponse::Playlists(vec![
+                Playlist {
+                    name: "Favourites".into(),
+                    last_modified: Some(UTC.timestamp(1_490_000_000, 0)),
+                },
+                Playlist {
+                    name: "New".into(),
+                    last_modified: None,
+                },
+            ])
+        );
+        response_round_trip(
+            Command::List {
+                tag: TagType::Album,
+                query: Query::all(),
+                group: vec![TagType::AlbumArtist],
+            },
+            CommandResponse::TagValues(vec![
+                TagValues {
+                    tag: TagType::Album,
+                    group: vec![(TagType::AlbumArtist, "Pink Floyd".into())],
+                    values: vec!["Animals".into(), "The Wall".into()],
+                },
+                TagValues {
+                    tag: TagType::Album,
+                    group: vec![(TagType::AlbumArtist, "Sigur Rós".into())],
+                    values: vec!["()".into()],
+                },
+            ])
+        );
+        response_round_trip(
+            Command::Count { query: Query::all(), group: None },
+            CommandResponse::SongCounts(vec![SongCount {
+                group: None,
+                songs: 12,
+                playtime: Duration::from_secs(3021),
+            }])
+        );
+        response_round_trip(
+            Command::GroupCount(TagType::AlbumArtist),
+            CommandResponse::SongCounts(vec![
+                SongCount {
+                    group: Some((TagType::AlbumArtist, "Pink Floyd".into())),
+                    songs: 2,
+                    playtime: Duration::from_secs(540),
+                },
+                SongCount {
+                    group: Some((TagType::AlbumArtist, "".into())),
+                    songs: 1,
+                    playtime: Duration::from_secs(181),
+                },
+            ])
+        );
+        response_round_trip(
+            Command::ReplayGainStatus,
+            CommandResponse::ReplayGainMode(ReplayGainMode::Track)
+        );
+    }
+
+    #[test]
+    fn list_responses() {
+        let response = CommandListResponse {
+            responses: vec![CommandResponse::Blank, CommandResponse::Id(3)],
+            error: Some(CmdError {
+                error_type: CmdErrorType::NoExist,
+                command_no: 2,
+                command_name: "play".to_owned(),
+                message_text: "No such song".to_owned(),
+            }),
+        };
+        let mut buf = Vec::new();
+        write_list_response(&mut buf, &response, true).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "list_OK\nId: 3\nlist_OK\nACK [50@2] {play} No such song\n"
+        );
+        let mut buf = Vec::new();
+        write_list_response(&mut buf, &CommandListResponse {
+            responses: vec![CommandResponse::Blank, CommandResponse::Blank],
+            error: None,
+        }, false).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "OK\n");
+
+        let mut buf = Vec::new();
+        write_greeting(&mut buf, &Version::parse("0.20.0").unwrap()).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "OK MPD 0.20.0\n");
+    }
+}
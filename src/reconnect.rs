@@ -0,0 +1,443 @@
+//! A client that reconnects when its connection to the server is lost.
+//!
+//! The server closes connections that have been idle for too long, and all of them when it
+//! restarts. `ReconnectingClient` wraps a `Client`, and when the connection fails it connects
+//! again (waiting longer after each failed attempt), sends the password again and resubscribes to
+//! the channels it was subscribed to.
+//!
+//! Commands that are safe to send twice are retried on the new connection. Others, like `Next`
+//! or `Add`, may or may not have run before the connection was lost, so their error is returned
+//! once the client has reconnected.
+
+use std::cmp;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+use semver::Version;
+
+use client::{Client, Error, HostSpec, IdleHandle, Timeouts};
+use protocol::command::{Command, CommandList, CommandListResponse, CommandResponse};
+use types::SubSystem;
+
+/// Reported each time the client has reconnected
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reconnected {
+    /// Why the old connection was given up on
+    pub reason: String,
+    /// The number of attempts it took to connect again
+    pub attempts: u32,
+}
+
+/// A client that reconnects, and restores its session, when the connection is lost
+pub struct ReconnectingClient {
+    host: HostSpec,
+    timeouts: Timeouts,
+    client: Option<Client>,
+    version: Version,
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+    subscriptions: Vec<String>,
+    on_reconnect: Option<Box<FnMut(&Reconnected) + Send>>,
+}
+
+impl fmt::Debug for ReconnectingClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReconnectingClient")
+            .field("host", &self.host)
+            .field("connected", &self.client.is_some())
+            .field("version", &self.version)
+            .field("subscriptions", &self.subscriptions)
+            .finish()
+    }
+}
+
+impl ReconnectingClient {
+    /// Connect to the server, authenticating if the host includes a password.
+    ///
+    /// The first connection isn't retried, so that a wrong address is reported straight away.
+    pub fn connect(host: HostSpec) -> Result<ReconnectingClient, Error> {
+        ReconnectingClient::connect_with_timeouts(host, Timeouts::default())
+    }
+
+    /// Connect to the server, using the timeouts for this and every later connection.
+    ///
+    /// A command that times out is treated like a lost connection.
+    pub fn connect_with_timeouts(host: HostSpec, timeouts: Timeouts)
+        -> Result<ReconnectingClient, Error>
+    {
+        let client = Client::connect_host_with_timeouts(&host, timeouts)?;
+        Ok(ReconnectingClient {
+            host: host,
+            timeouts: timeouts,
+            version: client.version(),
+            client: Some(client),
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+            subscriptions: Vec::new(),
+            on_reconnect: None,
+        })
+    }
+
+    /// Set how long to wait after the first failed attempt to reconnect, and the most to wait
+    /// between attempts. The wait doubles after each attempt.
+    ///
+    /// Defaults to 100ms and 30s.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> ReconnectingClient {
+        self.initial_delay = initial;
+        self.max_delay = max;
+        self
+    }
+
+    /// Give up reconnecting after this many attempts, returning the last error. By default the
+    /// client keeps trying.
+    pub fn max_attempts(mut self, attempts: u32) -> ReconnectingClient {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Call `f` every time the client reconnects.
+    ///
+    /// Changes made while the client was disconnected aren't reported by `idle`, so this is the
+    /// place to find out that state needs fetching again.
+    pub fn on_reconnect<F>(mut self, f: F) -> ReconnectingClient
+        where F: FnMut(&Reconnected) + Send + 'static
+    {
+        self.on_reconnect = Some(Box::new(f));
+        self
+    }
+
+    /// The protocol version of the server, as of the last connection
+    pub fn version(&self) -> Version {
+        self.version.clone()
+    }
+
+    /// Whether the client currently has a connection.
+    ///
+    /// This is false after reconnecting has failed, in which case the next command tries again.
+    pub fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /// Run a single command, returning its response.
+    ///
+    /// The password and channel subscriptions are remembered, to be restored on reconnecting.
+    pub fn run_command(&mut self, command: Command) -> Result<CommandResponse, Error> {
+        let response = self.run(is_idempotent(&command), |client| {
+            client.run_command(command.clone())
+        })?;
+        match command {
+            Command::Password(password) => self.host.password = Some(password),
+            Command::Subscribe(channel) => if !self.subscriptions.contains(&channel) {
+                self.subscriptions.push(channel);
+            },
+            Command::Unsubscribe(channel) => self.subscriptions.retain(|c| *c != channel),
+            _ => (),
+        }
+        Ok(response)
+    }
+
+    /// Run a list of commands, returning their responses in the order the commands were given.
+    ///
+    /// See `Client::run_commands`.
+    pub fn run_commands(&mut self, commands: CommandList)
+        -> Result<Vec<CommandResponse>, Error>
+    {
+        self.run_commands_partial(commands)?.into_result().map_err(Error::from)
+    }
+
+    /// Run a list of commands, returning the responses of all commands that succeeded.
+    ///
+    /// The list is only retried after reconnecting if every command in it can be.
+    /// Subscriptions made in a command list aren't restored on reconnecting.
+    pub fn run_commands_partial(&mut self, commands: CommandList)
+        -> Result<CommandListResponse, Error>
+    {
+        let retry = commands.iter().all(is_idempotent);
+        self.run(retry, |client| client.run_commands_partial(commands.clone()))
+    }
+
+    /// Block until one of the given subsystems changes, returning the subsystems that changed.
+    ///
+    /// If the connection is lost, the client reconnects and waits again.
+    pub fn idle(&mut self, subsystems: &[SubSystem]) -> Result<Vec<SubSystem>, Error> {
+        self.run(true, |client| client.idle(subsystems))
+    }
+
+    /// Like `idle`, but calls `started` with a handle for cancelling the wait each time the
+    /// server has been asked to idle, which is more than once if the client reconnects.
+    ///
+    /// See `Client::idle_with_handle`.
+    pub fn idle_with_handle<F>(&mut self, subsystems: &[SubSystem], mut started: F)
+        -> Result<Vec<SubSystem>, Error>
+        where F: FnMut(IdleHandle)
+    {
+        self.run(true, |client| client.idle_with_handle(subsystems, &mut started))
+    }
+
+    /// Authenticate with a password, which is also sent after reconnecting
+    pub fn password(&mut self, password: &str) -> Result<(), Error> {
+        self.run_command(Command::Password(password.to_owned()))?;
+        Ok(())
+    }
+
+    /// Run something on the connection, reconnecting if it fails and retrying if `retry` is set
+    fn run<F, T>(&mut self, retry: bool, mut f: F) -> Result<T, Error>
+        where F: FnMut(&mut Client) -> Result<T, Error>
+    {
+        if self.client.is_none() {
+            self.reconnect("the previous connection was dropped".to_owned())?;
+        }
+        loop {
+            let result = f(self.client.as_mut().unwrap());
+            match result {
+                Err(e @ Error::Io(_)) | Err(e @ Error::Closed) => {
+                    self.client = None;
+                    self.reconnect(e.to_string())?;
+                    if !retry {
+                        return Err(e);
+                    }
+                },
+                Err(Error::Parse(e)) => {
+                    // the rest of the response is still waiting to be read, so the connection
+                    // can't be used again
+                    self.client = None;
+                    return Err(Error::Parse(e));
+                },
+                result => return result,
+            }
+        }
+    }
+
+    fn reconnect(&mut self, reason: String) -> Result<(), Error> {
+        let mut delay = self.initial_delay;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.restore_session() {
+                Ok(client) => {
+                    self.version = client.version();
+                    self.client = Some(client);
+                    if let Some(ref mut on_reconnect) = self.on_reconnect {
+                        on_reconnect(&Reconnected {
+                            reason: reason,
+                            attempts: attempts,
+                        });
+                    }
+                    return Ok(());
+                },
+                // the server answered but refused the password or a subscription, which won't
+                // change by trying again
+                Err(Error::Command(e)) => return Err(Error::Command(e)),
+                Err(e) => if self.max_attempts.map_or(false, |max| attempts >= max) {
+                    return Err(e);
+                },
+            }
+            thread::sleep(delay);
+            delay = cmp::min(delay * 2, self.max_delay);
+        }
+    }
+
+    fn restore_session(&self) -> Result<Client, Error> {
+        let mut client = Client::connect_host_with_timeouts(&self.host, self.timeouts)?;
+        for channel in &self.subscriptions {
+            client.run_command(Command::Subscribe(channel.clone()))?;
+        }
+        Ok(client)
+    }
+}
+
+/// Whether running a command twice has the same effect as running it once.
+///
+/// Only reads, and commands that set something to a given value, are listed. Anything else,
+/// including commands added in future, is not retried.
+fn is_idempotent(command: &Command) -> bool {
+    match *command {
+        Command::ClearError
+            | Command::CurrentSong
+            | Command::Idle(_)
+            | Command::Status
+            | Command::Stats
+            | Command::Consume(_)
+            | Command::Crossfade(_)
+            | Command::MixRampDB(_)
+            | Command::MixRampDelay(_)
+            | Command::Random(_)
+            | Command::Repeat(_)
+            | Command::Volume(_)
+            | Command::Single(_)
+            | Command::ReplayGainMode(_)
+            | Command::ReplayGainStatus
+            | Command::Pause(_)
+            | Command::Play(_)
+            | Command::PlayId(_)
+            | Command::Seek { .. }
+            | Command::SeekId { .. }
+            | Command::SeekCurrent(_)
+            | Command::Stop
+            | Command::Clear
+            | Command::MoveId { .. }
+            | Command::PlaylistFind(_)
+            | Command::PlaylistId(_)
+            | Command::PlaylistInfo(_)
+            | Command::PlaylistSearch(_)
+            | Command::PlaylistChanges { .. }
+            | Command::PlaylistChangesPositionId { .. }
+            | Command::Priority { .. }
+            | Command::PriorityId { .. }
+            | Command::RangeId { .. }
+            | Command::ClearTagId { .. }
+            | Command::ListPlaylist(_)
+            | Command::ListPlaylistInfo(_)
+            | Command::ListPlaylists
+            | Command::PlaylistClear(_)
+            | Command::Count { .. }
+            | Command::GroupCount(_)
+            | Command::ListAllInfo(_)
+            | Command::Find { .. }
+            | Command::List { .. }
+            | Command::Search { .. }
+            | Command::Password(_)
+            | Command::Commands
+            | Command::NotCommands
+            | Command::TagTypes
+            | Command::UrlHandlers
+            | Command::Decoders
+            | Command::Config
+            | Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::Channels
+            | Command::ReadMessages
+            | Command::Ping
+            | Command::StickerGet { .. }
+            | Command::StickerSet { .. }
+            | Command::StickerList(_)
+            | Command::StickerFind { .. }
+            | Command::Outputs
+            | Command::EnableOutput(_)
+            | Command::DisableOutput(_)
+            | Command::ListMounts
+            | Command::ListNeighbors => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use client::{Address, HostSpec};
+    use protocol::command::{Command, CommandResponse};
+    use test_server::{FakeServer, Script};
+    use types::SubSystem;
+    use super::*;
+
+    fn host(server: &FakeServer, password: Option<&str>) -> HostSpec {
+        HostSpec {
+            address: Address::Tcp("127.0.0.1".into(), server.addr().port()),
+            password: password.map(|p| p.to_owned()),
+        }
+    }
+
+    #[test]
+    fn restores_session() {
+        let server = FakeServer::spawn_sequence(vec![
+            Script::new()
+                .respond("password \"secret\"\n", "OK\n")
+                .respond("subscribe \"chat\"\n", "OK\n"),
+            Script::new()
+                .greeting("0.20.0")
+                .respond("password \"secret\"\n", "OK\n")
+                .respond("subscribe \"chat\"\n", "OK\n")
+                .respond("currentsong\n", "OK\n"),
+        ]).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let mut client = ReconnectingClient::connect(host(&server, Some("secret")))
+            .unwrap()
+            .backoff(Duration::from_millis(1), Duration::from_millis(10))
+            .on_reconnect(move |event| recorded.lock().unwrap().push(event.attempts));
+        client.run_command(Command::Subscribe("chat".into())).unwrap();
+        assert_eq!(client.run_command(Command::CurrentSong).unwrap(), CommandResponse::Song(None));
+        assert_eq!(client.version().to_string(), "0.20.0");
+        assert_eq!(*events.lock().unwrap(), vec![1]);
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn does_not_retry_next() {
+        let server = FakeServer::spawn_sequence(vec![
+            Script::new(),
+            Script::new().respond("stop\n", "OK\n"),
+        ]).unwrap();
+        let mut client = ReconnectingClient::connect(host(&server, None))
+            .unwrap()
+            .backoff(Duration::from_millis(1), Duration::from_millis(10));
+        match client.run_command(Command::Next) {
+            Err(Error::Io(_)) | Err(Error::Closed) => (),
+            other => panic!("expected the connection to be lost, got {:?}", other),
+        }
+        assert!(client.is_connected());
+        client.run_command(Command::Stop).unwrap();
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn retry_policy() {
+        assert!(is_idempotent(&Command::Status));
+        assert!(is_idempotent(&Command::Volume(50)));
+        assert!(!is_idempotent(&Command::Next));
+        assert!(!is_idempotent(&Command::ToggleOutput(0)));
+        // the server doesn't answer noidle when it isn't idling
+        assert!(!is_idempotent(&Command::NoIdle));
+    }
+
+    #[test]
+    fn reenters_idle() {
+        let server = FakeServer::spawn_sequence(vec![
+            Script::new().expect("idle player\n"),
+            Script::new().respond("idle player\n", "changed: player\nOK\n"),
+        ]).unwrap();
+        let mut client = ReconnectingClient::connect(host(&server, None))
+            .unwrap()
+            .backoff(Duration::from_millis(1), Duration::from_millis(10));
+        assert_eq!(client.idle(&[SubSystem::Player]).unwrap(), vec![SubSystem::Player]);
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn new_idle_handle_after_reconnecting() {
+        let server = FakeServer::spawn_sequence(vec![
+            Script::new().expect("idle\n"),
+            Script::new().respond("idle\nnoidle\n", "OK\n"),
+        ]).unwrap();
+        let mut client = ReconnectingClient::connect(host(&server, None))
+            .unwrap()
+            .backoff(Duration::from_millis(1), Duration::from_millis(10));
+        let mut started = 0;
+        let changed = client.idle_with_handle(&[], |handle| {
+            started += 1;
+            if started == 2 {
+                handle.noidle().unwrap();
+            }
+        });
+        assert_eq!(changed.unwrap(), vec![]);
+        assert_eq!(started, 2);
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn gives_up() {
+        let server = FakeServer::spawn(Script::new()).unwrap();
+        let mut client = ReconnectingClient::connect(host(&server, None))
+            .unwrap()
+            .backoff(Duration::from_millis(1), Duration::from_millis(10))
+            .max_attempts(2);
+        server.finish().unwrap();
+        assert!(client.run_command(Command::Status).is_err());
+        assert!(!client.is_connected());
+    }
+}
@@ -0,0 +1,254 @@
+//! Typed access to the sticker database.
+//!
+//! Stickers are names and values that clients store on songs in the server's sticker database.
+//! There is no standard for what they are called, so ratings and counts use the names most
+//! clients that store them use: `rating` (from 0 to 10), `playCount` and `skipCount`.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
+use std::str::FromStr;
+
+use client::{Client, Error};
+use protocol::command::{Command, CommandResponse};
+use types::{CmdError, CmdErrorType};
+
+/// The name of the sticker holding a song's rating
+pub const RATING: &str = "rating";
+/// The name of the sticker counting how many times a song has been played
+pub const PLAY_COUNT: &str = "playCount";
+/// The name of the sticker counting how many times a song has been skipped
+pub const SKIP_COUNT: &str = "skipCount";
+/// The highest rating
+pub const MAX_RATING: u8 = 10;
+
+/// Reads and writes stickers over a client's connection, created by `Client::stickers`
+pub struct Stickers<'a> {
+    client: &'a mut Client,
+}
+
+impl<'a> Stickers<'a> {
+    /// Use the given client's connection
+    pub fn new(client: &'a mut Client) -> Stickers<'a> {
+        Stickers {
+            client: client,
+        }
+    }
+
+    /// Get the value of a sticker, or `None` if the song doesn't have it. A song that doesn't
+    /// exist is still an error.
+    pub fn get(&mut self, uri: &str, name: &str) -> Result<Option<String>, Error> {
+        let command = Command::StickerGet {
+            uri: uri.to_owned(),
+            name: name.to_owned(),
+        };
+        match self.client.run_command(command) {
+            Ok(CommandResponse::StickerValues(mut stickers)) => Ok(stickers.remove(name)),
+            Ok(_) => unreachable!(),
+            Err(Error::Command(ref e)) if is_no_such_sticker(e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Set a sticker on a song, replacing any existing value
+    pub fn set(&mut self, uri: &str, name: &str, value: &str) -> Result<(), Error> {
+        self.client.run_command(Command::StickerSet {
+            uri: uri.to_owned(),
+            name: name.to_owned(),
+            value: value.to_owned(),
+        })?;
+        Ok(())
+    }
+
+    /// Delete a sticker from a song. It isn't an error if the song doesn't have the sticker, but it
+    /// is if the song doesn't exist.
+    pub fn delete(&mut self, uri: &str, name: &str) -> Result<(), Error> {
+        let command = Command::StickerDelete {
+            uri: uri.to_owned(),
+            name: Some(name.to_owned()),
+        };
+        match self.client.run_command(command) {
+            Ok(_) => Ok(()),
+            Err(Error::Command(ref e)) if is_no_such_sticker(e) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Get all the stickers on a song, by name
+    pub fn list(&mut self, uri: &str) -> Result<HashMap<String, String>, Error> {
+        match self.client.run_command(Command::StickerList(uri.to_owned()))? {
+            CommandResponse::StickerValues(stickers) => Ok(stickers),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Find the songs in a directory (and its subdirectories) with a sticker, returning the
+    /// (uri, value) pairs. Use an empty uri to search the whole database.
+    pub fn find(&mut self, uri: &str, name: &str) -> Result<Vec<(String, String)>, Error> {
+        let command = Command::StickerFind {
+            uri: uri.to_owned(),
+            name: name.to_owned(),
+        };
+        match self.client.run_command(command)? {
+            CommandResponse::StickerMatches(_, matches) => Ok(matches),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Get the value of a sticker, parsed as a `T`.
+    ///
+    /// A value that doesn't parse is reported as an `InvalidData` io error.
+    pub fn get_parsed<T: FromStr>(&mut self, uri: &str, name: &str) -> Result<Option<T>, Error> {
+        match self.get(uri, name)? {
+            Some(value) => parse_value(name, &value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Set a sticker to anything that can be displayed, e.g. a number
+    pub fn set_value<T: Display>(&mut self, uri: &str, name: &str, value: T) -> Result<(), Error> {
+        self.set(uri, name, &value.to_string())
+    }
+
+    /// Get the rating of a song, from 0 to 10, or `None` if it hasn't been rated
+    pub fn rating(&mut self, uri: &str) -> Result<Option<u8>, Error> {
+        match self.get_parsed::<u8>(uri, RATING)? {
+            Some(rating) if rating > MAX_RATING => Err(invalid_value(RATING, &rating.to_string())),
+            rating => Ok(rating),
+        }
+    }
+
+    /// Rate a song from 0 to 10
+    pub fn set_rating(&mut self, uri: &str, rating: u8) -> Result<(), Error> {
+        if rating > MAX_RATING {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("a rating must be from 0 to {}, not {}", MAX_RATING, rating)
+            )));
+        }
+        self.set_value(uri, RATING, rating)
+    }
+
+    /// Get the ratings of the rated songs in a directory (and its subdirectories), as
+    /// (uri, rating) pairs. Use an empty uri for the whole database.
+    pub fn ratings(&mut self, uri: &str) -> Result<Vec<(String, u8)>, Error> {
+        self.find(uri, RATING)?.into_iter()
+            .map(|(uri, value)| match parse_value(RATING, &value)? {
+                rating if rating > MAX_RATING => Err(invalid_value(RATING, &value)),
+                rating => Ok((uri, rating)),
+            })
+            .collect()
+    }
+
+    /// Get the number of times a song has been played
+    pub fn play_count(&mut self, uri: &str) -> Result<u32, Error> {
+        Ok(self.get_parsed(uri, PLAY_COUNT)?.unwrap_or(0))
+    }
+
+    /// Add one to the number of times a song has been played, returning the new count.
+    ///
+    /// The count is read and then written, so if two clients do this at once, one of the plays
+    /// can be lost.
+    pub fn increment_play_count(&mut self, uri: &str) -> Result<u32, Error> {
+        self.increment(uri, PLAY_COUNT)
+    }
+
+    /// Get the number of times a song has been skipped
+    pub fn skip_count(&mut self, uri: &str) -> Result<u32, Error> {
+        Ok(self.get_parsed(uri, SKIP_COUNT)?.unwrap_or(0))
+    }
+
+    /// Add one to the number of times a song has been skipped, returning the new count.
+    ///
+    /// See `increment_play_count`.
+    pub fn increment_skip_count(&mut self, uri: &str) -> Result<u32, Error> {
+        self.increment(uri, SKIP_COUNT)
+    }
+
+    fn increment(&mut self, uri: &str, name: &str) -> Result<u32, Error> {
+        let count = self.get_parsed::<u32>(uri, name)?.unwrap_or(0) + 1;
+        self.set_value(uri, name, count)?;
+        Ok(count)
+    }
+}
+
+/// Whether the server rejected a command because the song doesn't have the sticker. The server
+/// uses the same error code when the song itself doesn't exist, so only the message tells them
+/// apart.
+fn is_no_such_sticker(e: &CmdError) -> bool {
+    e.error_type == CmdErrorType::NoExist && e.message_text == "no such sticker"
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, Error> {
+    value.parse().map_err(|_| invalid_value(name, value))
+}
+
+fn invalid_value(name: &str, value: &str) -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("the {} sticker has an invalid value {:?}", name, value)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{FakeServer, Script};
+
+    #[test]
+    fn stickers() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .respond(
+                    "sticker get song \"a.mp3\" \"rating\"\n",
+                    "ACK [50@0] {sticker} no such sticker\n"
+                )
+                .respond("sticker set song \"a.mp3\" \"rating\" \"8\"\n", "OK\n")
+                .respond("sticker get song \"a.mp3\" \"playCount\"\n", "sticker: playCount=2\nOK\n")
+                .respond("sticker set song \"a.mp3\" \"playCount\" \"3\"\n", "OK\n")
+                .respond(
+                    "sticker find song \"\" \"rating\"\n",
+                    "file: a.mp3\nsticker: rating=8\nfile: b.mp3\nsticker: rating=2\nOK\n"
+                )
+                .respond(
+                    "sticker get song \"b.mp3\" \"skipCount\"\n",
+                    "sticker: skipCount=lots\nOK\n"
+                )
+                .respond(
+                    "sticker delete song \"b.mp3\" \"comment\"\n",
+                    "ACK [50@0] {sticker} no such sticker\n"
+                )
+                .respond(
+                    "sticker get song \"typo.mp3\" \"rating\"\n",
+                    "ACK [50@0] {sticker} No such song\n"
+                )
+                .respond(
+                    "sticker delete song \"typo.mp3\" \"rating\"\n",
+                    "ACK [50@0] {sticker} No such song\n"
+                )
+        ).unwrap();
+        let mut client = Client::connect(server.addr()).unwrap();
+        {
+            let mut stickers = client.stickers();
+            assert_eq!(stickers.rating("a.mp3").unwrap(), None);
+            stickers.set_rating("a.mp3", 8).unwrap();
+            assert!(stickers.set_rating("a.mp3", 11).is_err());
+            assert_eq!(stickers.increment_play_count("a.mp3").unwrap(), 3);
+            assert_eq!(
+                stickers.ratings("").unwrap(),
+                vec![("a.mp3".to_owned(), 8), ("b.mp3".to_owned(), 2)]
+            );
+            match stickers.skip_count("b.mp3") {
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => (),
+                other => panic!("expected an invalid value, got {:?}", other),
+            }
+            stickers.delete("b.mp3", "comment").unwrap();
+            match stickers.rating("typo.mp3") {
+                Err(Error::Command(ref e)) if e.error_type == CmdErrorType::NoExist => (),
+                other => panic!("expected a missing song, got {:?}", other),
+            }
+            assert!(stickers.delete("typo.mp3", RATING).is_err());
+        }
+        server.finish().unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+//! A scripted fake MPD server, for testing clients without a real server.
+//!
+//! The server accepts a connection, sends the greeting, then works through its script:
+//! checking that it receives the expected requests and sending back canned responses. Responses
+//! are sent verbatim, so they can contain `ACK`s, be split across several writes or be delayed.
+//!
+//! Available in tests, and to other crates with the `test-server` feature.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let server = FakeServer::spawn(
+//!     Script::new()
+//!         .greeting("0.20.0")
+//!         .expect("status\n")
+//!         .send("volume: 50\n")
+//!         .delay(Duration::from_millis(10))
+//!         .send("OK\n")
+//! ).unwrap();
+//! let mut client = Client::connect(server.addr()).unwrap();
+//! client.run_command(Command::Status).unwrap();
+//! server.finish().unwrap();
+//! ```
+
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the server waits for the client before giving up, by default
+const TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Expect(String),
+    Send(Vec<u8>),
+    Delay(Duration),
+}
+
+/// What the server should receive and send, in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    greeting: String,
+    steps: Vec<Step>,
+    timeout: Duration,
+}
+
+impl Script {
+    /// Create an empty script, with the greeting `OK MPD 0.19.0`
+    pub fn new() -> Script {
+        Script {
+            greeting: "0.19.0".to_owned(),
+            steps: Vec::new(),
+            timeout: Duration::from_secs(TIMEOUT_SECS),
+        }
+    }
+
+    /// Set the protocol version sent in the greeting
+    pub fn greeting(mut self, version: &str) -> Script {
+        self.greeting = version.to_owned();
+        self
+    }
+
+    /// Set how long to wait for the client to connect, or to send an expected request, before
+    /// failing. Defaults to 5 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Script {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Wait until exactly `request` is received from the client
+    pub fn expect(mut self, request: &str) -> Script {
+        self.steps.push(Step::Expect(request.to_owned()));
+        self
+    }
+
+    /// Send `response` to the client as a single write
+    pub fn send(mut self, response: &str) -> Script {
+        self.steps.push(Step::Send(response.as_bytes().to_owned()));
+        self
+    }
+
+    /// Wait before the next step, so that the client sees a partial response
+    pub fn delay(mut self, delay: Duration) -> Script {
+        self.steps.push(Step::Delay(delay));
+        self
+    }
+
+    /// Shorthand for `expect(request).send(response)`
+    pub fn respond(self, request: &str, response: &str) -> Script {
+        self.expect(request).send(response)
+    }
+
+    /// Send `response` in pieces of `chunk` bytes, waiting `delay` between each
+    pub fn trickle(mut self, response: &str, chunk: usize, delay: Duration) -> Script {
+        assert!(chunk > 0, "chunk size must be greater than 0");
+        for (idx, piece) in response.as_bytes().chunks(chunk).enumerate() {
+            if idx > 0 {
+                self.steps.push(Step::Delay(delay));
+            }
+            self.steps.push(Step::Send(piece.to_owned()));
+        }
+        self
+    }
+
+    fn run(&self, stream: &mut TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        // the greeting is sent in one write, as a real server would
+        stream.write_all(format!("OK MPD {}\n", self.greeting).as_bytes())?;
+        for step in &self.steps {
+            match *step {
+                Step::Expect(ref expected) => {
+                    let mut received = vec![0; expected.len()];
+                    stream.read_exact(&mut received)?;
+                    if received != expected.as_bytes() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("expected {:?}, received {:?}",
+                                    expected, String::from_utf8_lossy(&received))
+                        ));
+                    }
+                },
+                Step::Send(ref response) => {
+                    stream.write_all(response)?;
+                    stream.flush()?;
+                },
+                Step::Delay(delay) => thread::sleep(delay),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A running fake server, listening on a local port
+#[derive(Debug)]
+pub struct FakeServer {
+    addr: SocketAddr,
+    handle: thread::JoinHandle<io::Result<()>>,
+}
+
+impl FakeServer {
+    /// Start listening on a free port, and run the script for the first client that connects
+    pub fn spawn(script: Script) -> io::Result<FakeServer> {
+        FakeServer::spawn_sequence(vec![script])
+    }
+
+    /// Start listening on a free port, and run each script for the next connection in turn.
+    ///
+    /// Each connection is closed when its script finishes, so this can be used to test
+    /// reconnecting.
+    pub fn spawn_sequence(scripts: Vec<Script>) -> io::Result<FakeServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        // polled, so that `finish` fails rather than hangs if the client never connects
+        listener.set_nonblocking(true)?;
+        let handle = thread::spawn(move || {
+            for script in scripts {
+                let mut stream = accept(&listener, script.timeout)?;
+                script.run(&mut stream)?;
+            }
+            Ok(())
+        });
+        Ok(FakeServer {
+            addr: addr,
+            handle: handle,
+        })
+    }
+
+    /// The address to connect to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wait for the scripts to finish, returning an error if the client didn't send what was
+    /// expected.
+    pub fn finish(self) -> io::Result<()> {
+        match self.handle.join() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "fake server panicked")),
+        }
+    }
+}
+
+/// Wait for a client to connect, failing with `TimedOut` after `timeout`
+fn accept(listener: &TcpListener, timeout: Duration) -> io::Result<TcpStream> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                return Ok(stream);
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "no client connected"));
+                }
+                thread::sleep(Duration::from_millis(1));
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script() {
+        let server = FakeServer::spawn(
+            Script::new()
+                .greeting("0.21.0")
+                .respond("status\n", "ACK [5@0] {status} nope\n")
+                .trickle("abc", 2, Duration::from_millis(1))
+        ).unwrap();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.write_all(b"status\n").unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "OK MPD 0.21.0\nACK [5@0] {status} nope\nabc");
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn unexpected_request() {
+        let server = FakeServer::spawn(Script::new().respond("play\n", "OK\n")).unwrap();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.write_all(b"stop\n").unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "OK MPD 0.19.0\n");
+        assert_eq!(server.finish().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn no_client() {
+        let server = FakeServer::spawn(Script::new().timeout(Duration::from_millis(20))).unwrap();
+        assert_eq!(server.finish().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
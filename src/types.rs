@@ -1,7 +1,12 @@
 use std::time::Duration;
 use chrono::{DateTime, UTC, TimeZone};
+use std::collections::HashMap;
 use std::default;
+use std::error;
 use std::fmt;
+use std::ops;
+
+use protocol::Quoted;
 
 /// The possible error types sent from mpd
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -41,6 +46,25 @@ impl CmdErrorType {
             _ => None
         }
     }
+
+    /// The code sent by the server for this error type
+    pub fn code(&self) -> u32 {
+        use self::CmdErrorType::*;
+        match *self {
+            NotList => 1,
+            Arg => 2,
+            Password => 3,
+            Permission => 4,
+            Unknown => 5,
+            NoExist => 50,
+            PlaylistMax => 51,
+            System => 52,
+            PlaylistLoad => 53,
+            UpdateAlready => 54,
+            PlayerSync => 55,
+            Exist => 56,
+        }
+    }
 }
 
 /// The error returned from the server for failed commands
@@ -56,6 +80,18 @@ pub struct CmdError {
     pub message_text: String
 }
 
+impl fmt::Display for CmdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.command_name.is_empty() {
+            f.write_str(&self.message_text)
+        } else {
+            write!(f, "{}: {}", self.command_name, self.message_text)
+        }
+    }
+}
+
+impl error::Error for CmdError {}
+
 /// A piece of textual information about a track of music or sound.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Tag {
@@ -66,7 +102,7 @@ pub struct Tag {
 }
 
 /// The following tags are supported by MPD
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum TagType {
     /// The artist name. Its meaning is not well-defined; see composer and performer for more
     /// specific tags.
@@ -115,8 +151,68 @@ pub enum TagType {
     MusicbrainzReleaseTrackId
 }
 
+impl TagType {
+    /// Maps tag names to tag types, ignoring case (the server sends e.g. `Artist` or
+    /// `MUSICBRAINZ_TRACKID` in responses)
+    pub fn from_name(name: &[u8]) -> Option<TagType> {
+        use self::TagType::*;
+        match &name.to_ascii_lowercase()[..] {
+            b"artist" => Some(Artist),
+            b"artistsort" => Some(ArtistSort),
+            b"album" => Some(Album),
+            b"albumsort" => Some(AlbumSort),
+            b"albumartist" => Some(AlbumArtist),
+            b"albumartistsort" => Some(AlbumArtistSort),
+            b"title" => Some(Title),
+            b"track" => Some(Track),
+            b"name" => Some(Name),
+            b"genre" => Some(Genre),
+            b"date" => Some(Date),
+            b"composer" => Some(Composer),
+            b"performer" => Some(Performer),
+            b"comment" => Some(Comment),
+            b"disc" => Some(Disc),
+            b"musicbrainz_artistid" => Some(MusicbrainzArtistId),
+            b"musicbrainz_albumid" => Some(MusicbrainzAlbumId),
+            b"musicbrainz_albumartistid" => Some(MusicbrainzAlbumArtistId),
+            b"musicbrainz_trackid" => Some(MusicbrainzTrackId),
+            b"musicbrainz_releasetrackid" => Some(MusicbrainzReleaseTrackId),
+            _ => None
+        }
+    }
+
+    /// The name the server uses for the tag in responses (commands use the lowercase `Display`
+    /// name)
+    pub fn response_name(&self) -> &'static str {
+        use self::TagType::*;
+        match *self {
+            Artist => "Artist",
+            ArtistSort => "ArtistSort",
+            Album => "Album",
+            AlbumSort => "AlbumSort",
+            AlbumArtist => "AlbumArtist",
+            AlbumArtistSort => "AlbumArtistSort",
+            Title => "Title",
+            Track => "Track",
+            Name => "Name",
+            Genre => "Genre",
+            Date => "Date",
+            Composer => "Composer",
+            Performer => "Performer",
+            Comment => "Comment",
+            Disc => "Disc",
+            MusicbrainzArtistId => "MUSICBRAINZ_ARTISTID",
+            MusicbrainzAlbumId => "MUSICBRAINZ_ALBUMID",
+            MusicbrainzAlbumArtistId => "MUSICBRAINZ_ALBUMARTISTID",
+            MusicbrainzTrackId => "MUSICBRAINZ_TRACKID",
+            MusicbrainzReleaseTrackId => "MUSICBRAINZ_RELEASETRACKID",
+        }
+    }
+}
+
 impl fmt::Display for TagType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::TagType::*;
         match *self {
             Artist => write!(f, "artist"),
             ArtistSort => write!(f, "artistsort"),
@@ -142,6 +238,264 @@ impl fmt::Display for TagType {
     }
 }
 
+/// What a needle is matched against in a database query
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Selector {
+    /// The given tag
+    Tag(TagType),
+    /// All tag values
+    Any,
+    /// The full path of the file, relative to the music directory
+    File,
+    /// Restricts the search to songs in the given directory (relative to the music directory)
+    Base,
+    /// Songs whose files were modified since the given time (ISO 8601 or UNIX time stamp)
+    ModifiedSince,
+}
+
+impl Selector {
+    /// Maps the names used in queries to selectors, ignoring case
+    pub fn from_name(name: &[u8]) -> Option<Selector> {
+        match &name.to_ascii_lowercase()[..] {
+            b"any" => Some(Selector::Any),
+            b"file" => Some(Selector::File),
+            b"base" => Some(Selector::Base),
+            b"modified-since" => Some(Selector::ModifiedSince),
+            name => TagType::from_name(name).map(Selector::Tag),
+        }
+    }
+}
+
+impl From<TagType> for Selector {
+    fn from(tag: TagType) -> Selector {
+        Selector::Tag(tag)
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Selector::Tag(tag) => write!(f, "{}", tag),
+            Selector::Any => write!(f, "any"),
+            Selector::File => write!(f, "file"),
+            Selector::Base => write!(f, "base"),
+            Selector::ModifiedSince => write!(f, "modified-since"),
+        }
+    }
+}
+
+/// What a `Filter` comparison is made against
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilterTag {
+    /// The given tag
+    Tag(TagType),
+    /// Any tag value
+    Any,
+    /// The full path of the file, relative to the music directory
+    File,
+}
+
+impl From<TagType> for FilterTag {
+    fn from(tag: TagType) -> FilterTag {
+        FilterTag::Tag(tag)
+    }
+}
+
+impl fmt::Display for FilterTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FilterTag::Tag(tag) => write!(f, "{}", tag),
+            FilterTag::Any => write!(f, "any"),
+            FilterTag::File => write!(f, "file"),
+        }
+    }
+}
+
+/// A filter expression for database queries (requires MPD 0.21 or later).
+///
+/// Filters can be combined using `&` (or `and`) and negated using `!`.
+///
+/// # Examples
+///
+/// Find songs by an artist from the 90s, that aren't live recordings:
+///
+/// ```ignore
+/// Filter::equal(TagType::Artist, "Pearl Jam")
+///     & Filter::matches(TagType::Date, "^199")
+///     & !Filter::contains(TagType::Title, "live")
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    /// The value is exactly the given string
+    Equal(FilterTag, String),
+    /// The value is not the given string
+    NotEqual(FilterTag, String),
+    /// The value contains the given string
+    Contains(FilterTag, String),
+    /// The value matches the given (Perl-compatible) regular expression
+    Matches(FilterTag, String),
+    /// The value doesn't match the given regular expression
+    NotMatches(FilterTag, String),
+    /// The song is in the given directory (relative to the music directory)
+    Base(String),
+    /// The song's file was modified since the given time (ISO 8601 or UNIX time stamp)
+    ModifiedSince(String),
+    /// The song's audio format is exactly the given format, `samplerate:bits:channels`
+    AudioFormat(String),
+    /// The song's audio format matches the given mask, e.g. `*:24:*`
+    AudioFormatMatches(String),
+    /// The song's priority in the queue is at least the given value
+    PriorityAtLeast(u8),
+    /// The inner filter doesn't match
+    Not(Box<Filter>),
+    /// The first filter and all of the others match. Made with `and` or `&`.
+    And(Box<Filter>, Vec<Filter>),
+}
+
+impl Filter {
+    /// The value of the tag is exactly `value`
+    pub fn equal<T: Into<FilterTag>, S: Into<String>>(tag: T, value: S) -> Filter {
+        Filter::Equal(tag.into(), value.into())
+    }
+
+    /// The value of the tag is not `value`
+    pub fn not_equal<T: Into<FilterTag>, S: Into<String>>(tag: T, value: S) -> Filter {
+        Filter::NotEqual(tag.into(), value.into())
+    }
+
+    /// The value of the tag contains `value`
+    pub fn contains<T: Into<FilterTag>, S: Into<String>>(tag: T, value: S) -> Filter {
+        Filter::Contains(tag.into(), value.into())
+    }
+
+    /// The value of the tag matches the regular expression `regex`
+    pub fn matches<T: Into<FilterTag>, S: Into<String>>(tag: T, regex: S) -> Filter {
+        Filter::Matches(tag.into(), regex.into())
+    }
+
+    /// The value of the tag doesn't match the regular expression `regex`
+    pub fn not_matches<T: Into<FilterTag>, S: Into<String>>(tag: T, regex: S) -> Filter {
+        Filter::NotMatches(tag.into(), regex.into())
+    }
+
+    /// Both this filter and `other` match
+    pub fn and(self, other: Filter) -> Filter {
+        let (first, mut rest) = match self {
+            Filter::And(first, rest) => (first, rest),
+            filter => (Box::new(filter), Vec::new()),
+        };
+        match other {
+            Filter::And(other_first, others) => {
+                rest.push(*other_first);
+                rest.extend(others);
+            },
+            other => rest.push(other),
+        }
+        Filter::And(first, rest)
+    }
+}
+
+impl ops::BitAnd for Filter {
+    type Output = Filter;
+
+    fn bitand(self, other: Filter) -> Filter {
+        self.and(other)
+    }
+}
+
+impl ops::Not for Filter {
+    type Output = Filter;
+
+    fn not(self) -> Filter {
+        Filter::Not(Box::new(self))
+    }
+}
+
+/// Displays the expression as sent to the server (before it is quoted as an argument)
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Filter::Equal(tag, ref value) => write!(f, "({} == {})", tag, Quoted(value)),
+            Filter::NotEqual(tag, ref value) => write!(f, "({} != {})", tag, Quoted(value)),
+            Filter::Contains(tag, ref value) => {
+                write!(f, "({} contains {})", tag, Quoted(value))
+            },
+            Filter::Matches(tag, ref value) => write!(f, "({} =~ {})", tag, Quoted(value)),
+            Filter::NotMatches(tag, ref value) => write!(f, "({} !~ {})", tag, Quoted(value)),
+            Filter::Base(ref dir) => write!(f, "(base {})", Quoted(dir)),
+            Filter::ModifiedSince(ref time) => write!(f, "(modified-since {})", Quoted(time)),
+            Filter::AudioFormat(ref format) => {
+                write!(f, "(AudioFormat == {})", Quoted(format))
+            },
+            Filter::AudioFormatMatches(ref mask) => {
+                write!(f, "(AudioFormat =~ {})", Quoted(mask))
+            },
+            Filter::PriorityAtLeast(prio) => write!(f, "(prio >= {})", prio),
+            Filter::Not(ref filter) => write!(f, "(!{})", filter),
+            Filter::And(ref first, ref rest) if rest.is_empty() => write!(f, "{}", first),
+            Filter::And(ref first, ref rest) => {
+                write!(f, "({}", first)?;
+                for filter in rest {
+                    write!(f, " AND {}", filter)?;
+                }
+                write!(f, ")")
+            },
+        }
+    }
+}
+
+/// The conditions songs must meet in a database query
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    /// All of the (selector, needle) pairs must match. This works with all versions of MPD
+    Tags(Vec<(Selector, String)>),
+    /// A filter expression (requires MPD 0.21 or later)
+    Filter(Filter),
+}
+
+impl Query {
+    /// A query with no conditions
+    pub fn all() -> Query {
+        Query::Tags(Vec::new())
+    }
+}
+
+impl From<Vec<(Selector, String)>> for Query {
+    fn from(tags: Vec<(Selector, String)>) -> Query {
+        Query::Tags(tags)
+    }
+}
+
+impl From<Filter> for Query {
+    fn from(filter: Filter) -> Query {
+        Query::Filter(filter)
+    }
+}
+
+/// The values of a tag returned from `Command::List`, for one group
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagValues {
+    /// The listed tag
+    pub tag: TagType,
+    /// The values of the group tags shared by this group, in the order the group tags were
+    /// given. Empty if the list wasn't grouped.
+    pub group: Vec<(TagType, String)>,
+    /// The values of the listed tag
+    pub values: Vec<String>,
+}
+
+/// The number of songs and their total playtime from `Command::Count`, for one group
+#[derive(Clone, Debug, PartialEq)]
+pub struct SongCount {
+    /// The group tag and the value shared by the counted songs, or `None` if the count wasn't
+    /// grouped
+    pub group: Option<(TagType, String)>,
+    /// The number of songs
+    pub songs: u64,
+    /// The total playtime of the songs
+    pub playtime: Duration,
+}
+
 /// The types of subsystem that can be subscribed to by `Command::Idle`
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum SubSystem {
@@ -158,6 +512,46 @@ pub enum SubSystem {
     Message
 }
 
+impl SubSystem {
+    /// Maps the names used by the server to subsystems
+    pub fn from_name(name: &[u8]) -> Option<SubSystem> {
+        use self::SubSystem::*;
+        match name {
+            b"database" => Some(Database),
+            b"update" => Some(Update),
+            b"stored_playlist" => Some(StoredPlaylist),
+            b"playlist" => Some(Playlist),
+            b"player" => Some(Player),
+            b"mixer" => Some(Mixer),
+            b"output" => Some(Output),
+            b"options" => Some(Options),
+            b"sticker" => Some(Sticker),
+            b"subscription" => Some(Subscription),
+            b"message" => Some(Message),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for SubSystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SubSystem::*;
+        match *self {
+            Database => write!(f, "database"),
+            Update => write!(f, "update"),
+            StoredPlaylist => write!(f, "stored_playlist"),
+            Playlist => write!(f, "playlist"),
+            Player => write!(f, "player"),
+            Mixer => write!(f, "mixer"),
+            Output => write!(f, "output"),
+            Options => write!(f, "options"),
+            Sticker => write!(f, "sticker"),
+            Subscription => write!(f, "subscription"),
+            Message => write!(f, "message"),
+        }
+    }
+}
+
 /// Some commands require a range (e.g. delete)
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Range {
@@ -179,6 +573,17 @@ impl fmt::Display for Range {
     }
 }
 
+/// A range of time within a song
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimeRange {
+    /// The start of the range
+    pub start: Duration,
+    /// (optional) The end of the range
+    ///
+    /// If `None`, the range continues to the end of the song
+    pub end: Option<Duration>,
+}
+
 /// Either a single value or a range of values.
 ///
 /// Used as parameter for certain commands
@@ -211,6 +616,131 @@ impl fmt::Display for SingleOrRange {
     }
 }
 
+/// A song, either in the database or in a playlist
+#[derive(Clone, Debug, PartialEq)]
+pub struct Song {
+    /// The uri of the song, relative to the music directory
+    pub file: String,
+    /// When the file was last modified
+    pub last_modified: Option<DateTime<UTC>>,
+    /// The length of the song
+    pub duration: Option<Duration>,
+    /// The position of the song in the playlist (only for songs in the current playlist)
+    pub position: Option<u32>,
+    /// The song id (only for songs in the current playlist)
+    pub id: Option<u32>,
+    /// The priority of the song when playing in random mode (only for songs in the current
+    /// playlist)
+    pub priority: Option<u8>,
+    /// The part of the song that will be played, if it has been restricted with `RangeId`
+    pub range: Option<TimeRange>,
+    /// The tags of the song. There may be more than one value for a tag (e.g. multiple
+    /// artists).
+    pub tags: HashMap<TagType, Vec<String>>,
+}
+
+impl Song {
+    /// Create a song with the given uri and no other information
+    pub fn new<S: Into<String>>(file: S) -> Song {
+        Song {
+            file: file.into(),
+            last_modified: None,
+            duration: None,
+            position: None,
+            id: None,
+            priority: None,
+            range: None,
+            tags: HashMap::new(),
+        }
+    }
+
+    /// Get the first value of a tag, if the song has it
+    pub fn tag(&self, tag_type: TagType) -> Option<&str> {
+        self.tags.get(&tag_type).and_then(|values| values.first()).map(|v| &v[..])
+    }
+
+    /// Add a value for a tag
+    pub fn add_tag(&mut self, tag: Tag) {
+        self.tags.entry(tag.tag_type).or_insert_with(Vec::new).push(tag.value);
+    }
+}
+
+/// A stored playlist, as listed by `ListPlaylists`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Playlist {
+    /// The name of the playlist, without the `.m3u` suffix
+    pub name: String,
+    /// When the playlist was last changed
+    pub last_modified: Option<DateTime<UTC>>,
+}
+
+/// An audio output, as listed by `Outputs`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Output {
+    /// The id used to enable, disable or toggle the output
+    pub id: u32,
+    /// The name given to the output in the server's configuration
+    pub name: String,
+    /// The plugin the output uses, e.g. `alsa` or `httpd`. Older servers don't send this.
+    pub plugin: Option<String>,
+    /// Whether audio is being played on the output
+    pub enabled: bool,
+    /// Settings of the output that can be changed while the server runs, e.g. `dop` for alsa
+    pub attributes: HashMap<String, String>,
+}
+
+impl Output {
+    /// Create a disabled output with no plugin or attributes
+    pub fn new<S: Into<String>>(id: u32, name: S) -> Output {
+        Output {
+            id: id,
+            name: name.into(),
+            plugin: None,
+            enabled: false,
+            attributes: HashMap::new(),
+        }
+    }
+}
+
+/// A storage mounted in the music directory, as listed by `ListMounts`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mount {
+    /// Where the storage is mounted, relative to the music directory. The music directory itself
+    /// is mounted at `""`.
+    pub path: String,
+    /// The uri of the storage, e.g. `nfs://server/export/music`, if the server knows it
+    pub storage: Option<String>,
+}
+
+/// A storage found on the network that could be mounted, as listed by `ListNeighbors`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Neighbor {
+    /// The uri to mount it with, e.g. `smb://server/share`
+    pub uri: String,
+    /// A name to show to the user
+    pub name: String,
+}
+
+/// A message sent to a channel by another client, as read by `ReadMessages`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    /// The channel the message was sent to
+    pub channel: String,
+    /// The text of the message
+    pub text: String,
+}
+
+/// A decoder plugin, and the files it can decode, as listed by `Decoders`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decoder {
+    /// The name of the plugin, e.g. `flac`
+    pub plugin: String,
+    /// The file name suffixes it decodes, e.g. `flac`
+    pub suffixes: Vec<String>,
+    /// The mime types it decodes, e.g. `audio/flac`
+    pub mime_types: Vec<String>,
+}
+
 /// Information about what mpd is doing.
 ///
 /// This is returned from the `Status` command
@@ -232,26 +762,26 @@ pub struct Status {
     pub playlist_length: u32,
     /// Whether mpd is playing, paused, or stopped
     pub state: State,
-    /// The position in the playlist of the currently playing song
-    pub song: u32,
-    /// The song id of the currently playing song
-    pub song_id: u32,
-    /// The playlist position of the next song to play
-    pub next_song: u32,
-    /// The song id of the next song to play
-    pub next_song_id: u32,
-    /// How far through the current song mpd is
-    pub elapsed: Duration,
+    /// The position in the playlist of the current song, if there is one
+    pub song: Option<u32>,
+    /// The song id of the current song, if there is one
+    pub song_id: Option<u32>,
+    /// The playlist position of the next song to play, if there is one
+    pub next_song: Option<u32>,
+    /// The song id of the next song to play, if there is one
+    pub next_song_id: Option<u32>,
+    /// How far through the current song mpd is (not sent when stopped)
+    pub elapsed: Option<Duration>,
     /// The length of the current song
     pub duration: Option<Duration>,
-    /// The bitrate at the current position of the current song in kbps
-    pub bitrate: u32,
-    /// The crossfade time in seconds
+    /// The bitrate at the current position of the current song in kbps (not sent when stopped)
+    pub bitrate: Option<u32>,
+    /// The crossfade time in seconds (0 if crossfading is off)
     pub crossfade: u32, // may need more
     /// The length of the mixramp time in seconds
     pub mix_ramp_db: f32,
-    /// Audio information: (sample rate, bits, channels)
-    pub audio: (u32, u32, u32), // check types
+    /// Audio information: (sample rate, bits, channels) (not sent when stopped)
+    pub audio: Option<(u32, u32, u32)>, // check types
     /// The job id (TODO needs more info)
     pub updating_db: Option<u32>,
     /// If there is an error that hasn't been cleared, it will be here
@@ -323,16 +853,17 @@ impl MaybeStatus {
             playlist: try_opt!(self.playlist),
             playlist_length: try_opt!(self.playlist_length),
             state: try_opt!(self.state),
-            song: try_opt!(self.song),
-            song_id: try_opt!(self.song_id),
-            next_song: try_opt!(self.next_song),
-            next_song_id: try_opt!(self.next_song_id),
-            elapsed: try_opt!(self.elapsed),
+            song: self.song,
+            song_id: self.song_id,
+            next_song: self.next_song,
+            next_song_id: self.next_song_id,
+            elapsed: self.elapsed,
             duration: self.duration,
-            bitrate: try_opt!(self.bitrate),
-            crossfade: try_opt!(self.crossfade),
+            bitrate: self.bitrate,
+            // the server leaves this out when crossfading is off
+            crossfade: self.crossfade.unwrap_or(0),
             mix_ramp_db: try_opt!(self.mix_ramp_db),
-            audio: try_opt!(self.audio),
+            audio: self.audio,
             updating_db: self.updating_db,
             error: self.error.clone(),
         })
@@ -418,3 +949,29 @@ pub enum ReplayGainMode {
     Album,
     Auto
 }
+
+impl ReplayGainMode {
+    /// Maps the names used by the server to replay gain modes
+    pub fn from_name(name: &[u8]) -> Option<ReplayGainMode> {
+        use self::ReplayGainMode::*;
+        match name {
+            b"off" => Some(Off),
+            b"track" => Some(Track),
+            b"album" => Some(Album),
+            b"auto" => Some(Auto),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for ReplayGainMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ReplayGainMode::*;
+        match *self {
+            Off => write!(f, "off"),
+            Track => write!(f, "track"),
+            Album => write!(f, "album"),
+            Auto => write!(f, "auto"),
+        }
+    }
+}
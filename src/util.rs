@@ -1,6 +1,6 @@
+use std::cmp;
 use std::io;
-use std::slice;
-use std::ops::{Index, RangeFull, RangeFrom};
+use std::ops::{Index, Range, RangeFull};
 use std::str;
 use nom::*;
 /*
@@ -41,16 +41,24 @@ impl Version {
 
 const DEFAULT_BLOCK_SIZE: usize = 512;
 
-/// A buffer struct
+/// Reads responses from a connection, growing as it goes.
 ///
-/// This doesn't do certain things very well, in fact it doesn't really work, but
-/// because of data coming in nice whole packets I think I don't need it to be any
-/// better.
+/// A buffer should be kept for as long as the connection it reads from, because a read can return
+/// more than one response. Bytes after the end of a parsed response are kept for the next call to
+/// `parse`.
+///
+/// Each read asks for at least as many bytes as are already waiting to be parsed, so a large
+/// response takes a number of reads that grows with the log of its size, not with its size.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Buffer {
+    /// The bytes read so far, followed by zeroed space for the next read
     buf: Vec<u8>,
     block_size: usize,
-    pos: usize
+    /// The start of the bytes that haven't been parsed yet
+    pos: usize,
+    /// The end of the bytes that have been read
+    end: usize,
+    max_size: Option<usize>,
 }
 
 impl Buffer {
@@ -61,78 +69,134 @@ impl Buffer {
         Buffer::with_block_size(DEFAULT_BLOCK_SIZE)
     }
 
-    /// Create a buffer that reallocates with given block size (underlying
-    /// Vec may reallocate less often)
+    /// Create a buffer that reads at least `block_size` bytes at a time
     pub fn with_block_size(block_size: usize) -> Buffer {
         Buffer {
             buf: Vec::with_capacity(block_size),
             block_size: block_size,
-            pos: 0
+            pos: 0,
+            end: 0,
+            max_size: None,
+        }
+    }
+
+    /// Limit the size of a single response, so that a huge response is an error rather than
+    /// using up all the memory. `None` means there is no limit, which is the default.
+    pub fn max_size(mut self, max_size: Option<usize>) -> Buffer {
+        self.set_max_size(max_size);
+        self
+    }
+
+    /// Change the limit on the size of a single response
+    pub fn set_max_size(&mut self, max_size: Option<usize>) {
+        self.max_size = max_size;
+    }
+
+    /// The number of bytes that have been read but not parsed
+    pub fn len(&self) -> usize {
+        self.end - self.pos
+    }
+
+    /// Whether all the bytes read have been parsed
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Mark the first `amt` unparsed bytes as parsed
+    pub fn consume(&mut self, amt: usize) {
+        assert!(amt <= self.len(), "consumed more bytes than the buffer holds");
+        self.pos += amt;
+        if self.pos == self.end {
+            self.pos = 0;
+            self.end = 0;
         }
     }
 
     /// Do a read call and add to our vector
     ///
-    /// Returns amount of new data added
+    /// Returns amount of new data added. Fails with `InvalidData` if the unparsed data would
+    /// grow beyond the maximum size.
     pub fn fetch<R>(&mut self, reader: &mut R) -> io::Result<usize>
     where R: io::Read {
-        if self.buf.capacity() == self.buf.len() {
-            self.buf.reserve(self.block_size);
-        }
+        self.read_more(reader).map(|(read, _)| read)
+    }
 
-        let p = self.buf.as_mut_ptr();
-        let len = self.buf.len();
-        // check for overflow (is this necessary)
-        assert!(len < isize::max_value() as usize);
-        let capacity = self.buf.capacity();
-
-        // create a slice from the unassigned part of the vec
-        let extra = unsafe {
-            slice::from_raw_parts_mut(
-                p.offset(len as isize),
-                capacity - len
-            )
-        };
-        // if this fails we just leave vec as is (i.e. do nothing in ? branch)
-        let amt = reader.read(extra)?;
-        // safety check
-        assert!(len + amt <= capacity);
-        unsafe {
-            // Adjust length to include new data
-            self.buf.set_len(len + amt);
+    /// Reads up to `max(block_size, len())` bytes, returning how many were read and whether that
+    /// was all that was asked for
+    fn read_more<R>(&mut self, reader: &mut R) -> io::Result<(usize, bool)>
+    where R: io::Read {
+        // move unparsed bytes to the front, rather than growing forever
+        if self.pos > 0 {
+            self.buf.truncate(self.end);
+            self.buf.drain(..self.pos);
+            self.end -= self.pos;
+            self.pos = 0;
+        }
+        let len = self.end;
+        let mut amt = cmp::max(self.block_size, len);
+        if let Some(max_size) = self.max_size {
+            if len >= max_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("the message is larger than the maximum of {} bytes", max_size)
+                ));
+            }
+            amt = cmp::min(amt, max_size - len);
+        }
+        // only newly added space needs zeroing; space left from earlier reads is reused
+        if self.buf.len() < len + amt {
+            self.buf.resize(len + amt, 0);
         }
-        Ok(amt)
+        let read = reader.read(&mut self.buf[len..len + amt])?;
+        self.end += read;
+        Ok((read, read == amt))
     }
 
-    /// Parses from a read source, asks for more data if we hit an incomplete
-    pub fn parse<F, R, O>(mut parser: F, mut reader: R)
-        -> IResult<(), O>
+    /// Parses a response, reading more data whenever the parser needs it.
+    ///
+    /// Bytes already in the buffer are parsed before anything is read. If the reader runs out
+    /// before the parser is done, an `UnexpectedEof` error is returned. Interrupted reads are
+    /// retried.
+    ///
+    /// The parser has to start from the beginning of the response each time, so after it comes
+    /// back `Incomplete` it isn't run again until the unparsed bytes have doubled, or a read
+    /// returns less than was asked for (in which case the rest of the response may not have
+    /// been sent yet).
+    pub fn parse<F, R, O>(&mut self, mut parser: F, mut reader: R)
+        -> io::Result<IResult<(), O>>
         where F: FnMut(&[u8]) -> IResult<&[u8], O>,
-        R: io::Read,
-        O: Clone
+        R: io::Read
     {
-        let mut buf = Self::new();
+        // how many bytes the parser saw when it last came back incomplete
+        let mut incomplete_at = 0;
+        let mut filled = false;
         loop {
-            // this intermediate variable is here for borrow-checker reasons
-            let mut res = None;
-            // TODO io error
-            buf.fetch(&mut reader).unwrap();
-            println!("try parse on **{}**", str::from_utf8(&buf[..]).unwrap());
-            match parser(&buf[..]) {
-                IResult::Done(i, o) => {
-                    res = Some((i.len(), o));
-                },
-                IResult::Error(e) => {
-                    return IResult::Error(e);
-                },
-                IResult::Incomplete(i) => {
-                    //println!("got {:?}, carrying on", i);
+            let len = self.len();
+            let full = match self.max_size {
+                Some(max_size) => len >= max_size,
+                None => false,
+            };
+            if len > incomplete_at && (!filled || len >= 2 * incomplete_at || full) {
+                let parsed = match parser(&self[..]) {
+                    IResult::Done(rest, out) => Some((len - rest.len(), out)),
+                    IResult::Error(e) => return Ok(IResult::Error(forget_input(e))),
+                    IResult::Incomplete(_) => None,
+                };
+                if let Some((amt, out)) = parsed {
+                    self.consume(amt);
+                    return Ok(IResult::Done((), out));
                 }
+                incomplete_at = len;
             }
-            if let Some((amt, out)) = res {
-                buf.pos += amt;
-                return IResult::Done((), out);
-            }
+            filled = match self.read_more(&mut reader) {
+                Ok((0, _)) => return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the connection was closed"
+                )),
+                Ok((_, filled)) => filled,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => filled,
+                Err(e) => return Err(e),
+            };
         }
     }
 }
@@ -140,8 +204,9 @@ impl Buffer {
 impl Index<RangeFull> for Buffer {
     type Output = [u8];
     fn index(&self, _: RangeFull) -> &[u8] {
-        self.buf.index(RangeFrom {
-            start: self.pos
+        self.buf.index(Range {
+            start: self.pos,
+            end: self.end,
         })
     }
 }
@@ -152,11 +217,11 @@ fn test_simple() {
     let data2 = vec![3u8, 4, 5];
     let mut b = Buffer::new();
     b.fetch(&mut &data1[..]).unwrap();
-    assert_eq!(b.buf.len(), 3);
-    assert_eq!(&b.buf[..], [1u8, 0, 1]);
+    assert_eq!(b.len(), 3);
+    assert_eq!(&b[..], [1u8, 0, 1]);
     b.fetch(&mut &data2[..]).unwrap();
-    assert_eq!(b.buf.len(), 6);
-    assert_eq!(&b.buf[..], [1u8, 0, 1, 3, 4, 5]);
+    assert_eq!(b.len(), 6);
+    assert_eq!(&b[..], [1u8, 0, 1, 3, 4, 5]);
 }
 
 #[test]
@@ -165,11 +230,29 @@ fn test_with_realloc() {
     let data2 = vec![3u8, 4, 5];
     let mut b = Buffer::with_block_size(2);
     b.fetch(&mut &data1[..]).unwrap();
-    assert_eq!(b.buf.len(), 2);
-    assert_eq!(&b.buf[..], [1u8, 0]);
+    assert_eq!(b.len(), 2);
+    assert_eq!(&b[..], [1u8, 0]);
     b.fetch(&mut &data2[..]).unwrap();
-    assert_eq!(b.buf.len(), 4);
-    assert_eq!(&b.buf[..], [1u8, 0, 3, 4]);
+    assert_eq!(b.len(), 4);
+    assert_eq!(&b[..], [1u8, 0, 3, 4]);
+}
+
+/// Drops the input positions from a parse error, so that it doesn't borrow the buffer that was
+/// parsed
+#[cfg(feature = "verbose-errors")]
+pub fn forget_input<P>(e: Err<P>) -> Err<()> {
+    match e {
+        Err::Code(kind) | Err::Position(kind, _) => Err::Code(kind),
+        Err::Node(kind, next) | Err::NodePosition(kind, _, next) => {
+            Err::Node(kind, Box::new(forget_input(*next)))
+        },
+    }
+}
+
+/// Drops the input positions from a parse error. Without verbose errors there aren't any.
+#[cfg(not(feature = "verbose-errors"))]
+pub fn forget_input(e: ErrorKind) -> ErrorKind {
+    e
 }
 
 /// Parse from bytes, rather than str
@@ -216,5 +299,90 @@ fn err_map_str(e: Err<&[u8]>) -> String {
 mod tests {
     use super::*;
 
+    named!(line<Vec<u8>>, map!(terminated!(not_line_ending, tag!("\n")), |l: &[u8]| l.to_vec()));
+
+    #[test]
+    fn parse_keeps_leftover() {
+        let mut reader = &b"one\ntwo\nthr"[..];
+        let mut b = Buffer::with_block_size(5);
+        assert_eq!(b.parse(line, &mut reader).unwrap(), IResult::Done((), b"one".to_vec()));
+        assert_eq!(b.parse(line, &mut reader).unwrap(), IResult::Done((), b"two".to_vec()));
+        assert_eq!(&b[..], b"th");
+        let e = b.parse(line, &mut reader).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn parse_compacts() {
+        let data = b"ab\n".repeat(1000);
+        let mut reader = &data[..];
+        let mut b = Buffer::with_block_size(8);
+        for _ in 0..1000 {
+            assert_eq!(b.parse(line, &mut reader).unwrap(), IResult::Done((), b"ab".to_vec()));
+        }
+        assert!(b.is_empty());
+        assert!(b.buf.capacity() <= 16, "the buffer grew to {} bytes", b.buf.capacity());
+    }
+
+    #[test]
+    fn max_size() {
+        let mut reader = io::repeat(b'a');
+        let mut b = Buffer::with_block_size(16).max_size(Some(40));
+        let e = b.parse(line, &mut reader).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(b.len(), 40);
+    }
+
+    /// Counts the reads made through it
+    struct Counted<R> {
+        inner: R,
+        reads: usize,
+    }
 
+    impl<R: io::Read> io::Read for Counted<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    /// Parses everything up to and including an `OK` line
+    fn until_ok(i: &[u8]) -> IResult<&[u8], usize> {
+        match i.windows(4).position(|w| w == b"\nOK\n") {
+            Some(idx) => IResult::Done(&i[idx + 4..], idx + 1),
+            None => IResult::Incomplete(Needed::Unknown),
+        }
+    }
+
+    #[test]
+    fn parse_large_response() {
+        let mut data = b"file: song.mp3\nTitle: A song\n".repeat(150000);
+        data.extend_from_slice(b"OK\nnext");
+        let mut reader = Counted { inner: &data[..], reads: 0 };
+        let mut attempts = 0;
+        let mut b = Buffer::new();
+        let parsed = b.parse(|i| { attempts += 1; until_ok(i) }, &mut reader).unwrap();
+        assert_eq!(parsed, IResult::Done((), data.len() - 7));
+        assert_eq!(&b[..], b"next");
+        // the reads double in size, so there are about log2(4.5 MB / 512) of them
+        assert!(reader.reads <= 16, "{} reads", reader.reads);
+        assert!(attempts <= 16, "parsed {} times", attempts);
+    }
+
+    #[test]
+    fn parse_retries_interrupted() {
+        struct Interrupting<'a>(bool, &'a [u8]);
+        impl<'a> io::Read for Interrupting<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0 {
+                    self.0 = false;
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "signal"));
+                }
+                self.1.read(buf)
+            }
+        }
+        let mut b = Buffer::new();
+        let reader = Interrupting(true, b"one\n");
+        assert_eq!(b.parse(line, reader).unwrap(), IResult::Done((), b"one".to_vec()));
+    }
 }